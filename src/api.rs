@@ -0,0 +1,359 @@
+//! Operations on the diorama shared by every way of controlling it: the HTTP
+//! routes call these directly, and message based clients send a `Command`.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use heapless::Vec;
+
+use crate::device_id::{mac_string, DeviceInfo};
+use crate::dmx::DmxConfig;
+use crate::event_log;
+use crate::midi::MidiConfig;
+use crate::mqtt::{MqttConfigUpdate, RedactedMqttConfig};
+use crate::network_config::NetworkConfig;
+use crate::playlist::{Playlist, PlaylistCommand, PlaylistStatus};
+use crate::state::{AppState, SharedState};
+use crate::storage::{PresetName, StorageError, MAX_PRESETS};
+use crate::streetlamps::StreetlampMode;
+use crate::supervisor::{self, ServiceStatuses};
+use crate::underpass_lights::LightingState;
+use crate::validation::{Validate, ValidationError};
+use crate::DEVICE_NAME;
+
+/// Failure of an operation, either in the request itself or in flash.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ApiError {
+    Invalid(ValidationError),
+    Storage(StorageError),
+}
+
+impl From<ValidationError> for ApiError {
+    fn from(err: ValidationError) -> Self {
+        ApiError::Invalid(err)
+    }
+}
+
+impl From<StorageError> for ApiError {
+    fn from(err: StorageError) -> Self {
+        ApiError::Storage(err)
+    }
+}
+
+pub type Presets = Vec<PresetName, MAX_PRESETS>;
+
+/// What the strip was showing when it was last turned off, so turning it back
+/// on can restore it
+static LIGHTING_WHEN_OFF: Mutex<CriticalSectionRawMutex, Cell<Option<LightingState>>> =
+    Mutex::new(Cell::new(None));
+
+/// Turn the strip on or off, remembering what it was showing while it's off.
+/// For clients with a power switch, like WLED and Home Assistant.
+pub fn set_strip_power(lighting: &mut LightingState, on: bool) {
+    LIGHTING_WHEN_OFF.lock(|when_off| match (on, *lighting) {
+        (false, LightingState::Off) => {}
+        (false, current) => {
+            when_off.set(Some(current));
+            *lighting = LightingState::Off;
+        }
+        (true, LightingState::Off) => {
+            *lighting = when_off
+                .take()
+                .unwrap_or(SharedState::default().underpass_lights_state);
+        }
+        (true, _) => {}
+    });
+}
+
+pub async fn get_state(app: &AppState) -> SharedState {
+    app.shared.get()
+}
+
+pub async fn set_state(app: &AppState, state: SharedState) -> Result<SharedState, ApiError> {
+    state.validate()?;
+    app.shared.set(state.clone());
+    Ok(state)
+}
+
+/// Turn all the streetlamps on or off, returning whether they're now on.
+pub async fn toggle_power(app: &AppState) -> bool {
+    app.shared.modify(|state| {
+        state.streetlamps_enabled = !state.streetlamps_enabled;
+        state.streetlamps_enabled
+    })
+}
+
+/// Turn all the streetlamps on or off.
+pub async fn set_power(app: &AppState, on: bool) {
+    app.shared.modify(|state| state.streetlamps_enabled = on)
+}
+
+/// Set lamp `id` to Off (0), On (1) or Flickering (2).
+pub async fn set_lamp(app: &AppState, id: usize, mode: u8) {
+    app.shared.modify(|state| {
+        if id < state.streetlamps_modes.len() {
+            state.streetlamps_modes[id] = match mode {
+                0 => StreetlampMode::Off,
+                1 => StreetlampMode::On,
+                2 => StreetlampMode::Flickering { chance: 90 },
+                _ => StreetlampMode::Off,
+            };
+        }
+    })
+}
+
+pub async fn list_presets(app: &AppState) -> Result<Presets, ApiError> {
+    Ok(app.storage.presets().await?)
+}
+
+/// Save the current state as a preset, replacing any existing one with that name
+pub async fn save_preset(app: &AppState, name: PresetName) -> Result<Presets, ApiError> {
+    let state = get_state(app).await;
+    app.storage.save_preset(name, state).await?;
+    list_presets(app).await
+}
+
+pub async fn recall_preset(app: &AppState, name: &PresetName) -> Result<SharedState, ApiError> {
+    let state = app.storage.load_preset(name).await?;
+    // Presets saved by older firmware may not pass newer validation
+    set_state(app, state).await
+}
+
+pub async fn rename_preset(
+    app: &AppState,
+    name: &PresetName,
+    new_name: PresetName,
+) -> Result<Presets, ApiError> {
+    app.storage.rename_preset(name, new_name).await?;
+    list_presets(app).await
+}
+
+pub async fn delete_preset(app: &AppState, name: &PresetName) -> Result<Presets, ApiError> {
+    app.storage.delete_preset(name).await?;
+    list_presets(app).await
+}
+
+pub async fn get_playlist(app: &AppState) -> Playlist {
+    app.playlist.playlist().await
+}
+
+pub async fn set_playlist(app: &AppState, playlist: Playlist) -> Result<Playlist, ApiError> {
+    playlist.validate()?;
+    app.storage.save_playlist(&playlist).await?;
+    app.playlist.set_playlist(playlist.clone()).await;
+    Ok(playlist)
+}
+
+pub async fn playlist_status(app: &AppState) -> PlaylistStatus {
+    app.playlist.status().await
+}
+
+pub async fn control_playlist(app: &AppState, command: PlaylistCommand) {
+    app.playlist.send(command).await
+}
+
+pub async fn get_dmx_config(app: &AppState) -> DmxConfig {
+    app.dmx.config()
+}
+
+pub async fn set_dmx_config(app: &AppState, config: DmxConfig) -> Result<DmxConfig, ApiError> {
+    config.validate()?;
+    app.storage.save_dmx_config(&config).await?;
+    app.dmx.set_config(config);
+    Ok(config)
+}
+
+pub async fn get_mqtt_config(app: &AppState) -> RedactedMqttConfig {
+    RedactedMqttConfig::from(&app.mqtt.config())
+}
+
+pub async fn set_mqtt_config(
+    app: &AppState,
+    update: MqttConfigUpdate,
+) -> Result<RedactedMqttConfig, ApiError> {
+    let config = update.apply(&app.mqtt.config());
+    config.validate()?;
+    app.storage.save_mqtt_config(&config).await?;
+    let redacted = RedactedMqttConfig::from(&config);
+    app.mqtt.set_config(config);
+    Ok(redacted)
+}
+
+pub async fn get_midi_config(app: &AppState) -> MidiConfig {
+    app.midi.config()
+}
+
+pub async fn set_midi_config(app: &AppState, config: MidiConfig) -> Result<MidiConfig, ApiError> {
+    config.validate()?;
+    app.storage.save_midi_config(&config).await?;
+    app.midi.set_config(config.clone());
+    Ok(config)
+}
+
+/// The network config that will be used from the next boot.
+pub async fn get_network_config(app: &AppState) -> Result<NetworkConfig, ApiError> {
+    Ok(app.storage.load_network_config().await?.unwrap_or_default())
+}
+
+/// Save a network config to be used from the next boot.
+pub async fn set_network_config(
+    app: &AppState,
+    config: NetworkConfig,
+) -> Result<NetworkConfig, ApiError> {
+    config.validate()?;
+    app.storage.save_network_config(&config).await?;
+    event_log::record(format_args!("Network config saved, restart to apply"));
+    Ok(config)
+}
+
+/// Which board this is and how to reach it.
+pub async fn get_info(app: &AppState) -> DeviceInfo {
+    DeviceInfo {
+        name: DEVICE_NAME,
+        version: env!("CARGO_PKG_VERSION"),
+        serial: &app.device.serial,
+        mac: mac_string(app.device.mac),
+        host_mac: mac_string(app.device.host_mac),
+        hostname: &app.network.hostname,
+        address: app.network.address,
+    }
+}
+
+/// How the network services are doing.
+pub fn get_services() -> ServiceStatuses {
+    supervisor::statuses()
+}
+
+/// A request from a message based client, mirroring the HTTP routes.
+// Only ever one at a time on a client's stack, so the size is fine
+#[allow(clippy::large_enum_variant)]
+#[derive(serde::Deserialize)]
+pub enum Command {
+    GetState,
+    SetState(SharedState),
+    TogglePower,
+    SetLamp {
+        id: usize,
+        mode: u8,
+    },
+    ListPresets,
+    SavePreset(PresetName),
+    RecallPreset(PresetName),
+    RenamePreset {
+        name: PresetName,
+        new_name: PresetName,
+    },
+    DeletePreset(PresetName),
+    GetPlaylist,
+    SetPlaylist(Playlist),
+    GetPlaylistStatus,
+    StartPlaylist,
+    StopPlaylist,
+    NextScene,
+    GetDmxConfig,
+    SetDmxConfig(DmxConfig),
+    GetMqttConfig,
+    SetMqttConfig(MqttConfigUpdate),
+    GetMidiConfig,
+    SetMidiConfig(MidiConfig),
+    GetNetworkConfig,
+    SetNetworkConfig(NetworkConfig),
+    GetInfo,
+    GetServices,
+}
+
+#[derive(serde::Serialize)]
+#[serde(untagged)]
+pub enum ErrorReply {
+    Invalid(ValidationError),
+    /// A storage failure, or a message that isn't a `Command`
+    Failed {
+        reason: &'static str,
+    },
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(serde::Serialize)]
+pub enum Reply {
+    State(SharedState),
+    Power(bool),
+    Presets(Presets),
+    Playlist(Playlist),
+    PlaylistStatus(PlaylistStatus),
+    DmxConfig(DmxConfig),
+    MqttConfig(RedactedMqttConfig),
+    MidiConfig(MidiConfig),
+    NetworkConfig(NetworkConfig),
+    Info(DeviceInfo),
+    Services(ServiceStatuses),
+    Done,
+    Error(ErrorReply),
+}
+
+impl From<ApiError> for Reply {
+    fn from(err: ApiError) -> Self {
+        Reply::Error(match err {
+            ApiError::Invalid(err) => ErrorReply::Invalid(err),
+            ApiError::Storage(err) => ErrorReply::Failed {
+                reason: err.reason(),
+            },
+        })
+    }
+}
+
+impl Command {
+    pub async fn execute(self, app: &AppState) -> Reply {
+        let result = match self {
+            Command::GetState => Ok(Reply::State(get_state(app).await)),
+            Command::SetState(state) => set_state(app, state).await.map(Reply::State),
+            Command::TogglePower => Ok(Reply::Power(toggle_power(app).await)),
+            Command::SetLamp { id, mode } => {
+                set_lamp(app, id, mode).await;
+                Ok(Reply::Done)
+            }
+            Command::ListPresets => list_presets(app).await.map(Reply::Presets),
+            Command::SavePreset(name) => save_preset(app, name).await.map(Reply::Presets),
+            Command::RecallPreset(name) => recall_preset(app, &name).await.map(Reply::State),
+            Command::RenamePreset { name, new_name } => rename_preset(app, &name, new_name)
+                .await
+                .map(Reply::Presets),
+            Command::DeletePreset(name) => delete_preset(app, &name).await.map(Reply::Presets),
+            Command::GetPlaylist => Ok(Reply::Playlist(get_playlist(app).await)),
+            Command::SetPlaylist(playlist) => {
+                set_playlist(app, playlist).await.map(Reply::Playlist)
+            }
+            Command::GetPlaylistStatus => Ok(Reply::PlaylistStatus(playlist_status(app).await)),
+            Command::StartPlaylist => {
+                control_playlist(app, PlaylistCommand::Start).await;
+                Ok(Reply::Done)
+            }
+            Command::StopPlaylist => {
+                control_playlist(app, PlaylistCommand::Stop).await;
+                Ok(Reply::Done)
+            }
+            Command::NextScene => {
+                control_playlist(app, PlaylistCommand::Next).await;
+                Ok(Reply::Done)
+            }
+            Command::GetDmxConfig => Ok(Reply::DmxConfig(get_dmx_config(app).await)),
+            Command::SetDmxConfig(config) => {
+                set_dmx_config(app, config).await.map(Reply::DmxConfig)
+            }
+            Command::GetMqttConfig => Ok(Reply::MqttConfig(get_mqtt_config(app).await)),
+            Command::SetMqttConfig(config) => {
+                set_mqtt_config(app, config).await.map(Reply::MqttConfig)
+            }
+            Command::GetMidiConfig => Ok(Reply::MidiConfig(get_midi_config(app).await)),
+            Command::SetMidiConfig(config) => {
+                set_midi_config(app, config).await.map(Reply::MidiConfig)
+            }
+            Command::GetNetworkConfig => get_network_config(app).await.map(Reply::NetworkConfig),
+            Command::SetNetworkConfig(config) => set_network_config(app, config)
+                .await
+                .map(Reply::NetworkConfig),
+            Command::GetInfo => Ok(Reply::Info(get_info(app).await)),
+            Command::GetServices => Ok(Reply::Services(get_services())),
+        };
+        result.unwrap_or_else(Reply::from)
+    }
+}
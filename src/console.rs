@@ -0,0 +1,441 @@
+//! Line based command shell for the USB serial port, for when the network
+//! won't come up. Most commands are shorthands for an `api::Command`, and any
+//! `Command` can also be typed as JSON, just as over the WebSocket. Replies
+//! are printed as the JSON of the `api::Reply`.
+
+use core::fmt::Write as _;
+
+use embassy_time::Timer;
+use embedded_io_async::{Read, Write};
+use heapless::{String, Vec};
+
+use crate::api::{self, Command, ErrorReply, Reply};
+use crate::device_id::mac_string;
+use crate::event_log;
+use crate::state::{AppState, SharedState};
+use crate::storage::PresetName;
+use crate::supervisor::{self, ServiceState};
+use crate::DEVICE_NAME;
+
+const MAX_LINE_LEN: usize = 1024;
+const REPLY_BUFFER_LEN: usize = 2048;
+const PROMPT: &str = "> ";
+
+const HELP: &str = "\
+state                   show the state
+state <json>            replace the state
+power                   toggle the lights
+lamp <0-5> <off|on|flicker>
+presets                 list presets
+save|recall|delete <name>
+rename <name> <new name>
+playlist [start|stop|next]
+dmx|mqtt|midi|network   show a config
+info                    show the board's serial number and addresses
+net                     show the network status and services
+logs                    show recent events
+reboot                  restart the board
+<json>                  run any API command, e.g. \"GetState\"
+";
+
+/// What a line asks for.
+// Only ever one at a time, so the size is fine
+#[allow(clippy::large_enum_variant)]
+enum Action {
+    Run(Command),
+    Help,
+    Net,
+    Logs,
+    Reboot,
+}
+
+fn parse(line: &str) -> Result<Action, &'static str> {
+    if line.starts_with(['{', '"']) {
+        return serde_json_core::from_str_escaped::<Command>(line, &mut [0; 64])
+            .map(|(command, _)| Action::Run(command))
+            .map_err(|_| "Unrecognised command");
+    }
+
+    let (word, rest) = line.split_once(' ').unwrap_or((line, ""));
+    let rest = rest.trim();
+    let preset = || rest.parse::<PresetName>();
+    let command = match (word, rest) {
+        ("help" | "?", _) => return Ok(Action::Help),
+        ("net", "") => return Ok(Action::Net),
+        ("logs", "") => return Ok(Action::Logs),
+        ("reboot", "") => return Ok(Action::Reboot),
+        ("state", "") => Command::GetState,
+        ("state", json) => {
+            let (state, _) = serde_json_core::from_str_escaped::<SharedState>(json, &mut [0; 64])
+                .map_err(|_| "Invalid state")?;
+            Command::SetState(state)
+        }
+        ("power", "") => Command::TogglePower,
+        ("lamp", args) => {
+            let (id, mode) = args.split_once(' ').ok_or("Usage: lamp <id> <mode>")?;
+            let id = id.parse().map_err(|_| "Invalid lamp")?;
+            let mode = match mode.trim() {
+                "off" | "0" => 0,
+                "on" | "1" => 1,
+                "flicker" | "2" => 2,
+                _ => return Err("Mode must be off, on or flicker"),
+            };
+            Command::SetLamp { id, mode }
+        }
+        ("presets", "") => Command::ListPresets,
+        ("save", _) => Command::SavePreset(preset()?),
+        ("recall", _) => Command::RecallPreset(preset()?),
+        ("delete", _) => Command::DeletePreset(preset()?),
+        ("rename", args) => {
+            let (name, new_name) = args
+                .split_once(' ')
+                .ok_or("Usage: rename <name> <new name>")?;
+            Command::RenamePreset {
+                name: name.parse()?,
+                new_name: new_name.trim().parse()?,
+            }
+        }
+        ("playlist", "") => Command::GetPlaylistStatus,
+        ("playlist", "start") => Command::StartPlaylist,
+        ("playlist", "stop") => Command::StopPlaylist,
+        ("playlist", "next") => Command::NextScene,
+        ("dmx", "") => Command::GetDmxConfig,
+        ("mqtt", "") => Command::GetMqttConfig,
+        ("midi", "") => Command::GetMidiConfig,
+        ("network", "") => Command::GetNetworkConfig,
+        ("info", "") => Command::GetInfo,
+        _ => return Err("Unknown command, try help"),
+    };
+    Ok(Action::Run(command))
+}
+
+/// One session on the serial port, until the host closes it.
+pub struct Console<T, L> {
+    io: T,
+    app: AppState,
+    /// Whether the network link is up, which only the caller knows
+    link_up: L,
+    /// Terminals leave echoing typed characters to the other end
+    echo: bool,
+    line: Vec<u8, MAX_LINE_LEN>,
+    /// The line grew past `MAX_LINE_LEN` and is being skipped
+    overflowed: bool,
+    last_byte: u8,
+}
+
+impl<T: Read + Write, L: Fn() -> bool> Console<T, L> {
+    pub fn new(io: T, app: AppState, link_up: L, echo: bool) -> Self {
+        Self {
+            io,
+            app,
+            link_up,
+            echo,
+            line: Vec::new(),
+            overflowed: false,
+            last_byte: 0,
+        }
+    }
+
+    pub async fn run(mut self) -> Result<(), T::Error> {
+        let mut banner = String::<64>::new();
+        let _ = write!(
+            banner,
+            "{DEVICE_NAME} {}, type help for commands\r\n",
+            env!("CARGO_PKG_VERSION")
+        );
+        self.write(&banner).await?;
+        self.write(PROMPT).await?;
+
+        let mut buffer = [0; 64];
+        loop {
+            let len = self.io.read(&mut buffer).await?;
+            if len == 0 {
+                return Ok(());
+            }
+            for &byte in &buffer[..len] {
+                self.handle_byte(byte).await?;
+            }
+            self.io.flush().await?;
+        }
+    }
+
+    async fn handle_byte(&mut self, byte: u8) -> Result<(), T::Error> {
+        let last_byte = core::mem::replace(&mut self.last_byte, byte);
+        match byte {
+            // Terminals end lines with \r, \r\n or \n
+            b'\n' if last_byte == b'\r' => Ok(()),
+            b'\r' | b'\n' => {
+                if self.echo {
+                    self.write("\r\n").await?;
+                }
+                self.end_line().await?;
+                self.write(PROMPT).await
+            }
+            // Backspace and delete
+            0x08 | 0x7F => {
+                if self.line.pop().is_some() && self.echo {
+                    self.write("\x08 \x08").await?;
+                }
+                Ok(())
+            }
+            // Ctrl-C
+            0x03 => {
+                self.line.clear();
+                self.overflowed = false;
+                self.write("^C\r\n").await?;
+                self.write(PROMPT).await
+            }
+            byte if byte >= 0x20 => {
+                if self.line.push(byte).is_err() {
+                    self.overflowed = true;
+                } else if self.echo {
+                    self.io.write_all(&[byte]).await?;
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn end_line(&mut self) -> Result<(), T::Error> {
+        let line = core::mem::take(&mut self.line);
+        if core::mem::take(&mut self.overflowed) {
+            return self.error("Line too long").await;
+        }
+        let Ok(line) = core::str::from_utf8(&line) else {
+            return self.error("Invalid UTF-8").await;
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(());
+        }
+
+        match parse(line) {
+            Ok(Action::Run(command)) => {
+                let reply = command.execute(&self.app).await;
+                self.reply(&reply).await
+            }
+            Ok(Action::Help) => self.write_lines(HELP).await,
+            Ok(Action::Net) => self.network_status().await,
+            Ok(Action::Logs) => self.logs().await,
+            Ok(Action::Reboot) => {
+                self.write("Restarting\r\n").await?;
+                self.io.flush().await?;
+                // Give the host time to read it before the port disappears
+                Timer::after_millis(100).await;
+                crate::reboot()
+            }
+            Err(reason) => self.error(reason).await,
+        }
+    }
+
+    async fn network_status(&mut self) -> Result<(), T::Error> {
+        let network = self.app.network;
+        let device = self.app.device;
+        let mut text = String::<512>::new();
+        let _ = write!(
+            text,
+            "link:           {}\r\n\
+             address:        {}/{}\r\n\
+             hostname:       {}.local\r\n\
+             dhcp pool:      {} - {}, {} s leases\r\n\
+             captive portal: {}\r\n\
+             mac:            {}\r\n\
+             host mac:       {}\r\n",
+            if (self.link_up)() { "up" } else { "down" },
+            network.address,
+            network.prefix_len,
+            network.hostname,
+            network.dhcp_pool_start,
+            network.dhcp_pool_end(),
+            network.dhcp_lease_secs,
+            if network.captive_portal { "on" } else { "off" },
+            mac_string(device.mac),
+            mac_string(device.host_mac),
+        );
+        if api::get_network_config(&self.app).await.ok().as_ref() != Some(network) {
+            let _ = write!(text, "Saved changes take effect after a restart\r\n");
+        }
+        self.write(&text).await?;
+
+        for status in api::get_services() {
+            let mut text = String::<{ supervisor::MAX_ERROR_LEN + 64 }>::new();
+            let name = status.service.name();
+            let _ = write!(text, "{}:{:width$}", name, "", width = 15 - name.len());
+            let _ = match status.state {
+                ServiceState::Disabled => write!(text, "off"),
+                ServiceState::Running => write!(text, "running"),
+                ServiceState::Restarting => write!(text, "restarting"),
+            };
+            if let Some(error) = &status.last_error {
+                let _ = write!(text, ", failures: {}, last: {}", status.failures, error);
+            }
+            self.write(&text).await?;
+            self.write("\r\n").await?;
+        }
+        Ok(())
+    }
+
+    async fn logs(&mut self) -> Result<(), T::Error> {
+        for event in event_log::events() {
+            let mut text = String::<{ event_log::MAX_EVENT_LEN + 16 }>::new();
+            let _ = write!(text, "[{:>6}] {}\r\n", event.at, event.message);
+            self.write(&text).await?;
+        }
+        Ok(())
+    }
+
+    async fn error(&mut self, reason: &'static str) -> Result<(), T::Error> {
+        self.reply(&Reply::Error(ErrorReply::Failed { reason }))
+            .await
+    }
+
+    async fn reply(&mut self, reply: &Reply) -> Result<(), T::Error> {
+        let mut buffer = [0; REPLY_BUFFER_LEN];
+        match serde_json_core::to_slice(reply, &mut buffer) {
+            Ok(len) => {
+                self.io.write_all(&buffer[..len]).await?;
+                self.write("\r\n").await
+            }
+            Err(_) => self.write("Reply too long\r\n").await,
+        }
+    }
+
+    /// Write text with `\n` line endings, as terminals expect `\r\n`.
+    async fn write_lines(&mut self, text: &str) -> Result<(), T::Error> {
+        for line in text.lines() {
+            self.write(line).await?;
+            self.write("\r\n").await?;
+        }
+        Ok(())
+    }
+
+    async fn write(&mut self, text: &str) -> Result<(), T::Error> {
+        self.io.write_all(text.as_bytes()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(line: &str) -> Command {
+        match parse(line) {
+            Ok(Action::Run(command)) => command,
+            _ => panic!("{line} isn't a command"),
+        }
+    }
+
+    fn named(name: &PresetName, expected: &str) -> bool {
+        name.as_str() == expected
+    }
+
+    #[test]
+    fn parses_shell_commands() {
+        assert!(matches!(parse("help"), Ok(Action::Help)));
+        assert!(matches!(parse("?"), Ok(Action::Help)));
+        assert!(matches!(parse("net"), Ok(Action::Net)));
+        assert!(matches!(parse("logs"), Ok(Action::Logs)));
+        assert!(matches!(parse("reboot"), Ok(Action::Reboot)));
+    }
+
+    #[test]
+    fn parses_shorthands() {
+        assert!(matches!(run("state"), Command::GetState));
+        assert!(matches!(run("power"), Command::TogglePower));
+        assert!(matches!(run("presets"), Command::ListPresets));
+        assert!(matches!(run("playlist"), Command::GetPlaylistStatus));
+        assert!(matches!(run("playlist start"), Command::StartPlaylist));
+        assert!(matches!(run("playlist stop"), Command::StopPlaylist));
+        assert!(matches!(run("playlist next"), Command::NextScene));
+        assert!(matches!(run("dmx"), Command::GetDmxConfig));
+        assert!(matches!(run("mqtt"), Command::GetMqttConfig));
+        assert!(matches!(run("midi"), Command::GetMidiConfig));
+        assert!(matches!(run("network"), Command::GetNetworkConfig));
+        assert!(matches!(run("info"), Command::GetInfo));
+    }
+
+    #[test]
+    fn parses_a_state() {
+        let Command::SetState(state) = run(
+            r#"state {"streetlamps_enabled":false,"streetlamps_brightness":9,"streetlamps_modes":["On","On","On","On","On","On"],"underpass_lights_state":"Off"}"#,
+        ) else {
+            panic!("not SetState");
+        };
+        assert!(!state.streetlamps_enabled);
+        assert_eq!(state.streetlamps_brightness, 9);
+        assert!(matches!(parse("state {"), Err("Invalid state")));
+    }
+
+    #[test]
+    fn parses_lamp_modes() {
+        assert!(matches!(
+            run("lamp 3 flicker"),
+            Command::SetLamp { id: 3, mode: 2 }
+        ));
+        assert!(matches!(
+            run("lamp 0 on"),
+            Command::SetLamp { id: 0, mode: 1 }
+        ));
+        assert!(matches!(
+            run("lamp 5 0"),
+            Command::SetLamp { id: 5, mode: 0 }
+        ));
+        assert!(matches!(parse("lamp 3"), Err("Usage: lamp <id> <mode>")));
+        assert!(matches!(parse("lamp x on"), Err("Invalid lamp")));
+        assert!(matches!(
+            parse("lamp 1 dim"),
+            Err("Mode must be off, on or flicker")
+        ));
+    }
+
+    #[test]
+    fn parses_preset_names() {
+        assert!(matches!(run("save night"), Command::SavePreset(name) if named(&name, "night")));
+        assert!(matches!(
+            run("recall late night"),
+            Command::RecallPreset(name) if named(&name, "late night")
+        ));
+        assert!(
+            matches!(run("delete night"), Command::DeletePreset(name) if named(&name, "night"))
+        );
+        assert!(parse("save").is_err());
+        assert!(parse("save 0123456789012345678901234").is_err());
+    }
+
+    #[test]
+    fn parses_renames() {
+        assert!(matches!(
+            run("rename night late night"),
+            Command::RenamePreset { name, new_name }
+                if named(&name, "night") && named(&new_name, "late night")
+        ));
+        assert!(matches!(
+            parse("rename night"),
+            Err("Usage: rename <name> <new name>")
+        ));
+    }
+
+    #[test]
+    fn parses_json_commands() {
+        assert!(matches!(run(r#""GetState""#), Command::GetState));
+        assert!(matches!(
+            run(r#"{"SetLamp":{"id":2,"mode":1}}"#),
+            Command::SetLamp { id: 2, mode: 1 }
+        ));
+        assert!(matches!(parse("{"), Err("Unrecognised command")));
+    }
+
+    #[test]
+    fn rejects_unknown_commands() {
+        assert!(matches!(parse("dance"), Err("Unknown command, try help")));
+        assert!(matches!(
+            parse("power on"),
+            Err("Unknown command, try help")
+        ));
+        assert!(matches!(
+            parse("playlist pause"),
+            Err("Unknown command, try help")
+        ));
+    }
+}
@@ -0,0 +1,121 @@
+//! Identity of this particular board, worked out from the flash chip's unique
+//! ID so that several dioramas plugged into one host can be told apart by its
+//! network manager and udev rules.
+
+use core::fmt::Write as _;
+use core::net::Ipv4Addr;
+
+use heapless::String;
+
+pub const UNIQUE_ID_LEN: usize = 8;
+
+pub type Mac = [u8; 6];
+
+pub struct DeviceId {
+    /// The unique ID in upper case hex, as the USB serial number
+    pub serial: String<{ UNIQUE_ID_LEN * 2 }>,
+    /// Our end of the USB network
+    pub mac: Mac,
+    /// The host's end of the USB network
+    pub host_mac: Mac,
+}
+
+impl DeviceId {
+    pub fn new(unique_id: [u8; UNIQUE_ID_LEN]) -> Self {
+        let mut serial = String::new();
+        for byte in unique_id {
+            let _ = write!(serial, "{byte:02X}");
+        }
+
+        // FNV-1a, so IDs that differ in any byte give unrelated addresses
+        let hash = unique_id
+            .iter()
+            .fold(0xcbf2_9ce4_8422_2325_u64, |hash, &byte| {
+                (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+            });
+        let mut mac = [0; 6];
+        mac.copy_from_slice(&hash.to_be_bytes()[..6]);
+        // Locally administered and unicast
+        mac[0] = (mac[0] & 0xFC) | 0x02;
+        let mut host_mac = mac;
+        host_mac[5] ^= 0x01;
+
+        Self {
+            serial,
+            mac,
+            host_mac,
+        }
+    }
+
+    /// `mac` as WLED and Home Assistant write it, in lower case hex without
+    /// separators.
+    pub fn mac_hex(&self) -> String<12> {
+        let mut hex = String::new();
+        for byte in self.mac {
+            let _ = write!(hex, "{byte:02x}");
+        }
+        hex
+    }
+}
+
+/// A MAC address written the usual way, e.g. `02:1a:2b:3c:4d:5e`.
+pub fn mac_string(mac: Mac) -> String<17> {
+    let mut string = String::new();
+    for (i, byte) in mac.iter().enumerate() {
+        let separator = if i == 0 { "" } else { ":" };
+        let _ = write!(string, "{separator}{byte:02x}");
+    }
+    string
+}
+
+/// What the `/info` endpoint reports about the board.
+#[derive(serde::Serialize)]
+pub struct DeviceInfo {
+    pub name: &'static str,
+    pub version: &'static str,
+    pub serial: &'static str,
+    pub mac: String<17>,
+    pub host_mac: String<17>,
+    pub hostname: &'static str,
+    pub address: Ipv4Addr,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UNIQUE_ID: [u8; UNIQUE_ID_LEN] = [0xE6, 0x61, 0x41, 0x04, 0x03, 0x4F, 0x7B, 0x2A];
+
+    #[test]
+    fn keeps_the_serial_and_macs_stable() {
+        // Host network manager and udev rules match on these, so changing how
+        // they're worked out would break them
+        let device = DeviceId::new(UNIQUE_ID);
+        assert_eq!(device.serial, "E6614104034F7B2A");
+        assert_eq!(mac_string(device.mac), "da:7b:bd:91:bf:ee");
+        assert_eq!(mac_string(device.host_mac), "da:7b:bd:91:bf:ef");
+        assert_eq!(device.mac_hex(), "da7bbd91bfee");
+    }
+
+    #[test]
+    fn makes_locally_administered_unicast_macs() {
+        for unique_id in [[0; UNIQUE_ID_LEN], [0xFF; UNIQUE_ID_LEN], UNIQUE_ID] {
+            let device = DeviceId::new(unique_id);
+            for mac in [device.mac, device.host_mac] {
+                assert_eq!(mac[0] & 0x02, 0x02, "not locally administered");
+                assert_eq!(mac[0] & 0x01, 0x00, "not unicast");
+            }
+            assert_ne!(device.mac, device.host_mac);
+        }
+    }
+
+    #[test]
+    fn gives_each_board_its_own_mac() {
+        let mut other_id = UNIQUE_ID;
+        other_id[UNIQUE_ID_LEN - 1] ^= 0x01;
+        let (device, other) = (DeviceId::new(UNIQUE_ID), DeviceId::new(other_id));
+        assert_ne!(device.serial, other.serial);
+        assert_ne!(device.mac, other.mac);
+        assert_ne!(device.mac, other.host_mac);
+    }
+}
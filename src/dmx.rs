@@ -0,0 +1,414 @@
+//! E1.31 (sACN) and Art-Net receivers, so show-control software can drive the
+//! lights from DMX channels. While a universe is being streamed to us it takes
+//! over from `SharedState` through `LiveInputs`, and once it stops the lights
+//! go back to whatever the state says.
+
+use core::net::Ipv4Addr;
+
+use defmt::Format;
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex,
+    watch::{Receiver, Watch},
+};
+use embassy_time::Duration;
+use smart_leds::RGB8;
+
+use crate::live::LiveInputs;
+use crate::underpass_lights::NUM_LEDS;
+use crate::validation::{Validate, ValidationError};
+
+pub const E131_PORT: u16 = 5568;
+pub const ARTNET_PORT: u16 = 6454;
+
+/// Largest E1.31 data packet, with a full universe of 512 channels
+pub const MAX_PACKET_LEN: usize = 638;
+
+const E131_PACKET_ID: &[u8] = b"ASC-E1.17\0\0\0";
+const E131_VECTOR_ROOT_DATA: u32 = 0x4;
+const E131_VECTOR_FRAMING_DMP: u32 = 0x2;
+const E131_VECTOR_DMP_SET_PROPERTY: u8 = 0x2;
+const E131_OPTION_PREVIEW: u8 = 0x80;
+const E131_OPTION_TERMINATED: u8 = 0x40;
+
+const ARTNET_ID: &[u8] = b"Art-Net\0";
+const ARTNET_OP_DMX: u16 = 0x5000;
+
+/// How many DMX receiver tasks watch the config
+const MAX_CONFIG_WATCHERS: usize = 2;
+
+#[derive(serde::Deserialize, serde::Serialize, Format, Clone, Copy, PartialEq)]
+pub struct DmxConfig {
+    pub enabled: bool,
+    /// E1.31 universe to listen to. Art-Net numbers its universes from 0
+    /// rather than 1, so Art-Net port-address 0 is universe 1 here and so on.
+    pub universe: u16,
+    /// DMX address of the first underpass LED's red channel, followed by
+    /// green and blue then the next LED
+    pub start_address: u16,
+    /// DMX address of the first streetlamp's brightness, one channel per
+    /// lamp, or `None` to leave the streetlamps alone
+    pub streetlamps_address: Option<u16>,
+    /// How long after the last packet to go back to the stored state
+    pub timeout_ms: u16,
+}
+
+impl Default for DmxConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            universe: 1,
+            start_address: 1,
+            streetlamps_address: None,
+            // E1.31's network data loss timeout
+            timeout_ms: 2500,
+        }
+    }
+}
+
+impl Validate for DmxConfig {
+    fn validate(&self) -> Result<(), ValidationError> {
+        if !(1..=63999).contains(&self.universe) {
+            return Err(ValidationError::new(
+                "universe",
+                "must be between 1 and 63999",
+            ));
+        }
+        if !(1..=465).contains(&self.start_address) {
+            return Err(ValidationError::new(
+                "start_address",
+                "must be between 1 and 465",
+            ));
+        }
+        if let Some(address) = self.streetlamps_address {
+            if !(1..=507).contains(&address) {
+                return Err(ValidationError::new(
+                    "streetlamps_address",
+                    "must be between 1 and 507",
+                ));
+            }
+        }
+        if self.timeout_ms < 100 {
+            return Err(ValidationError::new("timeout_ms", "must be at least 100"));
+        }
+        Ok(())
+    }
+}
+
+/// DMX data for one universe. `channels[0]` is address 1.
+pub struct DmxPacket<'a> {
+    pub universe: u16,
+    pub channels: &'a [u8],
+}
+
+fn u16_at(packet: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([packet[at], packet[at + 1]])
+}
+
+fn u32_at(packet: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([packet[at], packet[at + 1], packet[at + 2], packet[at + 3]])
+}
+
+/// Parse an E1.31 data packet, ignoring preview data and anything that isn't
+/// plain DMX.
+pub fn parse_e131(packet: &[u8]) -> Option<DmxPacket<'_>> {
+    if packet.len() < 126 || &packet[4..16] != E131_PACKET_ID {
+        return None;
+    }
+    if u32_at(packet, 18) != E131_VECTOR_ROOT_DATA
+        || u32_at(packet, 40) != E131_VECTOR_FRAMING_DMP
+        || packet[117] != E131_VECTOR_DMP_SET_PROPERTY
+    {
+        return None;
+    }
+    if packet[112] & (E131_OPTION_PREVIEW | E131_OPTION_TERMINATED) != 0 {
+        return None;
+    }
+
+    // The property values start with the DMX start code
+    let count = u16_at(packet, 123) as usize;
+    let (&start_code, channels) = packet.get(125..125 + count)?.split_first()?;
+    (start_code == 0).then_some(DmxPacket {
+        universe: u16_at(packet, 113),
+        channels,
+    })
+}
+
+/// Parse an ArtDmx packet, numbering its universe as E1.31 would: one more
+/// than the Art-Net port-address.
+pub fn parse_artnet(packet: &[u8]) -> Option<DmxPacket<'_>> {
+    if packet.len() < 18 || &packet[..8] != ARTNET_ID {
+        return None;
+    }
+    if u16::from_le_bytes([packet[8], packet[9]]) != ARTNET_OP_DMX {
+        return None;
+    }
+
+    let length = u16_at(packet, 16) as usize;
+    Some(DmxPacket {
+        universe: (u16::from_le_bytes([packet[14], packet[15]]) & 0x7fff) + 1,
+        channels: packet.get(18..18 + length)?,
+    })
+}
+
+/// Multicast group E1.31 senders use for `universe`.
+pub fn e131_multicast_group(universe: u16) -> Ipv4Addr {
+    let [high, low] = universe.to_be_bytes();
+    Ipv4Addr::new(239, 255, high, low)
+}
+
+/// DMX settings shared between the web API and the receiver tasks.
+pub struct DmxControl {
+    config: Watch<CriticalSectionRawMutex, DmxConfig, MAX_CONFIG_WATCHERS>,
+}
+
+impl DmxControl {
+    pub fn new(config: DmxConfig) -> Self {
+        Self {
+            config: Watch::new_with(config),
+        }
+    }
+
+    pub fn config(&self) -> DmxConfig {
+        self.config.try_get().unwrap_or_default()
+    }
+
+    pub fn set_config(&self, config: DmxConfig) {
+        self.config.sender().send(config);
+    }
+
+    pub fn receiver(
+        &self,
+    ) -> Option<Receiver<'_, CriticalSectionRawMutex, DmxConfig, MAX_CONFIG_WATCHERS>> {
+        self.config.receiver()
+    }
+}
+
+/// Turns DMX packets into lighting for `LiveInputs`.
+#[derive(Clone, Copy)]
+pub struct DmxReceiver {
+    control: &'static DmxControl,
+    live: &'static LiveInputs,
+}
+
+impl DmxReceiver {
+    pub fn new(control: &'static DmxControl, live: &'static LiveInputs) -> Self {
+        Self { control, live }
+    }
+
+    /// Handle a packet that arrived on `port`.
+    pub fn handle(&self, port: u16, packet: &[u8]) {
+        let config = self.control.config();
+        if !config.enabled {
+            return;
+        }
+        let dmx = match port {
+            E131_PORT => parse_e131(packet),
+            ARTNET_PORT => parse_artnet(packet),
+            _ => None,
+        };
+        let Some(dmx) = dmx.filter(|dmx| dmx.universe == config.universe) else {
+            return;
+        };
+
+        // Senders may leave off channels they don't use
+        let channel = |address: u16| {
+            (address as usize)
+                .checked_sub(1)
+                .and_then(|i| dmx.channels.get(i))
+                .copied()
+                .unwrap_or(0)
+        };
+        let timeout = Duration::from_millis(config.timeout_ms as u64);
+
+        let leds: [RGB8; NUM_LEDS] = core::array::from_fn(|i| {
+            let address = config.start_address + 3 * i as u16;
+            RGB8::new(channel(address), channel(address + 1), channel(address + 2))
+        });
+        self.live.underpass.send(leds, timeout);
+
+        if let Some(address) = config.streetlamps_address {
+            let brightnesses = core::array::from_fn(|i| channel(address + i as u16));
+            self.live.streetlamps.send(brightnesses, timeout);
+        }
+    }
+
+    /// Receive packets on `port` for as long as it can be listened on. E1.31
+    /// is multicast to a group per universe, which is joined and rejoined as
+    /// the config changes.
+    #[cfg(target_os = "none")]
+    pub async fn run(
+        self,
+        stack: embassy_net::Stack<'static>,
+        port: u16,
+    ) -> Result<(), embassy_net::udp::BindError> {
+        use embassy_futures::select::{select, Either};
+        use embassy_net::udp::{PacketMetadata, UdpSocket};
+
+        let mut rx_meta = [PacketMetadata::EMPTY; 4];
+        let mut rx_buffer = [0; 2 * MAX_PACKET_LEN];
+        let mut tx_meta = [PacketMetadata::EMPTY; 1];
+        let mut tx_buffer = [0; 64];
+        let mut socket = UdpSocket::new(
+            stack,
+            &mut rx_meta,
+            &mut rx_buffer,
+            &mut tx_meta,
+            &mut tx_buffer,
+        );
+        socket.bind(port)?;
+
+        let mut config_changes = self.control.receiver().unwrap();
+        let mut joined = None;
+        let mut packet = [0; MAX_PACKET_LEN];
+        loop {
+            let config = self.control.config();
+            let group = (port == E131_PORT && config.enabled)
+                .then(|| e131_multicast_group(config.universe));
+            if group != joined {
+                if let Some(old) = joined {
+                    let _ = stack.leave_multicast_group(old);
+                }
+                if let Some(new) = group {
+                    if stack.join_multicast_group(new).is_err() {
+                        defmt::info!("Couldn't join E1.31 multicast group");
+                    }
+                }
+                joined = group;
+            }
+
+            match select(socket.recv_from(&mut packet), config_changes.changed()).await {
+                Either::First(Ok((len, _))) => self.handle(port, &packet[..len]),
+                Either::First(Err(_)) | Either::Second(_) => {}
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "none")]
+#[embassy_executor::task(pool_size = 2)]
+pub async fn dmx_task(stack: embassy_net::Stack<'static>, port: u16, receiver: DmxReceiver) -> ! {
+    use crate::supervisor::{supervise, Service};
+
+    let service = match port {
+        E131_PORT => Service::E131,
+        _ => Service::ArtNet,
+    };
+    supervise(service, || receiver.run(stack, port)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const E131_HEADER_LEN: usize = 126;
+
+    /// An E1.31 data packet for `universe`, with `channels` after the start code.
+    fn e131(universe: u16, start_code: u8, channels: &[u8]) -> ([u8; MAX_PACKET_LEN], usize) {
+        let mut packet = [0; MAX_PACKET_LEN];
+        packet[..4].copy_from_slice(&[0x00, 0x10, 0x00, 0x00]);
+        packet[4..16].copy_from_slice(b"ASC-E1.17\0\0\0");
+        packet[18..22].copy_from_slice(&[0, 0, 0, 0x04]);
+        packet[40..44].copy_from_slice(&[0, 0, 0, 0x02]);
+        packet[108] = 100; // Priority
+        packet[113..115].copy_from_slice(&universe.to_be_bytes());
+        packet[117] = 0x02;
+        packet[118] = 0xa1;
+        packet[121..123].copy_from_slice(&[0, 1]);
+        packet[123..125].copy_from_slice(&(channels.len() as u16 + 1).to_be_bytes());
+        packet[125] = start_code;
+        packet[E131_HEADER_LEN..E131_HEADER_LEN + channels.len()].copy_from_slice(channels);
+        (packet, E131_HEADER_LEN + channels.len())
+    }
+
+    /// An ArtDmx packet for `port_address`.
+    fn artnet(port_address: u16, channels: &[u8]) -> ([u8; MAX_PACKET_LEN], usize) {
+        let mut packet = [0; MAX_PACKET_LEN];
+        packet[..8].copy_from_slice(b"Art-Net\0");
+        packet[8..10].copy_from_slice(&0x5000u16.to_le_bytes());
+        packet[11] = 14; // Protocol version
+        packet[14..16].copy_from_slice(&port_address.to_le_bytes());
+        packet[16..18].copy_from_slice(&(channels.len() as u16).to_be_bytes());
+        packet[18..18 + channels.len()].copy_from_slice(channels);
+        (packet, 18 + channels.len())
+    }
+
+    #[test]
+    fn parses_e131() {
+        let (packet, len) = e131(0x1234, 0, &[1, 2, 3]);
+        let dmx = parse_e131(&packet[..len]).unwrap();
+        assert_eq!(dmx.universe, 0x1234);
+        assert_eq!(dmx.channels, &[1, 2, 3]);
+    }
+
+    #[test]
+    fn parses_a_full_e131_universe() {
+        let (packet, len) = e131(1, 0, &[0xff; 512]);
+        assert_eq!(len, MAX_PACKET_LEN);
+        assert_eq!(parse_e131(&packet[..len]).unwrap().channels.len(), 512);
+    }
+
+    #[test]
+    fn ignores_e131_that_isnt_plain_dmx() {
+        let (packet, len) = e131(1, 0xdd, &[1, 2, 3]);
+        assert!(parse_e131(&packet[..len]).is_none());
+
+        let (mut packet, len) = e131(1, 0, &[1, 2, 3]);
+        packet[112] = E131_OPTION_PREVIEW;
+        assert!(parse_e131(&packet[..len]).is_none());
+        packet[112] = E131_OPTION_TERMINATED;
+        assert!(parse_e131(&packet[..len]).is_none());
+    }
+
+    #[test]
+    fn rejects_short_e131() {
+        let (packet, _) = e131(1, 0, &[]);
+        assert!(parse_e131(&packet[..E131_HEADER_LEN - 1]).is_none());
+
+        // Claims more channels than it carries
+        let (packet, len) = e131(1, 0, &[1, 2, 3]);
+        assert!(parse_e131(&packet[..len - 1]).is_none());
+    }
+
+    #[test]
+    fn rejects_foreign_packets_as_e131() {
+        let (packet, len) = artnet(0, &[0; 200]);
+        assert!(parse_e131(&packet[..len]).is_none());
+
+        let (mut packet, len) = e131(1, 0, &[1, 2, 3]);
+        packet[21] = 0x08; // Extended root vector
+        assert!(parse_e131(&packet[..len]).is_none());
+    }
+
+    #[test]
+    fn numbers_artnet_universes_from_one() {
+        let (packet, len) = artnet(0, &[1, 2, 3, 4]);
+        let dmx = parse_artnet(&packet[..len]).unwrap();
+        assert_eq!(dmx.universe, 1);
+        assert_eq!(dmx.channels, &[1, 2, 3, 4]);
+
+        // Net 1, sub-net 2, universe 3, with the top bit ignored
+        let (packet, len) = artnet(0x8123, &[]);
+        assert_eq!(parse_artnet(&packet[..len]).unwrap().universe, 0x124);
+        let (packet, len) = artnet(0x7fff, &[]);
+        assert_eq!(parse_artnet(&packet[..len]).unwrap().universe, 0x8000);
+    }
+
+    #[test]
+    fn rejects_short_artnet() {
+        let (packet, _) = artnet(0, &[]);
+        assert!(parse_artnet(&packet[..17]).is_none());
+
+        let (packet, len) = artnet(0, &[1, 2, 3, 4]);
+        assert!(parse_artnet(&packet[..len - 1]).is_none());
+    }
+
+    #[test]
+    fn rejects_foreign_packets_as_artnet() {
+        let (mut packet, len) = artnet(0, &[1, 2]);
+        packet[8..10].copy_from_slice(&0x2000u16.to_le_bytes()); // ArtPoll
+        assert!(parse_artnet(&packet[..len]).is_none());
+
+        let (packet, len) = e131(1, 0, &[1, 2, 3]);
+        assert!(parse_artnet(&packet[..len]).is_none());
+    }
+}
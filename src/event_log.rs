@@ -0,0 +1,45 @@
+//! The last few notable events, kept in RAM for the serial console's `logs`
+//! command, as defmt's log only reaches a debug probe.
+
+use core::cell::RefCell;
+use core::fmt::Write as _;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Instant;
+use heapless::{Deque, String};
+
+pub const MAX_EVENTS: usize = 16;
+pub const MAX_EVENT_LEN: usize = 80;
+
+#[derive(Clone)]
+pub struct Event {
+    /// Seconds since boot
+    pub at: u64,
+    pub message: String<MAX_EVENT_LEN>,
+}
+
+static EVENTS: Mutex<CriticalSectionRawMutex, RefCell<Deque<Event, MAX_EVENTS>>> =
+    Mutex::new(RefCell::new(Deque::new()));
+
+/// Remember an event, forgetting the oldest if the log is full. Messages
+/// longer than `MAX_EVENT_LEN` are cut short.
+pub fn record(args: core::fmt::Arguments) {
+    let mut message = String::new();
+    let _ = message.write_fmt(args);
+    let event = Event {
+        at: Instant::now().as_secs(),
+        message,
+    };
+    EVENTS.lock(|events| {
+        let mut events = events.borrow_mut();
+        if events.is_full() {
+            events.pop_front();
+        }
+        let _ = events.push_back(event);
+    });
+}
+
+/// A copy of the log, oldest first.
+pub fn events() -> Deque<Event, MAX_EVENTS> {
+    EVENTS.lock(|events| events.borrow().clone())
+}
@@ -0,0 +1,318 @@
+//! Binary control protocol for host tools, spoken over the vendor defined HID
+//! interface in usb_hid.rs.
+//!
+//! Every report is 64 bytes without a report ID, zero padded. The host sends
+//! `[command, tag, payload..]` as an output report and gets back one input
+//! report `[command, tag, status, payload..]`, with the tag copied so it can
+//! match replies to requests. States are sent as `[len, state..]`, in the
+//! versioned layout used in flash (see schema.rs), and preset names as
+//! `[len, utf-8..]`.
+//!
+//! | Command            | Payload          | Reply payload             |
+//! |--------------------|------------------|---------------------------|
+//! | 0x00 ping          |                  | `[PROTOCOL_VERSION]`      |
+//! | 0x01 get state     |                  | state                     |
+//! | 0x02 set state     | state            | state as applied          |
+//! | 0x03 set power     | `[0 or 1]`       |                           |
+//! | 0x04 toggle power  |                  | `[0 or 1]`, the new power |
+//! | 0x05 set lamp      | `[id, mode]`     |                           |
+//! | 0x06 list presets  | `[index]`        | `[count]` then name       |
+//! | 0x07 recall preset | name             | state                     |
+//! | 0x08 save preset   | name             |                           |
+//!
+//! Lamp modes are 0 for off, 1 for on and 2 for flickering. Listing presets
+//! gives the name at `index`, empty past the end, along with how many there
+//! are.
+
+use crate::api::{self, ApiError};
+use crate::schema;
+use crate::state::{AppState, SharedState};
+use crate::storage::{PresetName, StorageError};
+
+pub const REPORT_LEN: usize = 64;
+/// Bumped whenever a command changes, so tools can tell what they're talking to
+const PROTOCOL_VERSION: u8 = 1;
+
+const PING: u8 = 0x00;
+const GET_STATE: u8 = 0x01;
+const SET_STATE: u8 = 0x02;
+const SET_POWER: u8 = 0x03;
+const TOGGLE_POWER: u8 = 0x04;
+const SET_LAMP: u8 = 0x05;
+const LIST_PRESETS: u8 = 0x06;
+const RECALL_PRESET: u8 = 0x07;
+const SAVE_PRESET: u8 = 0x08;
+
+const STATUS_OK: u8 = 0;
+const STATUS_UNKNOWN_COMMAND: u8 = 1;
+/// The payload couldn't be read
+const STATUS_MALFORMED: u8 = 2;
+/// The state was read but isn't allowed
+const STATUS_INVALID: u8 = 3;
+const STATUS_NOT_FOUND: u8 = 4;
+const STATUS_FULL: u8 = 5;
+const STATUS_FAILED: u8 = 6;
+
+fn api_status(err: ApiError) -> u8 {
+    match err {
+        ApiError::Invalid(_) => STATUS_INVALID,
+        ApiError::Storage(StorageError::NotFound) => STATUS_NOT_FOUND,
+        ApiError::Storage(StorageError::Full) => STATUS_FULL,
+        ApiError::Storage(_) => STATUS_FAILED,
+    }
+}
+
+/// Split a `[len, bytes..]` field off the front of `payload`.
+fn field(payload: &[u8]) -> Result<&[u8], u8> {
+    let (&len, rest) = payload.split_first().ok_or(STATUS_MALFORMED)?;
+    rest.get(..len as usize).ok_or(STATUS_MALFORMED)
+}
+
+fn read_state(payload: &[u8]) -> Result<SharedState, u8> {
+    schema::decode(field(payload)?).map_err(|_| STATUS_MALFORMED)
+}
+
+fn write_state(state: &SharedState, out: &mut [u8]) -> Result<(), u8> {
+    let len = schema::encode(state, &mut out[1..]).map_err(|_| STATUS_FAILED)?;
+    out[0] = len as u8;
+    Ok(())
+}
+
+fn read_preset_name(payload: &[u8]) -> Result<PresetName, u8> {
+    core::str::from_utf8(field(payload)?)
+        .ok()
+        .and_then(|name| name.parse().ok())
+        .ok_or(STATUS_MALFORMED)
+}
+
+/// Carry out one command, writing its reply payload to `out`.
+async fn execute(app: &AppState, command: u8, payload: &[u8], out: &mut [u8]) -> Result<(), u8> {
+    match command {
+        PING => out[0] = PROTOCOL_VERSION,
+        GET_STATE => write_state(&api::get_state(app).await, out)?,
+        SET_STATE => {
+            let state = api::set_state(app, read_state(payload)?)
+                .await
+                .map_err(api_status)?;
+            write_state(&state, out)?;
+        }
+        SET_POWER => match payload[0] {
+            on @ (0 | 1) => api::set_power(app, on == 1).await,
+            _ => return Err(STATUS_MALFORMED),
+        },
+        TOGGLE_POWER => out[0] = api::toggle_power(app).await as u8,
+        SET_LAMP => api::set_lamp(app, payload[0] as usize, payload[1]).await,
+        LIST_PRESETS => {
+            let presets = api::list_presets(app).await.map_err(api_status)?;
+            out[0] = presets.len() as u8;
+            let name = presets
+                .get(payload[0] as usize)
+                .map_or("", PresetName::as_str);
+            out[1] = name.len() as u8;
+            out[2..2 + name.len()].copy_from_slice(name.as_bytes());
+        }
+        RECALL_PRESET => {
+            let name = read_preset_name(payload)?;
+            let state = api::recall_preset(app, &name).await.map_err(api_status)?;
+            write_state(&state, out)?;
+        }
+        SAVE_PRESET => {
+            api::save_preset(app, read_preset_name(payload)?)
+                .await
+                .map_err(api_status)?;
+        }
+        _ => return Err(STATUS_UNKNOWN_COMMAND),
+    }
+    Ok(())
+}
+
+pub async fn handle_request(app: &AppState, request: &[u8; REPORT_LEN]) -> [u8; REPORT_LEN] {
+    let [command, tag, payload @ ..] = request;
+    let mut response = [0; REPORT_LEN];
+    let (header, out) = response.split_at_mut(3);
+    let status = match execute(app, *command, payload, out).await {
+        Ok(()) => STATUS_OK,
+        Err(status) => {
+            out.fill(0);
+            status
+        }
+    };
+    header.copy_from_slice(&[*command, *tag, status]);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device_id::DeviceId;
+    use crate::dmx::DmxControl;
+    use crate::live::LiveInputs;
+    use crate::midi::MidiControl;
+    use crate::mqtt::MqttControl;
+    use crate::network_config::NetworkConfig;
+    use crate::playlist::PlaylistControl;
+    use crate::simulator::RamFlash;
+    use crate::state::{SharedStateWatch, StateWatch};
+    use crate::storage::Storage;
+    use crate::streetlamps::StreetlampMode;
+    use crate::underpass_lights::MAX_TRANSITION_MS;
+
+    fn leak<T>(value: T) -> &'static T {
+        Box::leak(Box::new(value))
+    }
+
+    fn app() -> AppState {
+        AppState {
+            shared: SharedStateWatch(leak(StateWatch::new_with(SharedState::default()))),
+            storage: leak(Storage::new(RamFlash::new(), 0..RamFlash::SIZE as u32)),
+            playlist: leak(PlaylistControl::new(Default::default())),
+            dmx: leak(DmxControl::new(Default::default())),
+            mqtt: leak(MqttControl::new(Default::default())),
+            midi: leak(MidiControl::new(Default::default())),
+            network: leak(NetworkConfig::default()),
+            device: leak(DeviceId::new(*b"HIDTESTS")),
+            live: leak(LiveInputs::new()),
+        }
+    }
+
+    fn send(app: &AppState, command: u8, payload: &[u8]) -> [u8; REPORT_LEN] {
+        let mut request = [0; REPORT_LEN];
+        request[0] = command;
+        request[1] = 0x5A;
+        request[2..2 + payload.len()].copy_from_slice(payload);
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(handle_request(app, &request))
+    }
+
+    fn state_field(state: &SharedState) -> std::vec::Vec<u8> {
+        let mut buffer = [0; REPORT_LEN - 2];
+        write_state(state, &mut buffer).unwrap();
+        buffer[..1 + buffer[0] as usize].to_vec()
+    }
+
+    fn name_field(name: &str) -> std::vec::Vec<u8> {
+        let mut field = std::vec![name.len() as u8];
+        field.extend_from_slice(name.as_bytes());
+        field
+    }
+
+    #[test]
+    fn reads_length_prefixed_fields() {
+        assert_eq!(field(&[3, 1, 2, 3, 4]), Ok(&[1, 2, 3][..]));
+        assert_eq!(field(&[0, 1]), Ok(&[][..]));
+        assert_eq!(field(&[]), Err(STATUS_MALFORMED));
+        assert_eq!(field(&[4, 1, 2, 3]), Err(STATUS_MALFORMED));
+    }
+
+    #[test]
+    fn reads_preset_names() {
+        assert!(read_preset_name(&name_field("night")).unwrap().as_str() == "night");
+        assert_eq!(
+            read_preset_name(&[2, 0xC3, 0x28]).map(|_| ()),
+            Err(STATUS_MALFORMED)
+        );
+        assert_eq!(
+            read_preset_name(&name_field(&"x".repeat(60))).map(|_| ()),
+            Err(STATUS_MALFORMED)
+        );
+    }
+
+    #[test]
+    fn ping_echoes_the_command_and_tag() {
+        let response = send(&app(), PING, &[]);
+        assert_eq!(response[..4], [PING, 0x5A, STATUS_OK, PROTOCOL_VERSION]);
+    }
+
+    #[test]
+    fn rejects_unknown_commands() {
+        let response = send(&app(), 0x7F, &[1, 2, 3]);
+        assert_eq!(response[..3], [0x7F, 0x5A, STATUS_UNKNOWN_COMMAND]);
+        assert!(response[3..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn gets_and_sets_the_state() {
+        let app = app();
+        let response = send(&app, GET_STATE, &[]);
+        assert_eq!(response[2], STATUS_OK);
+        assert!(read_state(&response[3..]).unwrap() == SharedState::default());
+
+        let state = SharedState {
+            streetlamps_enabled: false,
+            ..SharedState::default()
+        };
+        let response = send(&app, SET_STATE, &state_field(&state));
+        assert_eq!(response[..3], [SET_STATE, 0x5A, STATUS_OK]);
+        assert!(read_state(&response[3..]).unwrap() == state);
+        assert!(app.shared.get() == state);
+    }
+
+    #[test]
+    fn rejects_bad_states() {
+        let app = app();
+        let response = send(&app, SET_STATE, &[200, 1, 2, 3]);
+        assert_eq!(response[2], STATUS_MALFORMED);
+
+        let state = SharedState {
+            underpass_transition_ms: MAX_TRANSITION_MS + 1,
+            ..SharedState::default()
+        };
+        let response = send(&app, SET_STATE, &state_field(&state));
+        assert_eq!(response[2], STATUS_INVALID);
+        assert!(response[3..].iter().all(|&byte| byte == 0));
+        assert!(app.shared.get() == SharedState::default());
+    }
+
+    #[test]
+    fn sets_and_toggles_the_power() {
+        let app = app();
+        assert_eq!(send(&app, SET_POWER, &[0])[2], STATUS_OK);
+        assert!(!app.shared.get().streetlamps_enabled);
+        assert_eq!(send(&app, SET_POWER, &[2])[2], STATUS_MALFORMED);
+        assert!(!app.shared.get().streetlamps_enabled);
+
+        let response = send(&app, TOGGLE_POWER, &[]);
+        assert_eq!(response[2..4], [STATUS_OK, 1]);
+        assert!(app.shared.get().streetlamps_enabled);
+    }
+
+    #[test]
+    fn ignores_lamps_out_of_range() {
+        let app = app();
+        assert_eq!(send(&app, SET_LAMP, &[0, 2])[2], STATUS_OK);
+        assert!(matches!(
+            app.shared.get().streetlamps_modes[0],
+            StreetlampMode::Flickering { .. }
+        ));
+        let before = app.shared.get();
+        assert_eq!(send(&app, SET_LAMP, &[200, 2])[2], STATUS_OK);
+        assert!(app.shared.get() == before);
+    }
+
+    #[test]
+    fn saves_lists_and_recalls_presets() {
+        let app = app();
+        let response = send(&app, RECALL_PRESET, &name_field("night"));
+        assert_eq!(response[2], STATUS_NOT_FOUND);
+
+        assert_eq!(send(&app, SAVE_PRESET, &name_field("night"))[2], STATUS_OK);
+        let response = send(&app, LIST_PRESETS, &[0]);
+        assert_eq!(response[2..4], [STATUS_OK, 1]);
+        assert_eq!(response[4..10], name_field("night")[..]);
+        // Past the end gives the count and an empty name
+        let response = send(&app, LIST_PRESETS, &[1]);
+        assert_eq!(response[2..5], [STATUS_OK, 1, 0]);
+
+        let response = send(&app, RECALL_PRESET, &name_field("night"));
+        assert_eq!(response[2], STATUS_OK);
+        assert!(read_state(&response[3..]).unwrap() == SharedState::default());
+    }
+
+    #[test]
+    fn rejects_malformed_preset_names() {
+        let response = send(&app(), SAVE_PRESET, &[63]);
+        assert_eq!(response[..3], [SAVE_PRESET, 0x5A, STATUS_MALFORMED]);
+    }
+}
@@ -0,0 +1,95 @@
+//! Lighting streamed in over the network, which takes over from `SharedState`
+//! for as long as it keeps arriving.
+
+use core::cell::Cell;
+
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embassy_time::{Duration, Instant};
+use smart_leds::RGB8;
+
+use crate::streetlamps::NUM_STREETLAMPS;
+use crate::underpass_lights::NUM_LEDS;
+
+#[derive(Clone)]
+pub struct Streamed<T> {
+    pub value: T,
+    /// When to go back to `SharedState` if nothing newer arrives
+    pub until: Instant,
+}
+
+impl<T> Streamed<T> {
+    pub fn is_current(&self) -> bool {
+        Instant::now() < self.until
+    }
+}
+
+/// The latest value from a stream. Only the newest is kept, so a runner that
+/// falls behind skips straight to it.
+pub struct LiveInput<T> {
+    latest: Signal<CriticalSectionRawMutex, Streamed<T>>,
+    /// When the newest value stops being current, kept apart from `latest` so
+    /// it can be checked without taking the value
+    until: Mutex<CriticalSectionRawMutex, Cell<Instant>>,
+}
+
+impl<T: Send> LiveInput<T> {
+    pub const fn new() -> Self {
+        Self {
+            latest: Signal::new(),
+            until: Mutex::new(Cell::new(Instant::MIN)),
+        }
+    }
+
+    pub fn send(&self, value: T, timeout: Duration) {
+        let until = Instant::now() + timeout;
+        self.until.lock(|current| current.set(until));
+        self.latest.signal(Streamed { value, until });
+    }
+
+    /// Whether a stream is in control.
+    pub fn is_streaming(&self) -> bool {
+        Instant::now() < self.until.lock(Cell::get)
+    }
+
+    pub fn try_take(&self) -> Option<Streamed<T>> {
+        self.latest.try_take()
+    }
+
+    pub async fn wait(&self) -> Streamed<T> {
+        self.latest.wait().await
+    }
+}
+
+impl<T: Send> Default for LiveInput<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct LiveInputs {
+    pub underpass: LiveInput<[RGB8; NUM_LEDS]>,
+    /// Brightness of each streetlamp
+    pub streetlamps: LiveInput<[u8; NUM_STREETLAMPS]>,
+}
+
+impl LiveInputs {
+    pub const fn new() -> Self {
+        Self {
+            underpass: LiveInput::new(),
+            streetlamps: LiveInput::new(),
+        }
+    }
+
+    pub fn is_streaming(&self) -> bool {
+        self.underpass.is_streaming() || self.streetlamps.is_streaming()
+    }
+}
+
+impl Default for LiveInputs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
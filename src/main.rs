@@ -1,60 +1,119 @@
-#![no_std]
-#![no_main]
+// Building for the RP2040 produces the firmware; building for any hosted
+// target (`cargo sim`) produces the simulator in `simulator.rs` instead.
+#![cfg_attr(target_os = "none", no_std)]
+#![cfg_attr(target_os = "none", no_main)]
 #![feature(impl_trait_in_assoc_type)]
-
+// The web app's router is one deeply nested type
+#![recursion_limit = "256"]
+
+mod api;
+mod console;
+mod device_id;
+mod dmx;
+mod event_log;
+mod hid;
+mod live;
+mod midi;
+mod mqtt;
+#[cfg(target_os = "none")]
 mod network;
+mod network_config;
 mod pins;
+mod playlist;
+mod realtime;
+mod schema;
 mod state;
+mod storage;
 mod streetlamps;
+mod supervisor;
+mod traffic;
 mod underpass_lights;
+#[cfg(target_os = "none")]
+mod usb_console;
+#[cfg(target_os = "none")]
 mod usb_device;
+#[cfg(target_os = "none")]
 mod usb_ethernet;
+#[cfg(target_os = "none")]
+mod usb_hid;
+#[cfg(target_os = "none")]
+mod usb_midi;
+mod validation;
 mod web;
+mod wled;
 
+#[cfg(target_os = "none")]
 mod rp;
+#[cfg(not(target_os = "none"))]
+mod simulator;
 
 static DEVICE_NAME: &str = "Underpass Diorama";
-static DEVICE_HOST: &str = "road";
-
-static OUR_IP: Ipv4Addr = Ipv4Addr::new(10, 42, 0, 1);
-static DNS_SERVERS: [Ipv4Addr; 1] = [OUR_IP];
 
+#[cfg(target_os = "none")]
 const MTU: usize = 1514;
 
+#[cfg(target_os = "none")]
 const FLASH_SIZE: usize = 2 * 1024 * 1024; // 2MB
+#[cfg(target_os = "none")]
 const FLASH_SIZE_U32: u32 = FLASH_SIZE as u32;
+#[cfg(target_os = "none")]
 const FLASH_STORE_LOCATION: Range<u32> = (FLASH_SIZE_U32 - 128 * 1024)..FLASH_SIZE_U32; // 128KB
 
+#[cfg(not(target_os = "none"))]
+fn main() {
+    simulator::run();
+}
+
+/// Restart the board, or quit the simulator.
+pub fn reboot() -> ! {
+    #[cfg(target_os = "none")]
+    cortex_m::peripheral::SCB::sys_reset();
+    #[cfg(not(target_os = "none"))]
+    std::process::exit(0)
+}
+
+#[cfg(target_os = "none")]
 use {
-    core::{net::Ipv4Addr, ops::Range},
+    core::ops::Range,
     defmt::info,
     defmt_rtt as _,
+    device_id::{DeviceId, UNIQUE_ID_LEN},
+    dmx::{DmxControl, DmxReceiver},
     embassy_executor::Spawner,
+    embassy_futures::select::{select, Either},
     embassy_rp::{
         adc, bind_interrupts,
         clocks::RoscRng,
         flash::Flash,
         gpio::{AnyPin, Level, Output},
         i2c::InterruptHandler,
-        peripherals::{I2C1, PIN_8, PIO0, USB},
+        peripherals::{I2C1, PIO0, USB},
         pio::Pio,
+        pio_programs::ws2812::{PioWs2812, PioWs2812Program},
+        pwm::{self, Pwm},
         usb::{self, Driver},
     },
-    embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex},
     embassy_time::{Duration, Timer},
     embassy_usb::{class::cdc_ncm::embassy_net::Device, UsbDevice},
+    live::LiveInputs,
+    midi::{MidiControl, MidiReceiver},
+    mqtt::{MqttClient, MqttControl},
+    network_config::NetworkConfig,
     panic_probe as _,
     picoserve::make_static,
+    pins::LampPin,
+    playlist::{Playlist, PlaylistControl, PlaylistRunner},
     rand::RngCore,
-    sequential_storage::{
-        cache::NoCache,
-        map::{fetch_item, store_item},
-    },
-    smart_leds::RGB8,
-    state::{AppState, SharedState, SharedStateMutex},
+    realtime::RealtimeReceiver,
+    rp::PwmChannel,
+    state::{AppState, SharedState, SharedStateWatch, StateWatch},
+    storage::{Storage, StorageError},
     streetlamps::StreetlampsRunner,
+    underpass_lights::{Crossfade, NUM_LEDS},
+    validation::Validate,
 };
 
+#[cfg(target_os = "none")]
 bind_interrupts!(struct Irqs {
     I2C1_IRQ => InterruptHandler<I2C1>;
     USBCTRL_IRQ => usb::InterruptHandler<USB>;
@@ -62,26 +121,19 @@ bind_interrupts!(struct Irqs {
     PIO0_IRQ_0 => embassy_rp::pio::InterruptHandler<PIO0>;
 });
 
+#[cfg(target_os = "none")]
 #[embassy_executor::main]
 async fn main(spawner: Spawner) -> ! {
     let p = embassy_rp::init(Default::default());
     let led = Output::new(AnyPin::from(p.PIN_22), Level::Low);
 
-    let shared_state = SharedStateMutex(
-        make_static!(Mutex<CriticalSectionRawMutex, SharedState>, Mutex::new(SharedState {
-            streetlamps_enabled: true,
-            streetlamps_brightness: 255,
-            streetlamps_modes: [
-                streetlamps::StreetlampMode::On,
-                streetlamps::StreetlampMode::On,
-                streetlamps::StreetlampMode::On,
-                streetlamps::StreetlampMode::On,
-                streetlamps::StreetlampMode::On,
-                streetlamps::StreetlampMode::On,
-            ],
-            underpass_lights_state: underpass_lights::LightingState::Cars {default_color:RGB8::new(40,20,2), min_interval: 20 , max_interval: 500, speed_limit_kph: 100 }
-        })),
-    );
+    let shared_state = SharedStateWatch(make_static!(
+        StateWatch,
+        StateWatch::new_with(SharedState::default())
+    ));
+
+    let live = make_static!(LiveInputs, LiveInputs::new());
+    let crossfade = make_static!(Crossfade, Crossfade::new());
 
     let mut diag_lights = [
         Output::new(p.PIN_16, Level::Low),
@@ -96,48 +148,115 @@ async fn main(spawner: Spawner) -> ! {
     let mut rng = RoscRng;
     let seed = rng.next_u64();
 
+    let mut flash = Flash::new(p.FLASH, p.DMA_CH1);
+    let mut unique_id = [0; UNIQUE_ID_LEN];
+    if let Err(err) = flash.blocking_unique_id(&mut unique_id) {
+        info!("Failed to read flash unique ID: {:?}", err);
+    }
+    let device_id = make_static!(DeviceId, DeviceId::new(unique_id));
+    info!("Serial number {}", device_id.serial.as_str());
+
     let usb_driver = Driver::new(p.USB, Irqs);
 
-    let mut builder = usb_device::get_usb_builder(usb_driver);
-    let (ncm_runner, device) = usb_ethernet::make_usb_ethernet_device(&mut builder);
-    let (net_runner, stack) = network::make_network_stack(device, seed);
+    let mut builder = usb_device::get_usb_builder(usb_driver, device_id);
+    let (ncm_runner, device) = usb_ethernet::make_usb_ethernet_device(&mut builder, device_id);
+    let console = usb_console::make_usb_console(&mut builder);
+    let hid = usb_hid::make_usb_hid(&mut builder);
+    let midi_class = usb_midi::make_usb_midi(&mut builder);
+    let (lamp1, lamp0) = PwmChannel::split(Pwm::new_output_ab(
+        p.PWM_SLICE3,
+        p.PIN_6,
+        p.PIN_7,
+        pwm::Config::default(),
+    ));
+    let (lamp3, lamp2) = PwmChannel::split(Pwm::new_output_ab(
+        p.PWM_SLICE2,
+        p.PIN_4,
+        p.PIN_5,
+        pwm::Config::default(),
+    ));
+    let (lamp5, lamp4) = PwmChannel::split(Pwm::new_output_ab(
+        p.PWM_SLICE1,
+        p.PIN_2,
+        p.PIN_3,
+        pwm::Config::default(),
+    ));
     let streetlamps_runner = streetlamps::StreetlampsRunner::new(
         [
-            Output::new(p.PIN_7, Level::Low),
-            Output::new(p.PIN_6, Level::Low),
-            Output::new(p.PIN_5, Level::Low),
-            Output::new(p.PIN_4, Level::Low),
-            Output::new(p.PIN_3, Level::Low),
-            Output::new(p.PIN_2, Level::Low),
+            LampPin::Pwm(lamp0),
+            LampPin::Pwm(lamp1),
+            LampPin::Pwm(lamp2),
+            LampPin::Pwm(lamp3),
+            LampPin::Pwm(lamp4),
+            LampPin::Pwm(lamp5),
         ],
         RoscRng,
-        shared_state,
+        shared_state.receiver().unwrap(),
+        &live.streetlamps,
     );
     let usb = builder.build();
     let (app, config) = web::make_web_app();
 
-    let mut flash: Flash<'_, _, _, FLASH_SIZE> = Flash::new(p.FLASH, p.DMA_CH1);
-    let mut data_buffer = [0; 128];
-
-    let val = fetch_item::<u8, SharedState, _>(
-        &mut flash,
-        FLASH_STORE_LOCATION.clone(),
-        &mut NoCache::new(),
-        &mut data_buffer,
-        &1,
-    )
-    .await;
+    let storage = make_static!(Storage, Storage::new(flash, FLASH_STORE_LOCATION.clone()));
+
+    let val = storage.load_state().await;
     match val {
-        Ok(Some(val)) => {
-            info!("Fetched value: {:?}", val);
-            let SharedStateMutex(mutex) = shared_state;
-            let mut state = mutex.lock().await;
-            *state = val;
-        }
+        Ok(Some(val)) => match val.validate() {
+            Ok(()) => {
+                info!("Fetched value: {:?}", val);
+                shared_state.set(val);
+            }
+            Err(err) => info!("Discarding invalid stored state: {:?}", err),
+        },
         Err(err) => info!("Failed to fetch value: {:?}", err),
         _ => info!("Failed to fetch value"),
     }
 
+    let playlist = match storage.load_playlist().await {
+        Ok(Some(playlist)) => playlist,
+        Ok(None) => Playlist::default(),
+        Err(err) => {
+            info!("Failed to fetch playlist: {:?}", err);
+            Playlist::default()
+        }
+    };
+    let playlist = make_static!(PlaylistControl, PlaylistControl::new(playlist));
+
+    let dmx_config = config_or_default("DMX", storage.load_dmx_config().await);
+    let dmx = make_static!(DmxControl, DmxControl::new(dmx_config));
+
+    let mqtt_config = config_or_default("MQTT", storage.load_mqtt_config().await);
+    let mqtt = make_static!(MqttControl, MqttControl::new(mqtt_config));
+
+    let midi_config = config_or_default("MIDI", storage.load_midi_config().await);
+    let midi = make_static!(MidiControl, MidiControl::new(midi_config));
+
+    // A bad network config would leave the diorama unreachable, so fall back
+    // to the defaults rather than use it
+    let network_config = config_or_default("network", storage.load_network_config().await);
+    let network_config = make_static!(NetworkConfig, network_config);
+    info!(
+        "Network address {}/{}",
+        network_config.address, network_config.prefix_len
+    );
+    event_log::record(format_args!(
+        "Started as {}.local on {}/{}",
+        network_config.hostname, network_config.address, network_config.prefix_len
+    ));
+    let (net_runner, stack) = network::make_network_stack(device, seed, network_config);
+
+    let app_state = AppState {
+        shared: shared_state,
+        storage,
+        playlist,
+        dmx,
+        mqtt,
+        midi,
+        network: network_config,
+        device: device_id,
+        live,
+    };
+
     spawner.must_spawn(blinker(led, Duration::from_millis(500)));
 
     spawner.must_spawn(usb_task(usb));
@@ -145,6 +264,18 @@ async fn main(spawner: Spawner) -> ! {
 
     spawner.must_spawn(usb_ncm_task(ncm_runner));
     info!("USB NCM task started");
+
+    spawner.must_spawn(usb_console::console_task(console, app_state, stack));
+    info!("USB console task started");
+
+    spawner.must_spawn(usb_hid::hid_task(hid, app_state));
+    info!("USB HID task started");
+
+    spawner.must_spawn(usb_midi::midi_task(
+        midi_class,
+        MidiReceiver::new(app_state),
+    ));
+    info!("USB MIDI task started");
     diag_lights[0].set_high();
 
     spawner.must_spawn(network::net_task(net_runner));
@@ -152,64 +283,92 @@ async fn main(spawner: Spawner) -> ! {
     diag_lights[1].set_high();
 
     // Spawn network service tasks
-    spawner.must_spawn(network::dhcp_task(stack));
+    spawner.must_spawn(network::dhcp_task(stack, network_config));
     info!("DHCP server task started");
 
-    spawner.must_spawn(network::mdns_task(stack));
+    if network_config.captive_portal {
+        spawner.must_spawn(network::captive_dns_task(stack, network_config));
+        info!("Captive DNS task started");
+    }
+
+    spawner.must_spawn(network::mdns_task(stack, network_config, device_id));
     info!("mDNS server task started");
     diag_lights[2].set_high();
 
     for id in 0..web::WEB_TASK_POOL_SIZE {
-        spawner.must_spawn(web::web_task(
-            id,
+        spawner.must_spawn(web::web_task(id, stack, app_state, app, config));
+    }
+    info!("Web task started");
+
+    for port in [dmx::E131_PORT, dmx::ARTNET_PORT] {
+        spawner.must_spawn(dmx::dmx_task(stack, port, DmxReceiver::new(dmx, live)));
+    }
+    info!("DMX tasks started");
+
+    for port in [realtime::DDP_PORT, realtime::WLED_REALTIME_PORT] {
+        spawner.must_spawn(realtime::realtime_task(
             stack,
-            AppState {
-                shared: shared_state,
-            },
-            app,
-            config,
+            port,
+            RealtimeReceiver::new(live),
         ));
     }
-    info!("Web task started");
+    info!("Realtime tasks started");
+
+    spawner.must_spawn(mqtt::mqtt_task(
+        stack,
+        MqttClient::new(app_state, shared_state.receiver().unwrap()),
+    ));
+    info!("MQTT task started");
 
     spawner.must_spawn(streetlamp_task(streetlamps_runner));
     info!("Streetlamp task started");
 
+    let Pio {
+        mut common, sm0, ..
+    } = Pio::new(p.PIO0, Irqs);
+    let program = PioWs2812Program::new(&mut common);
+    let ws2812 = PioWs2812::new(&mut common, sm0, p.DMA_CH0, p.PIN_8, &program);
     spawner.must_spawn(underpass_lights_task(
         underpass_lights::UnderpassLightsRunner::new(
-            Pio::new(p.PIO0, Irqs),
-            p.PIN_8,
-            p.DMA_CH0,
+            ws2812,
             RoscRng,
-            shared_state,
+            shared_state.receiver().unwrap(),
+            &live.underpass,
+            crossfade,
         ),
     ));
     info!("Underpass lights task started");
+
+    spawner.must_spawn(playlist_task(PlaylistRunner::new(
+        RoscRng,
+        shared_state,
+        storage,
+        playlist,
+        crossfade,
+    )));
+    info!("Playlist task started");
     diag_lights[3].set_high();
 
-    let mut old_state = {
-        let SharedStateMutex(mutex) = shared_state;
-        let state = mutex.lock().await;
-        state.clone()
-    };
+    let mut state_changes = shared_state.receiver().unwrap();
+    let mut old_state = state_changes.get().await;
 
     loop {
-        Timer::after(Duration::from_secs(3)).await;
-        // Check if state has changed
-        let SharedStateMutex(mutex) = shared_state;
-        let state = { (*mutex.lock().await).clone() };
+        let mut state = state_changes.changed().await;
+        // Wait for the changes to settle so dragging a slider doesn't wear
+        // out the flash
+        while let Either::First(changed) = select(
+            state_changes.changed(),
+            Timer::after(Duration::from_secs(3)),
+        )
+        .await
+        {
+            state = changed;
+        }
+
         if state != old_state {
             info!("State changed: {:?}", state);
             // Update the flash memory with the new state
-            let result = store_item::<u8, SharedState, _>(
-                &mut flash,
-                FLASH_STORE_LOCATION.clone(),
-                &mut NoCache::new(),
-                &mut data_buffer,
-                &1,
-                &state,
-            )
-            .await;
+            let result = storage.save_state(&state).await;
             match result {
                 Ok(_) => info!("Stored state"),
                 Err(_) => info!("Failed to store state"),
@@ -220,6 +379,39 @@ async fn main(spawner: Spawner) -> ! {
     }
 }
 
+/// A config loaded from flash, or the defaults if there isn't one or it can't
+/// be used.
+#[cfg(target_os = "none")]
+fn config_or_default<T: Validate + Default>(
+    name: &str,
+    loaded: Result<Option<T>, StorageError>,
+) -> T {
+    match loaded {
+        Ok(Some(config)) => match config.validate() {
+            Ok(()) => config,
+            Err(err) => {
+                info!("Discarding invalid {} config: {:?}", name, err);
+                event_log::record(format_args!(
+                    "Invalid {} config ({}: {}), using defaults",
+                    name, err.field, err.reason
+                ));
+                T::default()
+            }
+        },
+        Ok(None) => T::default(),
+        Err(err) => {
+            info!("Failed to fetch {} config: {:?}", name, err);
+            event_log::record(format_args!(
+                "Failed to read {} config ({}), using defaults",
+                name,
+                err.reason()
+            ));
+            T::default()
+        }
+    }
+}
+
+#[cfg(target_os = "none")]
 #[embassy_executor::task]
 async fn blinker(mut led: Output<'static>, interval: Duration) {
     loop {
@@ -230,11 +422,13 @@ async fn blinker(mut led: Output<'static>, interval: Duration) {
     }
 }
 
+#[cfg(target_os = "none")]
 #[embassy_executor::task]
 pub(crate) async fn usb_task(mut usb: UsbDevice<'static, Driver<'static, USB>>) -> ! {
     usb.run().await
 }
 
+#[cfg(target_os = "none")]
 #[embassy_executor::task]
 pub(crate) async fn usb_ncm_task(
     class: embassy_usb::class::cdc_ncm::embassy_net::Runner<'static, Driver<'static, USB>, MTU>,
@@ -242,19 +436,30 @@ pub(crate) async fn usb_ncm_task(
     class.run().await
 }
 
+#[cfg(target_os = "none")]
 #[embassy_executor::task]
 pub(crate) async fn net_task(mut runner: embassy_net::Runner<'static, Device<'static, MTU>>) -> ! {
     runner.run().await
 }
 
+#[cfg(target_os = "none")]
 #[embassy_executor::task]
-async fn streetlamp_task(mut runner: StreetlampsRunner<Output<'static>, RoscRng, 6>) -> ! {
+async fn streetlamp_task(
+    mut runner: StreetlampsRunner<LampPin<PwmChannel<'static>, Output<'static>>, RoscRng, 6>,
+) -> ! {
     runner.run().await
 }
 
+#[cfg(target_os = "none")]
 #[embassy_executor::task]
 async fn underpass_lights_task(
-    runner: underpass_lights::UnderpassLightsRunner<RoscRng, PIN_8>,
+    runner: underpass_lights::UnderpassLightsRunner<RoscRng, PioWs2812<'static, PIO0, 0, NUM_LEDS>>,
 ) -> ! {
     runner.run().await
 }
+
+#[cfg(target_os = "none")]
+#[embassy_executor::task]
+async fn playlist_task(runner: PlaylistRunner<RoscRng>) -> ! {
+    runner.run().await
+}
@@ -0,0 +1,517 @@
+//! MIDI control, so a keyboard or control surface can play the diorama. Notes
+//! and program changes are mapped to presets or lighting effects through the
+//! triggers in `MidiConfig`, and control changes set the streetlamp
+//! brightness and the traffic. The messages come from the USB MIDI port on the
+//! board, and from UDP in the simulator.
+
+use defmt::{info, Format};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, watch::Watch};
+use heapless::Vec;
+
+use crate::api::{self, set_strip_power};
+use crate::state::{AppState, SharedState};
+use crate::storage::PresetName;
+use crate::underpass_lights::{LightingState, MAX_SPEED_LIMIT_KPH, MIN_SPEED_LIMIT_KPH};
+use crate::validation::{Validate, ValidationError};
+
+pub const MAX_TRIGGERS: usize = 16;
+
+/// Largest `max_interval` the car interval controller reaches
+const MAX_CC_INTERVAL: u16 = 2540;
+
+const NOTE_OFF: u8 = 0x80;
+const NOTE_ON: u8 = 0x90;
+const CONTROL_CHANGE: u8 = 0xB0;
+const PROGRAM_CHANGE: u8 = 0xC0;
+
+/// A channel message we act on. Channels are numbered from 0 here, as on the
+/// wire, but from 1 in `MidiConfig` as on the instruments.
+#[derive(Format, Clone, Copy, PartialEq)]
+pub enum MidiMessage {
+    NoteOn {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    ControlChange {
+        channel: u8,
+        controller: u8,
+        value: u8,
+    },
+    ProgramChange {
+        channel: u8,
+        program: u8,
+    },
+}
+
+impl MidiMessage {
+    pub fn channel(&self) -> u8 {
+        match *self {
+            MidiMessage::NoteOn { channel, .. }
+            | MidiMessage::ControlChange { channel, .. }
+            | MidiMessage::ProgramChange { channel, .. } => channel,
+        }
+    }
+}
+
+/// Parse one MIDI message from its status byte onwards. Note offs, including
+/// note ons with no velocity, and all other messages are ignored, as is
+/// anything after the message.
+pub fn parse_message(bytes: &[u8]) -> Option<MidiMessage> {
+    let (&status, data) = bytes.split_first()?;
+    let channel = status & 0x0F;
+    let is_data = |byte: &u8| *byte < 0x80;
+    match (status & 0xF0, data) {
+        (NOTE_ON, [note, velocity, ..]) if is_data(note) && is_data(velocity) => (*velocity > 0)
+            .then_some(MidiMessage::NoteOn {
+                channel,
+                note: *note,
+                velocity: *velocity,
+            }),
+        (CONTROL_CHANGE, [controller, value, ..]) if is_data(controller) && is_data(value) => {
+            Some(MidiMessage::ControlChange {
+                channel,
+                controller: *controller,
+                value: *value,
+            })
+        }
+        (PROGRAM_CHANGE, [program, ..]) if is_data(program) => Some(MidiMessage::ProgramChange {
+            channel,
+            program: *program,
+        }),
+        _ => None,
+    }
+}
+
+/// Parse a 4 byte USB MIDI event packet, `[cable and code index, message..]`.
+/// The code index says what kind of message follows, which keeps SysEx
+/// fragments from being taken for channel messages.
+fn parse_usb_packet(packet: &[u8]) -> Option<MidiMessage> {
+    let (&header, message) = packet.split_first()?;
+    match (header & 0x0F) << 4 {
+        NOTE_OFF | NOTE_ON | CONTROL_CHANGE | PROGRAM_CHANGE => parse_message(message),
+        _ => None,
+    }
+}
+
+/// What sets off a trigger.
+#[derive(serde::Deserialize, serde::Serialize, Format, Clone, Copy, PartialEq)]
+pub enum MidiEvent {
+    Note(u8),
+    Program(u8),
+}
+
+/// A lighting effect to switch to, in the colour currently showing.
+#[derive(serde::Deserialize, serde::Serialize, Format, Clone, Copy, PartialEq)]
+pub enum Effect {
+    Off,
+    SingleColour,
+    RainbowCycle,
+    Cars,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Format, Clone, PartialEq)]
+pub enum MidiAction {
+    RecallPreset(PresetName),
+    SetEffect(Effect),
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Format, Clone, PartialEq)]
+pub struct MidiTrigger {
+    pub event: MidiEvent,
+    pub action: MidiAction,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, PartialEq)]
+pub struct MidiConfig {
+    pub enabled: bool,
+    /// Channel to listen on, from 1 to 16, or `None` for all of them
+    pub channel: Option<u8>,
+    /// Checked in order, and the first that matches is used
+    pub triggers: Vec<MidiTrigger, MAX_TRIGGERS>,
+    /// Controller for the streetlamp brightness
+    pub brightness_cc: Option<u8>,
+    /// Controller for the longest gap between cars
+    pub car_interval_cc: Option<u8>,
+    /// Controller for the cars' speed limit
+    pub speed_limit_cc: Option<u8>,
+}
+
+impl Default for MidiConfig {
+    fn default() -> Self {
+        // Middle C and the three notes above it pick the effects, and the
+        // controllers are volume and two of the undefined ones
+        let triggers = [
+            Effect::Off,
+            Effect::SingleColour,
+            Effect::RainbowCycle,
+            Effect::Cars,
+        ]
+        .into_iter()
+        .zip(60..)
+        .map(|(effect, note)| MidiTrigger {
+            event: MidiEvent::Note(note),
+            action: MidiAction::SetEffect(effect),
+        })
+        .collect();
+        Self {
+            enabled: true,
+            channel: None,
+            triggers,
+            brightness_cc: Some(7),
+            car_interval_cc: Some(20),
+            speed_limit_cc: Some(21),
+        }
+    }
+}
+
+impl MidiConfig {
+    /// Whether `message` should be acted on, being on a channel we listen to.
+    fn accepts(&self, message: &MidiMessage) -> bool {
+        self.enabled
+            && self
+                .channel
+                .is_none_or(|channel| channel == message.channel() + 1)
+    }
+
+    /// What a note or program change is mapped to, if anything.
+    fn action_for(&self, message: &MidiMessage) -> Option<&MidiAction> {
+        let event = match *message {
+            MidiMessage::NoteOn { note, .. } => MidiEvent::Note(note),
+            MidiMessage::ProgramChange { program, .. } => MidiEvent::Program(program),
+            MidiMessage::ControlChange { .. } => return None,
+        };
+        self.triggers
+            .iter()
+            .find(|trigger| trigger.event == event)
+            .map(|trigger| &trigger.action)
+    }
+
+    /// Set whatever `controller` is mapped to in `state` from `value`.
+    fn apply_control_change(&self, controller: u8, value: u8, state: &mut SharedState) {
+        let controller = Some(controller);
+        if controller == self.brightness_cc {
+            state.streetlamps_brightness = scale(value, 0, 255) as u8;
+        }
+        // Traffic controllers only apply while the cars are showing
+        if let LightingState::Cars {
+            min_interval,
+            max_interval,
+            speed_limit_kph,
+            ..
+        } = &mut state.underpass_lights_state
+        {
+            if controller == self.car_interval_cc {
+                *max_interval = scale(value, 0, MAX_CC_INTERVAL as u32) as u16;
+                *min_interval = (*min_interval).min(*max_interval);
+            }
+            if controller == self.speed_limit_cc {
+                *speed_limit_kph = scale(value, MIN_SPEED_LIMIT_KPH, MAX_SPEED_LIMIT_KPH);
+            }
+        }
+    }
+}
+
+impl Validate for MidiConfig {
+    fn validate(&self) -> Result<(), ValidationError> {
+        if let Some(channel) = self.channel {
+            if !(1..=16).contains(&channel) {
+                return Err(ValidationError::new("channel", "must be between 1 and 16"));
+            }
+        }
+        for (i, trigger) in self.triggers.iter().enumerate() {
+            let (MidiEvent::Note(number) | MidiEvent::Program(number)) = trigger.event;
+            if number > 127 {
+                return Err(ValidationError::new("triggers.event", "must be at most 127").at(i));
+            }
+        }
+        for (field, controller) in [
+            ("brightness_cc", self.brightness_cc),
+            ("car_interval_cc", self.car_interval_cc),
+            ("speed_limit_cc", self.speed_limit_cc),
+        ] {
+            if controller.is_some_and(|controller| controller > 119) {
+                // 120 and up are channel mode messages
+                return Err(ValidationError::new(field, "must be at most 119"));
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct MidiControl {
+    config: Watch<CriticalSectionRawMutex, MidiConfig, 0>,
+}
+
+impl MidiControl {
+    pub fn new(config: MidiConfig) -> Self {
+        Self {
+            config: Watch::new_with(config),
+        }
+    }
+
+    pub fn config(&self) -> MidiConfig {
+        self.config.try_get().unwrap_or_default()
+    }
+
+    pub fn set_config(&self, config: MidiConfig) {
+        self.config.sender().send(config);
+    }
+}
+
+/// Switch to `effect`, turning the strip back on first if it's off so the
+/// effect picks up the colour it was showing.
+fn apply_effect(effect: Effect, lighting: &mut LightingState) {
+    set_strip_power(lighting, effect != Effect::Off);
+    let colour = lighting
+        .colour()
+        .or(SharedState::default().underpass_lights_state.colour())
+        .unwrap_or_default();
+    *lighting = match effect {
+        Effect::Off => return,
+        Effect::SingleColour => LightingState::SingleColour(colour),
+        Effect::RainbowCycle => LightingState::RainbowCycle,
+        Effect::Cars => match *lighting {
+            cars @ LightingState::Cars { .. } => cars,
+            _ => LightingState::cars(colour),
+        },
+    };
+}
+
+/// Scale a 7 bit controller value onto `min..=max`.
+fn scale(value: u8, min: u32, max: u32) -> u32 {
+    min + value.min(127) as u32 * (max - min) / 127
+}
+
+/// Turns MIDI messages into changes to `SharedState`.
+#[derive(Clone, Copy)]
+pub struct MidiReceiver {
+    app: AppState,
+}
+
+impl MidiReceiver {
+    pub fn new(app: AppState) -> Self {
+        Self { app }
+    }
+
+    /// Handle the MIDI event packets in a USB packet, any number of 4 bytes
+    /// each.
+    pub async fn handle_usb_packet(&self, packet: &[u8]) {
+        for event in packet.chunks_exact(4) {
+            if let Some(message) = parse_usb_packet(event) {
+                self.handle(message).await;
+            }
+        }
+    }
+
+    pub async fn handle(&self, message: MidiMessage) {
+        let config = self.app.midi.config();
+        if !config.accepts(&message) {
+            return;
+        }
+
+        if let MidiMessage::ControlChange {
+            controller, value, ..
+        } = message
+        {
+            self.app
+                .shared
+                .modify(|state| config.apply_control_change(controller, value, state));
+            return;
+        }
+        let Some(action) = config.action_for(&message) else {
+            return;
+        };
+        match action {
+            MidiAction::RecallPreset(name) => {
+                if api::recall_preset(&self.app, name).await.is_err() {
+                    info!("MIDI skipping missing or invalid preset {:?}", name);
+                }
+            }
+            MidiAction::SetEffect(effect) => self
+                .app
+                .shared
+                .modify(|state| apply_effect(*effect, &mut state.underpass_lights_state)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smart_leds::RGB8;
+
+    use super::*;
+
+    fn note_on(channel: u8, note: u8) -> MidiMessage {
+        MidiMessage::NoteOn {
+            channel,
+            note,
+            velocity: 100,
+        }
+    }
+
+    #[test]
+    fn parses_channel_messages() {
+        assert!(parse_message(&[0x91, 60, 100]) == Some(note_on(1, 60)));
+        assert!(
+            parse_message(&[0xBF, 7, 127])
+                == Some(MidiMessage::ControlChange {
+                    channel: 15,
+                    controller: 7,
+                    value: 127,
+                })
+        );
+        assert!(
+            parse_message(&[0xC2, 5])
+                == Some(MidiMessage::ProgramChange {
+                    channel: 2,
+                    program: 5,
+                })
+        );
+    }
+
+    #[test]
+    fn ignores_note_offs() {
+        assert!(parse_message(&[0x80, 60, 64]).is_none());
+        // A note on with no velocity is a note off
+        assert!(parse_message(&[0x90, 60, 0]).is_none());
+    }
+
+    #[test]
+    fn ignores_invalid_bytes() {
+        assert!(parse_message(&[]).is_none());
+        // Cut short
+        assert!(parse_message(&[0x90, 60]).is_none());
+        assert!(parse_message(&[0xC0]).is_none());
+        // Data bytes have the top bit clear
+        assert!(parse_message(&[0x90, 0x80, 100]).is_none());
+        assert!(parse_message(&[0xB0, 7, 0xFF]).is_none());
+        // Other messages
+        assert!(parse_message(&[0xE0, 0, 64]).is_none());
+        assert!(parse_message(&[0xF8]).is_none());
+    }
+
+    #[test]
+    fn ignores_running_status() {
+        // USB MIDI packets always carry their status byte, so data without
+        // one isn't taken for a repeat of the last message
+        assert!(parse_usb_packet(&[0x09, 60, 100, 0]).is_none());
+        assert!(parse_message(&[60, 100]).is_none());
+        // and bytes after a message aren't another one
+        assert!(parse_message(&[0x90, 60, 100, 62, 100]) == Some(note_on(0, 60)));
+    }
+
+    #[test]
+    fn parses_usb_packets_by_code_index() {
+        assert!(parse_usb_packet(&[0x09, 0x90, 60, 100]) == Some(note_on(0, 60)));
+        // The cable number is ignored
+        assert!(parse_usb_packet(&[0x19, 0x90, 60, 100]) == Some(note_on(0, 60)));
+        // SysEx that happens to look like a note on
+        assert!(parse_usb_packet(&[0x04, 0x90, 60, 100]).is_none());
+        assert!(parse_usb_packet(&[0x09]).is_none());
+    }
+
+    #[test]
+    fn filters_by_channel() {
+        let mut config = MidiConfig::default();
+        assert!((0..16).all(|channel| config.accepts(&note_on(channel, 60))));
+
+        // Channel 2 on the instrument is 1 on the wire
+        config.channel = Some(2);
+        assert!(config.accepts(&note_on(1, 60)));
+        assert!(!config.accepts(&note_on(0, 60)));
+        assert!(!config.accepts(&note_on(2, 60)));
+
+        config.enabled = false;
+        assert!(!config.accepts(&note_on(1, 60)));
+    }
+
+    #[test]
+    fn maps_notes_and_programs_to_triggers() {
+        let mut config = MidiConfig::default();
+        assert!(
+            config.action_for(&note_on(0, 62))
+                == Some(&MidiAction::SetEffect(Effect::RainbowCycle))
+        );
+        assert!(config.action_for(&note_on(0, 64)).is_none());
+
+        let program = MidiMessage::ProgramChange {
+            channel: 0,
+            program: 62,
+        };
+        assert!(config.action_for(&program).is_none());
+
+        // The first match wins
+        let preset: PresetName = "night".parse().unwrap();
+        for action in [
+            MidiAction::RecallPreset(preset.clone()),
+            MidiAction::SetEffect(Effect::Off),
+        ] {
+            let _ = config.triggers.push(MidiTrigger {
+                event: MidiEvent::Program(62),
+                action,
+            });
+        }
+        assert!(config.action_for(&program) == Some(&MidiAction::RecallPreset(preset)));
+    }
+
+    #[test]
+    fn maps_controllers_through_the_config() {
+        let config = MidiConfig::default();
+        let mut state = SharedState::default();
+
+        config.apply_control_change(7, 0, &mut state);
+        assert_eq!(state.streetlamps_brightness, 0);
+        config.apply_control_change(7, 127, &mut state);
+        assert_eq!(state.streetlamps_brightness, 255);
+
+        config.apply_control_change(20, 127, &mut state);
+        config.apply_control_change(21, 0, &mut state);
+        let LightingState::Cars {
+            min_interval,
+            max_interval,
+            speed_limit_kph,
+            ..
+        } = state.underpass_lights_state
+        else {
+            panic!("the default state shows cars");
+        };
+        assert_eq!(max_interval, MAX_CC_INTERVAL);
+        assert_eq!(speed_limit_kph, MIN_SPEED_LIMIT_KPH);
+        assert_eq!(min_interval, 20);
+
+        // Shortening the longest gap drags the shortest with it
+        config.apply_control_change(20, 0, &mut state);
+        assert!(matches!(
+            state.underpass_lights_state,
+            LightingState::Cars {
+                min_interval: 0,
+                max_interval: 0,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn ignores_unmapped_controllers() {
+        let config = MidiConfig {
+            brightness_cc: Some(1),
+            car_interval_cc: None,
+            ..MidiConfig::default()
+        };
+        let mut state = SharedState {
+            underpass_lights_state: LightingState::SingleColour(RGB8::new(1, 2, 3)),
+            ..SharedState::default()
+        };
+        let before = state.clone();
+
+        config.apply_control_change(7, 0, &mut state);
+        config.apply_control_change(20, 0, &mut state);
+        // Traffic controllers do nothing without traffic
+        config.apply_control_change(21, 0, &mut state);
+        assert!(state == before);
+
+        config.apply_control_change(1, 0, &mut state);
+        assert_eq!(state.streetlamps_brightness, 0);
+    }
+}
@@ -0,0 +1,1217 @@
+//! MQTT client that puts the diorama in Home Assistant. It publishes discovery
+//! configs for the streetlamps' power switch, a select for each lamp and the
+//! underpass light, keeps their state topics up to date and applies whatever
+//! Home Assistant sends to their command topics.
+//!
+//! Only the parts of MQTT 3.1.1 this needs are implemented: QoS 0 publishes and
+//! subscriptions, a last will for availability, and keepalive pings.
+
+use core::fmt::Write as _;
+use core::net::{Ipv4Addr, SocketAddrV4};
+
+use defmt::{info, Format};
+use embassy_futures::select::{select, select4, Either4};
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex,
+    watch::{Receiver, Watch},
+};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use embedded_io_async::{Read, Write};
+use heapless::{String, Vec};
+use smart_leds::RGB8;
+
+use crate::api;
+use crate::event_log;
+use crate::network_config::MAX_HOSTNAME_LEN;
+use crate::state::{AppState, SharedState, StateReceiver};
+use crate::streetlamps::{StreetlampMode, NUM_STREETLAMPS};
+use crate::underpass_lights::LightingState;
+use crate::validation::{Validate, ValidationError};
+use crate::DEVICE_NAME;
+
+pub const DEFAULT_PORT: u16 = 1883;
+pub const MAX_CREDENTIAL_LEN: usize = 32;
+
+const DISCOVERY_PREFIX: &str = "homeassistant";
+/// Home Assistant publishes `online` here when it starts, asking for discovery
+/// configs to be sent again
+const HA_STATUS_TOPIC: &str = "homeassistant/status";
+
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Room for the largest discovery config and its topic
+const MAX_PACKET_LEN: usize = 1024;
+const MAX_PAYLOAD_LEN: usize = 768;
+const RX_BUFFER_LEN: usize = 512;
+
+type Topic = String<96>;
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const SUBSCRIBE: u8 = 0x82;
+const PINGREQ: u8 = 0xC0;
+const DISCONNECT: u8 = 0xE0;
+const PACKET_TYPE_MASK: u8 = 0xF0;
+const PUBLISH_RETAIN: u8 = 0x01;
+const PUBLISH_QOS_MASK: u8 = 0x06;
+
+const PROTOCOL_LEVEL_3_1_1: u8 = 4;
+const CONNECT_CLEAN_SESSION: u8 = 0x02;
+const CONNECT_WILL: u8 = 0x04;
+const CONNECT_WILL_RETAIN: u8 = 0x20;
+const CONNECT_PASSWORD: u8 = 0x40;
+const CONNECT_USERNAME: u8 = 0x80;
+
+/// Names of the lamp modes, as the options of each lamp's select
+const LAMP_MODES: [&str; 3] = ["Off", "On", "Flickering"];
+/// Names of the `LightingState`s other than `Off`, as the light's effects
+const EFFECTS: [&str; 3] = ["SingleColour", "RainbowCycle", "Cars"];
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, PartialEq)]
+pub struct MqttConfig {
+    pub enabled: bool,
+    pub broker: Ipv4Addr,
+    pub port: u16,
+    /// Leave out for brokers that allow anonymous clients
+    pub username: Option<String<MAX_CREDENTIAL_LEN>>,
+    pub password: Option<String<MAX_CREDENTIAL_LEN>>,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            // There's no broker to talk to until one is set up
+            enabled: false,
+            // The first address the default DHCP pool hands out, which goes to
+            // the host on the other end of the USB link
+            broker: Ipv4Addr::new(10, 42, 0, 50),
+            port: DEFAULT_PORT,
+            username: None,
+            password: None,
+        }
+    }
+}
+
+impl Validate for MqttConfig {
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.enabled && self.broker.is_unspecified() {
+            return Err(ValidationError::new("broker", "must be set"));
+        }
+        if self.port == 0 {
+            return Err(ValidationError::new("port", "must be between 1 and 65535"));
+        }
+        if self.password.is_some() && self.username.is_none() {
+            return Err(ValidationError::new(
+                "password",
+                "can only be used with a username",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// An `MqttConfig` as the API shows it, which only says whether there's a
+/// password rather than giving it away.
+#[derive(serde::Serialize)]
+pub struct RedactedMqttConfig {
+    pub enabled: bool,
+    pub broker: Ipv4Addr,
+    pub port: u16,
+    pub username: Option<String<MAX_CREDENTIAL_LEN>>,
+    pub password_set: bool,
+}
+
+impl From<&MqttConfig> for RedactedMqttConfig {
+    fn from(config: &MqttConfig) -> Self {
+        Self {
+            enabled: config.enabled,
+            broker: config.broker,
+            port: config.port,
+            username: config.username.clone(),
+            password_set: config.password.is_some(),
+        }
+    }
+}
+
+/// A new `MqttConfig` sent to the API. The password can't be read back, so
+/// leaving it out keeps the one already saved, and `null` removes it.
+#[derive(serde::Deserialize)]
+pub struct MqttConfigUpdate {
+    pub enabled: bool,
+    pub broker: Ipv4Addr,
+    pub port: u16,
+    pub username: Option<String<MAX_CREDENTIAL_LEN>>,
+    #[serde(default, deserialize_with = "present")]
+    pub password: Option<Option<String<MAX_CREDENTIAL_LEN>>>,
+}
+
+/// Tells a field that's `null` apart from one that's left out, which is `None`.
+fn present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: serde::Deserialize<'de>,
+{
+    serde::Deserialize::deserialize(deserializer).map(Some)
+}
+
+impl MqttConfigUpdate {
+    /// The config this makes of `current`.
+    pub fn apply(self, current: &MqttConfig) -> MqttConfig {
+        MqttConfig {
+            enabled: self.enabled,
+            broker: self.broker,
+            port: self.port,
+            username: self.username,
+            password: self.password.unwrap_or_else(|| current.password.clone()),
+        }
+    }
+}
+
+/// MQTT settings shared between the web API and the client task.
+pub struct MqttControl {
+    config: Watch<CriticalSectionRawMutex, MqttConfig, 1>,
+}
+
+impl MqttControl {
+    pub fn new(config: MqttConfig) -> Self {
+        Self {
+            config: Watch::new_with(config),
+        }
+    }
+
+    pub fn config(&self) -> MqttConfig {
+        self.config.try_get().unwrap_or_default()
+    }
+
+    pub fn set_config(&self, config: MqttConfig) {
+        self.config.sender().send(config);
+    }
+
+    pub fn receiver(&self) -> Option<Receiver<'_, CriticalSectionRawMutex, MqttConfig, 1>> {
+        self.config.receiver()
+    }
+}
+
+#[derive(Format, Debug, Clone, Copy, PartialEq)]
+pub enum MqttError {
+    /// The connection failed or was closed
+    Io,
+    Timeout,
+    /// The broker turned us away, with its CONNACK return code
+    Refused(u8),
+    /// The broker sent something that isn't MQTT
+    Protocol,
+    /// A packet of ours didn't fit in `MAX_PACKET_LEN`
+    TooLong,
+}
+
+fn io_error<E>(_: E) -> MqttError {
+    MqttError::Io
+}
+
+/// Opens connections to the broker, over embassy-net on the RP2040 or tokio
+/// in the simulator.
+pub trait Connect {
+    type Connection<'a>: Read + Write
+    where
+        Self: 'a;
+
+    async fn connect(&mut self, broker: SocketAddrV4) -> Result<Self::Connection<'_>, MqttError>;
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq)]
+enum Power {
+    #[serde(rename = "ON")]
+    On,
+    #[serde(rename = "OFF")]
+    Off,
+}
+
+impl Power {
+    fn name(self) -> &'static str {
+        match self {
+            Power::On => "ON",
+            Power::Off => "OFF",
+        }
+    }
+}
+
+impl From<bool> for Power {
+    fn from(on: bool) -> Self {
+        match on {
+            true => Power::On,
+            false => Power::Off,
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy)]
+enum Effect {
+    SingleColour,
+    RainbowCycle,
+    Cars,
+}
+
+/// The underpass light's state, in Home Assistant's JSON light schema. There's
+/// no brightness apart from the colour, so the brightness is its brightest
+/// channel.
+#[derive(serde::Serialize)]
+struct LightState {
+    state: Power,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    color_mode: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    brightness: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    color: Option<RGB8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    effect: Option<Effect>,
+}
+
+impl From<LightingState> for LightState {
+    fn from(lighting: LightingState) -> Self {
+        let effect = match lighting {
+            LightingState::Off => None,
+            LightingState::SingleColour(_) => Some(Effect::SingleColour),
+            LightingState::RainbowCycle => Some(Effect::RainbowCycle),
+            LightingState::Cars { .. } => Some(Effect::Cars),
+        };
+        Self {
+            state: Power::from(effect.is_some()),
+            color_mode: effect.map(|_| "rgb"),
+            brightness: lighting.colour().map(brightness),
+            color: lighting.colour(),
+            effect,
+        }
+    }
+}
+
+fn brightness(colour: RGB8) -> u8 {
+    colour.r.max(colour.g).max(colour.b)
+}
+
+/// Scale `colour` so its brightest channel is `brightness`.
+fn with_brightness(colour: RGB8, brightness: u8) -> RGB8 {
+    let max = self::brightness(colour) as u16;
+    if max == 0 {
+        return RGB8::new(brightness, brightness, brightness);
+    }
+    let scale = |channel: u8| (channel as u16 * brightness as u16 / max) as u8;
+    RGB8::new(scale(colour.r), scale(colour.g), scale(colour.b))
+}
+
+/// A command for the underpass light, where everything left out stays as it is.
+#[derive(serde::Deserialize)]
+struct LightCommand {
+    state: Option<Power>,
+    brightness: Option<u8>,
+    /// Home Assistant sends colours at full brightness, apart from
+    /// `brightness`
+    color: Option<RGB8>,
+    effect: Option<Effect>,
+}
+
+impl LightCommand {
+    fn apply(&self, lighting: &mut LightingState) {
+        if let Some(power) = self.state {
+            api::set_strip_power(lighting, power == Power::On);
+        }
+        if self.state == Some(Power::Off) {
+            return;
+        }
+
+        let current = lighting.colour();
+        let fallback = || {
+            current
+                .or(SharedState::default().underpass_lights_state.colour())
+                .unwrap_or_default()
+        };
+        let colour = match (self.color, self.brightness) {
+            (colour, Some(level)) => with_brightness(colour.unwrap_or_else(fallback), level),
+            // A new colour keeps the brightness the current one has
+            (Some(colour), None) => match current {
+                Some(current) if brightness(current) > 0 => {
+                    with_brightness(colour, brightness(current))
+                }
+                _ => colour,
+            },
+            (None, None) => fallback(),
+        };
+        *lighting = match (self.effect, *lighting) {
+            (Some(Effect::SingleColour), _) => LightingState::SingleColour(colour),
+            (Some(Effect::RainbowCycle), _) => LightingState::RainbowCycle,
+            (
+                Some(Effect::Cars) | None,
+                LightingState::Cars {
+                    min_interval,
+                    max_interval,
+                    speed_limit_kph,
+                    ..
+                },
+            ) => LightingState::Cars {
+                default_color: colour,
+                min_interval,
+                max_interval,
+                speed_limit_kph,
+            },
+            (Some(Effect::Cars), _) => LightingState::cars(colour),
+            (None, LightingState::SingleColour(_)) => LightingState::SingleColour(colour),
+            // Picking a colour on an effect without one switches to it
+            (None, _) if self.color.is_some() => LightingState::SingleColour(colour),
+            (None, current) => current,
+        };
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Device<'a> {
+    identifiers: [&'a str; 1],
+    name: &'static str,
+    model: &'static str,
+    sw_version: &'static str,
+}
+
+/// A Home Assistant discovery config. The fields after `device` only apply
+/// to some components.
+#[derive(serde::Serialize)]
+struct Discovery<'a> {
+    name: &'a str,
+    unique_id: &'a str,
+    availability_topic: &'a str,
+    state_topic: &'a str,
+    command_topic: &'a str,
+    device: &'a Device<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    options: Option<&'a [&'a str]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    schema: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    supported_color_modes: Option<[&'static str; 1]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    effect: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    effect_list: Option<&'a [&'a str]>,
+}
+
+impl<'a> Discovery<'a> {
+    fn new(
+        name: &'a str,
+        unique_id: &'a str,
+        availability_topic: &'a str,
+        state_topic: &'a str,
+        command_topic: &'a str,
+        device: &'a Device<'a>,
+    ) -> Self {
+        Self {
+            name,
+            unique_id,
+            availability_topic,
+            state_topic,
+            command_topic,
+            device,
+            options: None,
+            schema: None,
+            supported_color_modes: None,
+            effect: None,
+            effect_list: None,
+        }
+    }
+}
+
+fn topic(args: core::fmt::Arguments) -> Topic {
+    let mut topic = Topic::new();
+    // Every topic is well within the limit
+    let _ = topic.write_fmt(args);
+    topic
+}
+
+fn lamp_mode_name(mode: StreetlampMode) -> &'static str {
+    match mode {
+        StreetlampMode::Off => LAMP_MODES[0],
+        StreetlampMode::On => LAMP_MODES[1],
+        StreetlampMode::Flickering { .. } => LAMP_MODES[2],
+    }
+}
+
+/// Write an MQTT variable length integer, returning how many bytes it took.
+fn encode_remaining_length(mut len: usize, buffer: &mut [u8; 4]) -> usize {
+    let mut written = 0;
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        buffer[written] = byte;
+        written += 1;
+        if len == 0 {
+            return written;
+        }
+    }
+}
+
+/// Split the first packet off the front of `buffer`, returning its first
+/// byte, where its body starts and where it ends, or `None` if it hasn't all
+/// arrived yet. A packet too big to buffer is returned as soon as its length
+/// is known, with its end past `RX_BUFFER_LEN`.
+fn split_packet(buffer: &[u8]) -> Result<Option<(u8, usize, usize)>, MqttError> {
+    let Some((&header, rest)) = buffer.split_first() else {
+        return Ok(None);
+    };
+    let mut len = 0;
+    for (i, &byte) in rest.iter().enumerate().take(4) {
+        len |= ((byte & 0x7F) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            let start = 2 + i;
+            let end = start + len;
+            let ready = end <= buffer.len() || end > RX_BUFFER_LEN;
+            return Ok(ready.then_some((header, start, end)));
+        }
+    }
+    match rest.len() {
+        0..4 => Ok(None),
+        _ => Err(MqttError::Protocol),
+    }
+}
+
+/// Split an MQTT string off the front of `bytes`.
+fn split_string(bytes: &[u8]) -> Option<(&str, &[u8])> {
+    let (len, rest) = bytes.split_first_chunk()?;
+    let (string, rest) = rest.split_at_checked(u16::from_be_bytes(*len) as usize)?;
+    Some((core::str::from_utf8(string).ok()?, rest))
+}
+
+/// Builds the body of an outgoing packet, after the fixed header.
+struct PacketBody(Vec<u8, MAX_PACKET_LEN>);
+
+impl PacketBody {
+    fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) -> Result<&mut Self, MqttError> {
+        self.0
+            .extend_from_slice(bytes)
+            .map_err(|_| MqttError::TooLong)?;
+        Ok(self)
+    }
+
+    fn u16(&mut self, value: u16) -> Result<&mut Self, MqttError> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    fn string(&mut self, string: &[u8]) -> Result<&mut Self, MqttError> {
+        self.u16(string.len() as u16)?.bytes(string)
+    }
+}
+
+/// One connection to the broker, from CONNECT until it drops.
+struct Session<T> {
+    io: T,
+    /// Our hostname, which every topic of ours starts with
+    host: &'static str,
+    /// Our MAC address, which identifies the device and its entities
+    mac: String<12>,
+    rx_buffer: [u8; RX_BUFFER_LEN],
+    rx_len: usize,
+    /// How much more of a packet too big to buffer is still to be dropped
+    discard_len: usize,
+    last_sent: Instant,
+    last_received: Instant,
+    /// Whether the broker accepted the connection
+    connected: bool,
+}
+
+impl<T: Read + Write> Session<T> {
+    fn new(io: T, app: &AppState) -> Self {
+        Self {
+            io,
+            host: &app.network.hostname,
+            mac: app.device.mac_hex(),
+            rx_buffer: [0; RX_BUFFER_LEN],
+            rx_len: 0,
+            discard_len: 0,
+            last_sent: Instant::now(),
+            last_received: Instant::now(),
+            connected: false,
+        }
+    }
+
+    async fn send(&mut self, header: u8, body: &[u8]) -> Result<(), MqttError> {
+        let mut remaining_length = [0; 4];
+        let len = encode_remaining_length(body.len(), &mut remaining_length);
+        self.io.write_all(&[header]).await.map_err(io_error)?;
+        self.io
+            .write_all(&remaining_length[..len])
+            .await
+            .map_err(io_error)?;
+        self.io.write_all(body).await.map_err(io_error)?;
+        self.io.flush().await.map_err(io_error)?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    async fn publish(&mut self, topic: &str, payload: &[u8]) -> Result<(), MqttError> {
+        let mut body = PacketBody::new();
+        body.string(topic.as_bytes())?.bytes(payload)?;
+        // Everything we publish describes the current state, so keep it for
+        // clients that subscribe later
+        self.send(PUBLISH | PUBLISH_RETAIN, &body.0).await
+    }
+
+    async fn publish_json(
+        &mut self,
+        topic: &str,
+        payload: &impl serde::Serialize,
+    ) -> Result<(), MqttError> {
+        let mut buffer = [0; MAX_PAYLOAD_LEN];
+        let len =
+            serde_json_core::to_slice(payload, &mut buffer).map_err(|_| MqttError::TooLong)?;
+        self.publish(topic, &buffer[..len]).await
+    }
+
+    /// Read whatever the broker has sent next. Cancel safe, so it can race
+    /// against other events.
+    async fn fill(&mut self) -> Result<(), MqttError> {
+        let len = self
+            .io
+            .read(&mut self.rx_buffer[self.rx_len..])
+            .await
+            .map_err(io_error)?;
+        if len == 0 {
+            return Err(MqttError::Io);
+        }
+        self.rx_len += len;
+        self.last_received = Instant::now();
+        Ok(())
+    }
+
+    /// Drop the first `len` bytes of the receive buffer.
+    fn consume(&mut self, len: usize) {
+        self.rx_buffer.copy_within(len..self.rx_len, 0);
+        self.rx_len -= len;
+    }
+
+    /// The next whole packet in the receive buffer, as from `split_packet`.
+    /// Packets too big to buffer, such as a large retained message on a topic
+    /// we subscribe to, are dropped as they arrive.
+    fn next_packet(&mut self) -> Result<Option<(u8, usize, usize)>, MqttError> {
+        loop {
+            let len = self.discard_len.min(self.rx_len);
+            self.consume(len);
+            self.discard_len -= len;
+            if self.discard_len > 0 {
+                return Ok(None);
+            }
+
+            match split_packet(&self.rx_buffer[..self.rx_len])? {
+                Some((header, _, end)) if end > RX_BUFFER_LEN => {
+                    info!("Dropping {} byte MQTT packet {:x}", end, header);
+                    self.discard_len = end;
+                }
+                packet => return Ok(packet),
+            }
+        }
+    }
+
+    async fn connect(&mut self, config: &MqttConfig) -> Result<(), MqttError> {
+        let host = self.host;
+        let mut client_id = String::<{ MAX_HOSTNAME_LEN + 13 }>::new();
+        let _ = write!(client_id, "{host}-{}", self.mac);
+        let availability = topic(format_args!("{host}/status"));
+
+        let mut flags = CONNECT_CLEAN_SESSION | CONNECT_WILL | CONNECT_WILL_RETAIN;
+        if config.username.is_some() {
+            flags |= CONNECT_USERNAME;
+        }
+        if config.password.is_some() {
+            flags |= CONNECT_PASSWORD;
+        }
+
+        let mut body = PacketBody::new();
+        body.string(b"MQTT")?
+            .bytes(&[PROTOCOL_LEVEL_3_1_1, flags])?
+            .u16(KEEP_ALIVE.as_secs() as u16)?
+            .string(client_id.as_bytes())?
+            .string(availability.as_bytes())?
+            .string(b"offline")?;
+        if let Some(username) = &config.username {
+            body.string(username.as_bytes())?;
+        }
+        if let Some(password) = &config.password {
+            body.string(password.as_bytes())?;
+        }
+        self.send(CONNECT, &body.0).await?;
+
+        with_timeout(CONNECT_TIMEOUT, async {
+            loop {
+                if let Some((header, start, end)) = self.next_packet()? {
+                    let return_code = self.rx_buffer[start..end].get(1).copied();
+                    self.consume(end);
+                    return match (header, return_code) {
+                        (CONNACK, Some(0)) => Ok(()),
+                        (CONNACK, Some(code)) => Err(MqttError::Refused(code)),
+                        _ => Err(MqttError::Protocol),
+                    };
+                }
+                self.fill().await?;
+            }
+        })
+        .await
+        .map_err(|_| MqttError::Timeout)?
+    }
+
+    async fn subscribe(&mut self) -> Result<(), MqttError> {
+        let host = self.host;
+        let mut body = PacketBody::new();
+        body.u16(1)?;
+        for filter in [
+            topic(format_args!("{host}/+/set")),
+            topic(format_args!("{host}/lamp/+/set")),
+            topic(format_args!("{HA_STATUS_TOPIC}")),
+        ] {
+            body.string(filter.as_bytes())?.bytes(&[0])?;
+        }
+        self.send(SUBSCRIBE, &body.0).await
+    }
+
+    /// Publish our availability and the discovery configs for every entity.
+    async fn announce(&mut self) -> Result<(), MqttError> {
+        let host = self.host;
+        let availability = topic(format_args!("{host}/status"));
+        self.publish(&availability, b"online").await?;
+
+        let mac = self.mac.clone();
+        let device = Device {
+            identifiers: [&mac],
+            name: DEVICE_NAME,
+            model: "RP2040",
+            sw_version: env!("CARGO_PKG_VERSION"),
+        };
+
+        let unique_id = topic(format_args!("{mac}_streetlamps"));
+        let state_topic = topic(format_args!("{host}/streetlamps/state"));
+        let command_topic = topic(format_args!("{host}/streetlamps/set"));
+        let config = Discovery::new(
+            "Streetlamps",
+            &unique_id,
+            &availability,
+            &state_topic,
+            &command_topic,
+            &device,
+        );
+        let config_topic = topic(format_args!(
+            "{DISCOVERY_PREFIX}/switch/{host}/streetlamps/config"
+        ));
+        self.publish_json(&config_topic, &config).await?;
+
+        for id in 0..NUM_STREETLAMPS {
+            let name = topic(format_args!("Lamp {}", id + 1));
+            let unique_id = topic(format_args!("{mac}_lamp_{id}"));
+            let state_topic = topic(format_args!("{host}/lamp/{id}/state"));
+            let command_topic = topic(format_args!("{host}/lamp/{id}/set"));
+            let config = Discovery {
+                options: Some(&LAMP_MODES),
+                ..Discovery::new(
+                    &name,
+                    &unique_id,
+                    &availability,
+                    &state_topic,
+                    &command_topic,
+                    &device,
+                )
+            };
+            let config_topic = topic(format_args!(
+                "{DISCOVERY_PREFIX}/select/{host}/lamp_{id}/config"
+            ));
+            self.publish_json(&config_topic, &config).await?;
+        }
+
+        let unique_id = topic(format_args!("{mac}_underpass"));
+        let state_topic = topic(format_args!("{host}/underpass/state"));
+        let command_topic = topic(format_args!("{host}/underpass/set"));
+        let config = Discovery {
+            schema: Some("json"),
+            supported_color_modes: Some(["rgb"]),
+            effect: Some(true),
+            effect_list: Some(&EFFECTS),
+            ..Discovery::new(
+                "Underpass",
+                &unique_id,
+                &availability,
+                &state_topic,
+                &command_topic,
+                &device,
+            )
+        };
+        let config_topic = topic(format_args!(
+            "{DISCOVERY_PREFIX}/light/{host}/underpass/config"
+        ));
+        self.publish_json(&config_topic, &config).await
+    }
+
+    async fn publish_state(&mut self, state: &SharedState) -> Result<(), MqttError> {
+        let host = self.host;
+        let power = Power::from(state.streetlamps_enabled).name();
+        let state_topic = topic(format_args!("{host}/streetlamps/state"));
+        self.publish(&state_topic, power.as_bytes()).await?;
+
+        for (id, &mode) in state.streetlamps_modes.iter().enumerate() {
+            let state_topic = topic(format_args!("{host}/lamp/{id}/state"));
+            self.publish(&state_topic, lamp_mode_name(mode).as_bytes())
+                .await?;
+        }
+
+        let state_topic = topic(format_args!("{host}/underpass/state"));
+        let light = LightState::from(state.underpass_lights_state);
+        self.publish_json(&state_topic, &light).await
+    }
+
+    /// Connect, announce ourselves and then keep Home Assistant and the
+    /// diorama in step until the connection drops or the config changes.
+    async fn run(
+        &mut self,
+        config: &MqttConfig,
+        app: &AppState,
+        state_changes: &mut StateReceiver,
+        config_changes: &mut Receiver<'static, CriticalSectionRawMutex, MqttConfig, 1>,
+    ) -> Result<(), MqttError> {
+        self.connect(config).await?;
+        self.connected = true;
+        info!("Connected to MQTT broker");
+        event_log::record(format_args!("Connected to MQTT broker {}", config.broker));
+
+        self.subscribe().await?;
+        self.announce().await?;
+        self.publish_state(&app.shared.get()).await?;
+
+        loop {
+            let ping_at = self.last_sent + KEEP_ALIVE / 2;
+            match select4(
+                self.fill(),
+                state_changes.changed(),
+                config_changes.changed(),
+                Timer::at(ping_at),
+            )
+            .await
+            {
+                Either4::First(result) => {
+                    result?;
+                    while let Some((header, start, end)) = self.next_packet()? {
+                        let announce =
+                            handle_packet(app, header, &self.rx_buffer[start..end]).await;
+                        self.consume(end);
+                        if announce {
+                            self.announce().await?;
+                            self.publish_state(&app.shared.get()).await?;
+                        }
+                    }
+                }
+                Either4::Second(state) => self.publish_state(&state).await?,
+                Either4::Third(_) => {
+                    let _ = self.send(DISCONNECT, &[]).await;
+                    return Ok(());
+                }
+                Either4::Fourth(()) => {
+                    // The broker answers pings, so silence means it's gone
+                    if Instant::now() - self.last_received > KEEP_ALIVE + KEEP_ALIVE / 2 {
+                        return Err(MqttError::Timeout);
+                    }
+                    self.send(PINGREQ, &[]).await?;
+                }
+            }
+        }
+    }
+}
+
+/// What a packet from the broker asks for.
+enum Incoming {
+    /// Home Assistant has restarted and wants the discovery configs again
+    Rediscover,
+    Power(bool),
+    Underpass(LightCommand),
+    Lamp {
+        id: usize,
+        mode: u8,
+    },
+}
+
+/// Work out what a packet from the broker asks for, if anything, given the
+/// hostname our topics are under.
+fn parse_packet(hostname: &str, header: u8, body: &[u8]) -> Option<Incoming> {
+    // Subscription acks and ping responses need no action
+    if header & PACKET_TYPE_MASK != PUBLISH {
+        return None;
+    }
+    let (topic, rest) = split_string(body)?;
+    // Only QoS 0 is subscribed to, but a broker may still include an ID
+    let payload = match header & PUBLISH_QOS_MASK {
+        0 => rest,
+        _ => rest.get(2..).unwrap_or_default(),
+    };
+
+    if topic == HA_STATUS_TOPIC {
+        return (payload == b"online").then_some(Incoming::Rediscover);
+    }
+    let entity = topic
+        .strip_prefix(hostname)?
+        .strip_prefix('/')?
+        .strip_suffix("/set")?;
+
+    match entity {
+        "streetlamps" => match payload {
+            b"ON" => Some(Incoming::Power(true)),
+            b"OFF" => Some(Incoming::Power(false)),
+            _ => None,
+        },
+        "underpass" => serde_json_core::from_slice::<LightCommand>(payload)
+            .ok()
+            .map(|(command, _)| Incoming::Underpass(command)),
+        lamp => {
+            let id = lamp.strip_prefix("lamp/")?.parse::<usize>().ok()?;
+            let mode = LAMP_MODES
+                .iter()
+                .position(|mode| mode.as_bytes() == payload)?;
+            Some(Incoming::Lamp {
+                id,
+                mode: mode as u8,
+            })
+        }
+    }
+}
+
+/// Apply a packet from the broker, returning whether Home Assistant has
+/// asked for the discovery configs again.
+async fn handle_packet(app: &AppState, header: u8, body: &[u8]) -> bool {
+    match parse_packet(app.network.hostname.as_str(), header, body) {
+        Some(Incoming::Rediscover) => return true,
+        Some(Incoming::Power(on)) => api::set_power(app, on).await,
+        Some(Incoming::Underpass(command)) => {
+            let mut state = api::get_state(app).await;
+            command.apply(&mut state.underpass_lights_state);
+            let _ = api::set_state(app, state).await;
+        }
+        Some(Incoming::Lamp { id, mode }) => api::set_lamp(app, id, mode).await,
+        None => {}
+    }
+    false
+}
+
+/// Keeps a connection to the broker configured in `MqttControl`, reconnecting
+/// with exponential backoff whenever it drops.
+pub struct MqttClient {
+    app: AppState,
+    state_changes: StateReceiver,
+}
+
+impl MqttClient {
+    pub fn new(app: AppState, state_changes: StateReceiver) -> Self {
+        Self { app, state_changes }
+    }
+
+    pub async fn run<C: Connect>(mut self, mut connector: C) -> ! {
+        let mut config_changes = self.app.mqtt.receiver().unwrap();
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            let config = self.app.mqtt.config();
+            if !config.enabled {
+                config_changes.changed().await;
+                continue;
+            }
+
+            let broker = SocketAddrV4::new(config.broker, config.port);
+            let (result, connected) = match connector.connect(broker).await {
+                Ok(connection) => {
+                    let mut session = Session::new(connection, &self.app);
+                    let result = session
+                        .run(
+                            &config,
+                            &self.app,
+                            &mut self.state_changes,
+                            &mut config_changes,
+                        )
+                        .await;
+                    (result, session.connected)
+                }
+                Err(err) => (Err(err), false),
+            };
+
+            if connected {
+                backoff = MIN_BACKOFF;
+            }
+            match result {
+                // The config changed, so connect again straight away
+                Ok(()) => {}
+                Err(err) => {
+                    info!("MQTT connection to {} failed: {:?}", broker, err);
+                    event_log::record(format_args!("MQTT connection to {broker} failed: {err:?}"));
+                    // Retry after the backoff, or sooner if the config changes
+                    select(Timer::after(backoff), config_changes.changed()).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+}
+
+/// Connects to the broker over the USB network.
+#[cfg(target_os = "none")]
+pub struct TcpConnector {
+    stack: embassy_net::Stack<'static>,
+    rx_buffer: [u8; RX_BUFFER_LEN],
+    tx_buffer: [u8; MAX_PACKET_LEN],
+}
+
+#[cfg(target_os = "none")]
+impl TcpConnector {
+    pub fn new(stack: embassy_net::Stack<'static>) -> Self {
+        Self {
+            stack,
+            rx_buffer: [0; RX_BUFFER_LEN],
+            tx_buffer: [0; MAX_PACKET_LEN],
+        }
+    }
+}
+
+#[cfg(target_os = "none")]
+impl Connect for TcpConnector {
+    type Connection<'a> = embassy_net::tcp::TcpSocket<'a>;
+
+    async fn connect(&mut self, broker: SocketAddrV4) -> Result<Self::Connection<'_>, MqttError> {
+        let mut socket =
+            embassy_net::tcp::TcpSocket::new(self.stack, &mut self.rx_buffer, &mut self.tx_buffer);
+        socket.set_timeout(Some(2 * KEEP_ALIVE));
+        match with_timeout(CONNECT_TIMEOUT, socket.connect(broker)).await {
+            Ok(Ok(())) => Ok(socket),
+            Ok(Err(_)) => Err(MqttError::Io),
+            Err(_) => Err(MqttError::Timeout),
+        }
+    }
+}
+
+#[cfg(target_os = "none")]
+#[embassy_executor::task]
+pub async fn mqtt_task(stack: embassy_net::Stack<'static>, client: MqttClient) -> ! {
+    client.run(TcpConnector::new(stack)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOSTNAME: &str = "road";
+    const COLOUR: RGB8 = RGB8::new(200, 100, 0);
+
+    fn encoded(len: usize) -> heapless::Vec<u8, 4> {
+        let mut buffer = [0; 4];
+        let written = encode_remaining_length(len, &mut buffer);
+        buffer[..written].iter().copied().collect()
+    }
+
+    /// A QoS 0 PUBLISH body for `topic`.
+    fn publish(topic: &str, payload: &[u8]) -> heapless::Vec<u8, 128> {
+        let mut body = heapless::Vec::new();
+        body.extend_from_slice(&(topic.len() as u16).to_be_bytes())
+            .unwrap();
+        body.extend_from_slice(topic.as_bytes()).unwrap();
+        body.extend_from_slice(payload).unwrap();
+        body
+    }
+
+    fn parse(topic: &str, payload: &[u8]) -> Option<Incoming> {
+        parse_packet(HOSTNAME, PUBLISH, &publish(topic, payload))
+    }
+
+    fn applied(command: &str, mut lighting: LightingState) -> LightingState {
+        let (command, _) = serde_json_core::from_str::<LightCommand>(command).unwrap();
+        command.apply(&mut lighting);
+        lighting
+    }
+
+    #[test]
+    fn encodes_remaining_lengths() {
+        assert_eq!(encoded(0).as_slice(), &[0x00]);
+        assert_eq!(encoded(127).as_slice(), &[0x7F]);
+        assert_eq!(encoded(128).as_slice(), &[0x80, 0x01]);
+        assert_eq!(encoded(16_383).as_slice(), &[0xFF, 0x7F]);
+        assert_eq!(encoded(16_384).as_slice(), &[0x80, 0x80, 0x01]);
+        assert_eq!(encoded(268_435_455).as_slice(), &[0xFF, 0xFF, 0xFF, 0x7F]);
+    }
+
+    #[test]
+    fn splits_whole_packets() {
+        assert!(split_packet(&[0xD0, 0x00]).ok() == Some(Some((0xD0, 2, 2))));
+        // Only the first of two packets
+        assert!(split_packet(&[0x30, 0x02, 1, 2, 0xD0, 0x00]).ok() == Some(Some((0x30, 2, 4))));
+        // A two byte length
+        let mut packet = [0; 3 + 200];
+        packet[..3].copy_from_slice(&[0x30, 0xC8, 0x01]);
+        assert!(split_packet(&packet).ok() == Some(Some((0x30, 3, 203))));
+    }
+
+    #[test]
+    fn waits_for_the_rest_of_a_packet() {
+        assert!(split_packet(&[]).ok() == Some(None));
+        assert!(split_packet(&[0x30]).ok() == Some(None));
+        // Part way through the length
+        assert!(split_packet(&[0x30, 0x80]).ok() == Some(None));
+        // Part way through the body
+        assert!(split_packet(&[0x30, 0x03, 1, 2]).ok() == Some(None));
+    }
+
+    #[test]
+    fn returns_oversized_packets_once_their_length_is_known() {
+        let len = RX_BUFFER_LEN + 1;
+        let mut header = [0x30, 0, 0, 0, 0];
+        let written = encode_remaining_length(len, (&mut header[1..]).try_into().unwrap());
+        let start = 1 + written;
+        assert!(split_packet(&header[..start]).ok() == Some(Some((0x30, start, start + len))));
+    }
+
+    #[test]
+    fn rejects_lengths_over_four_bytes() {
+        assert!(split_packet(&[0x30, 0xFF, 0xFF, 0xFF, 0xFF, 0x01]) == Err(MqttError::Protocol));
+    }
+
+    #[test]
+    fn turns_the_underpass_off_and_on() {
+        let off = applied(r#"{"state":"OFF"}"#, LightingState::SingleColour(COLOUR));
+        assert!(off == LightingState::Off);
+        // Anything else in the command waits until it's back on
+        let off = applied(
+            r#"{"state":"OFF","effect":"RainbowCycle"}"#,
+            LightingState::Off,
+        );
+        assert!(off == LightingState::Off);
+        let on = applied(r#"{"state":"ON"}"#, LightingState::Off);
+        assert!(on != LightingState::Off);
+    }
+
+    #[test]
+    fn scales_the_colour_to_the_brightness() {
+        let dimmed = applied(
+            r#"{"state":"ON","brightness":100}"#,
+            LightingState::SingleColour(COLOUR),
+        );
+        assert!(dimmed == LightingState::SingleColour(RGB8::new(100, 50, 0)));
+
+        let both = applied(
+            r#"{"color":{"r":0,"g":255,"b":51},"brightness":50}"#,
+            LightingState::SingleColour(COLOUR),
+        );
+        assert!(both == LightingState::SingleColour(RGB8::new(0, 50, 10)));
+    }
+
+    #[test]
+    fn keeps_the_brightness_when_changing_colour() {
+        let recoloured = applied(
+            r#"{"color":{"r":0,"g":0,"b":255}}"#,
+            LightingState::SingleColour(COLOUR),
+        );
+        assert!(recoloured == LightingState::SingleColour(RGB8::new(0, 0, 200)));
+
+        // The rainbow has no colour of its own, so switches to the one picked
+        let picked = applied(
+            r#"{"color":{"r":0,"g":0,"b":255}}"#,
+            LightingState::RainbowCycle,
+        );
+        assert!(picked == LightingState::SingleColour(RGB8::new(0, 0, 255)));
+    }
+
+    #[test]
+    fn colours_the_cars_without_stopping_them() {
+        let mut cars = LightingState::cars(COLOUR);
+        if let LightingState::Cars { min_interval, .. } = &mut cars {
+            *min_interval = 99;
+        }
+        let recoloured = applied(r#"{"color":{"r":255,"g":0,"b":0}}"#, cars);
+        assert!(matches!(
+            recoloured,
+            LightingState::Cars {
+                default_color: RGB8 { r: 200, g: 0, b: 0 },
+                min_interval: 99,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn switches_effects() {
+        let rainbow = applied(
+            r#"{"effect":"RainbowCycle"}"#,
+            LightingState::SingleColour(COLOUR),
+        );
+        assert!(rainbow == LightingState::RainbowCycle);
+
+        let cars = applied(r#"{"effect":"Cars"}"#, LightingState::SingleColour(COLOUR));
+        assert!(cars == LightingState::cars(COLOUR));
+
+        let solid = applied(r#"{"effect":"SingleColour"}"#, cars);
+        assert!(solid == LightingState::SingleColour(COLOUR));
+    }
+
+    #[test]
+    fn reports_brightness_as_the_brightest_channel() {
+        let state = LightState::from(LightingState::SingleColour(COLOUR));
+        assert_eq!(state.brightness, Some(200));
+        assert!(LightState::from(LightingState::RainbowCycle)
+            .brightness
+            .is_none());
+    }
+
+    #[test]
+    fn dispatches_by_topic() {
+        assert!(matches!(
+            parse("road/streetlamps/set", b"OFF"),
+            Some(Incoming::Power(false))
+        ));
+        assert!(matches!(
+            parse("road/streetlamps/set", b"ON"),
+            Some(Incoming::Power(true))
+        ));
+        assert!(matches!(
+            parse("road/lamp/4/set", b"Flickering"),
+            Some(Incoming::Lamp { id: 4, mode: 2 })
+        ));
+        assert!(matches!(
+            parse("road/underpass/set", br#"{"effect":"Cars"}"#),
+            Some(Incoming::Underpass(LightCommand {
+                effect: Some(Effect::Cars),
+                ..
+            }))
+        ));
+        assert!(matches!(
+            parse(HA_STATUS_TOPIC, b"online"),
+            Some(Incoming::Rediscover)
+        ));
+    }
+
+    #[test]
+    fn ignores_other_topics_and_payloads() {
+        assert!(parse(HA_STATUS_TOPIC, b"offline").is_none());
+        assert!(parse("other/streetlamps/set", b"ON").is_none());
+        assert!(parse("road/streetlamps", b"ON").is_none());
+        assert!(parse("road/streetlamps/set", b"on").is_none());
+        assert!(parse("road/lamp/x/set", b"On").is_none());
+        assert!(parse("road/lamp/1/set", b"Dim").is_none());
+        assert!(parse("road/underpass/set", b"{").is_none());
+        // Not a PUBLISH
+        let body = publish("road/streetlamps/set", b"ON");
+        assert!(parse_packet(HOSTNAME, 0x90, &body).is_none());
+    }
+
+    #[test]
+    fn skips_the_packet_id_above_qos_0() {
+        let mut body = publish("road/streetlamps/set", b"");
+        body.extend_from_slice(&[0x00, 0x01]).unwrap();
+        body.extend_from_slice(b"OFF").unwrap();
+        assert!(matches!(
+            parse_packet(HOSTNAME, PUBLISH | 0x02, &body),
+            Some(Incoming::Power(false))
+        ));
+    }
+}
@@ -1,15 +1,17 @@
 use core::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
 use defmt::info;
 
+use edge_captive::io::DnsIoError;
 use edge_dhcp::io::{self, DEFAULT_SERVER_PORT};
 use edge_dhcp::server::{Server, ServerOptions};
 use edge_mdns::buf::VecBufAccess;
 use edge_mdns::domain::base::Ttl;
-use edge_mdns::host::Host;
-use edge_mdns::io::{Mdns, IPV4_DEFAULT_SOCKET};
-use edge_mdns::HostAnswersMdnsHandler;
+use edge_mdns::domain::rdata::AllRecordData;
+use edge_mdns::host::{Host, Service, ServiceAnswers};
+use edge_mdns::io::{Mdns, MdnsIoError, IPV4_DEFAULT_SOCKET};
+use edge_mdns::{HostAnswer, HostAnswers, HostAnswersMdnsHandler, MdnsError, RecordDataChain};
 use edge_nal::{UdpBind, UdpSplit};
-use edge_nal_embassy::{Udp, UdpBuffers};
+use edge_nal_embassy::{Udp, UdpBuffers, UdpError};
 use embassy_net::driver::Driver;
 use embassy_net::{Ipv4Address, Ipv4Cidr, Stack, StackResources};
 use embassy_rp::clocks::RoscRng;
@@ -19,29 +21,40 @@ use heapless::Vec;
 use rand::RngCore;
 use static_cell::StaticCell;
 
-use crate::{DEVICE_HOST, DNS_SERVERS, OUR_IP};
+use crate::device_id::DeviceId;
+use crate::network_config::{NetworkConfig, MAX_DHCP_LEASES};
+use crate::supervisor::{self, supervise};
+use crate::web::WEB_TASK_POOL_SIZE;
+use crate::DEVICE_NAME;
 
 const MTU: usize = 1514;
 
+/// Every socket that can be open at once: one per web server task, one each
+/// for the DHCP, mDNS and captive DNS servers, two each for DMX (E1.31 and
+/// Art-Net) and the realtime protocols (WLED UDP and DDP) and one for MQTT,
+/// plus a few spare
+const MAX_SOCKETS: usize = WEB_TASK_POOL_SIZE + 3 + 2 + 2 + 1 + 3;
+
 pub fn make_network_stack<D>(
     net_driver: D,
     rnd_seed: u64,
+    network: &NetworkConfig,
 ) -> (embassy_net::Runner<'static, D>, Stack<'static>)
 where
     D: Driver,
 {
     let config = embassy_net::Config::ipv4_static(embassy_net::StaticConfigV4 {
-        address: Ipv4Cidr::new(Ipv4Address::new(10, 42, 0, 1), 24),
+        address: Ipv4Cidr::new(network.address, network.prefix_len),
         dns_servers: Vec::new(),
         gateway: None,
     });
 
     // Init network stack
-    static RESOURCES: StaticCell<StackResources<12>> = StaticCell::new();
+    static RESOURCES: StaticCell<StackResources<MAX_SOCKETS>> = StaticCell::new();
     let (stack, runner) = embassy_net::new(
         net_driver,
         config,
-        RESOURCES.init(StackResources::<12>::new()),
+        RESOURCES.init(StackResources::new()),
         rnd_seed,
     );
     stack
@@ -52,7 +65,14 @@ where
 }
 
 #[embassy_executor::task]
-pub async fn dhcp_task(stack: Stack<'static>) -> () {
+pub async fn dhcp_task(stack: Stack<'static>, config: &'static NetworkConfig) -> ! {
+    supervise(supervisor::Service::Dhcp, || run_dhcp(stack, config)).await
+}
+
+async fn run_dhcp(
+    stack: Stack<'static>,
+    config: &'static NetworkConfig,
+) -> Result<(), io::Error<UdpError>> {
     let mut buf = [0; 1500];
 
     let buffers: UdpBuffers<1, 1500, 1500, 2> = UdpBuffers::new();
@@ -63,29 +83,50 @@ pub async fn dhcp_task(stack: Stack<'static>) -> () {
             DEFAULT_SERVER_PORT,
         )))
         .await
-        .unwrap();
+        .map_err(io::Error::Io)?;
 
+    // As a captive portal we're the host's way to everything, and the only
+    // time our DNS responder runs. Otherwise the host finds us over mDNS and
+    // keeps its own DNS servers.
+    let mut gateway = [config.address];
+    let dns_servers = [config.address];
+    let portal_url = config.portal_url();
     let options = {
-        let mut options = ServerOptions::new(OUR_IP, None);
-        options.dns = &DNS_SERVERS;
+        let mut options = ServerOptions::new(
+            config.address,
+            config.captive_portal.then_some(&mut gateway),
+        );
+        options.subnet = Some(config.netmask());
+        options.lease_duration_secs = config.dhcp_lease_secs;
+        if config.captive_portal {
+            options.dns = &dns_servers;
+            options.captive_url = Some(&portal_url);
+        }
         options
     };
 
-    return io::server::run(
-        &mut Server::<_, 2>::new_with_et(OUR_IP),
-        &options,
-        &mut socket,
-        &mut buf,
-    )
-    .await
-    .unwrap();
+    let mut server = Server::<_, MAX_DHCP_LEASES>::new_with_et(config.address);
+    server.range_start = config.dhcp_pool_start;
+    server.range_end = config.dhcp_pool_end();
+
+    io::server::run(&mut server, &options, &mut socket, &mut buf).await
 }
 
 #[embassy_executor::task]
-pub async fn captive_dns_task(stack: Stack<'static>) -> () {
+pub async fn captive_dns_task(stack: Stack<'static>, config: &'static NetworkConfig) -> ! {
+    supervise(supervisor::Service::CaptiveDns, || {
+        run_captive_dns(stack, config)
+    })
+    .await
+}
+
+async fn run_captive_dns(
+    stack: Stack<'static>,
+    config: &'static NetworkConfig,
+) -> Result<(), DnsIoError<UdpError>> {
     let mut tx_buf: [u8; 1500] = [0; 1500];
     let mut rx_buf: [u8; 1500] = [0; 1500];
-    let ip = Ipv4Addr::new(10, 42, 0, 1);
+    let ip = config.address;
 
     let buffers: UdpBuffers<3, 1500, 1500, 2> = UdpBuffers::new();
     let udp = Udp::new(stack, &buffers);
@@ -99,21 +140,60 @@ pub async fn captive_dns_task(stack: Stack<'static>) -> () {
         core::time::Duration::from_secs(60),
     )
     .await
-    .unwrap();
+}
+
+/// Answers for a host and every DNS-SD service it offers.
+struct ServicesAnswers<'a> {
+    host: &'a Host<'a>,
+    services: &'a [Service<'a>],
+}
+
+impl HostAnswers for ServicesAnswers<'_> {
+    fn visit<F, E>(&self, mut f: F) -> Result<(), E>
+    where
+        F: FnMut(HostAnswer) -> Result<(), E>,
+        E: From<MdnsError>,
+    {
+        self.host.visit(&mut f)?;
+        for service in self.services {
+            // Each service repeats the host's addresses, which were just given
+            ServiceAnswers::new(self.host, service).visit(|answer| match answer.data() {
+                RecordDataChain::Next(AllRecordData::A(_) | AllRecordData::Aaaa(_)) => Ok(()),
+                _ => f(answer),
+            })?;
+        }
+        Ok(())
+    }
 }
 
 #[embassy_executor::task]
-pub async fn mdns_task(stack: Stack<'static>) -> () {
+pub async fn mdns_task(
+    stack: Stack<'static>,
+    config: &'static NetworkConfig,
+    device: &'static DeviceId,
+) -> ! {
+    supervise(supervisor::Service::Mdns, || {
+        run_mdns(stack, config, device)
+    })
+    .await
+}
+
+async fn run_mdns(
+    stack: Stack<'static>,
+    config: &'static NetworkConfig,
+    device: &'static DeviceId,
+) -> Result<(), MdnsIoError<UdpError>> {
     let (recv_buf, send_buf) = (
         VecBufAccess::<NoopRawMutex, 1500>::new(),
         VecBufAccess::<NoopRawMutex, 1500>::new(),
     );
 
-    let ip = Ipv4Addr::new(10, 42, 0, 1);
-
     let buffers: UdpBuffers<3, 1500, 1500, 2> = UdpBuffers::new();
     let udp = Udp::new(stack, &buffers);
-    let mut socket = udp.bind(IPV4_DEFAULT_SOCKET).await.unwrap();
+    let mut socket = udp
+        .bind(IPV4_DEFAULT_SOCKET)
+        .await
+        .map_err(MdnsIoError::IoError)?;
     let (recv, send) = socket.split();
 
     let signal = Signal::<NoopRawMutex, ()>::new();
@@ -129,15 +209,52 @@ pub async fn mdns_task(stack: Stack<'static>) -> () {
     );
 
     let host = Host {
-        hostname: DEVICE_HOST,
-        ipv4: ip,
+        hostname: &config.hostname,
+        ipv4: config.address,
         ipv6: Ipv6Addr::UNSPECIFIED,
         ttl: Ttl::from_secs(60),
     };
 
+    let mac = device.mac_hex();
+    let services = [
+        // Lets Bonjour and Avahi browsers list the web UI. `path` is where
+        // it's served and `api` is the state endpoint, see web.rs
+        Service {
+            name: DEVICE_NAME,
+            priority: 0,
+            weight: 0,
+            service: "_http",
+            protocol: "_tcp",
+            port: 80,
+            service_subtypes: &[],
+            txt_kvs: &[
+                ("version", env!("CARGO_PKG_VERSION")),
+                ("name", DEVICE_NAME),
+                ("path", "/"),
+                ("api", "/state"),
+            ],
+        },
+        // WLED apps find devices by this service, and Home Assistant
+        // recognises them by the MAC address
+        Service {
+            name: &config.hostname,
+            priority: 0,
+            weight: 0,
+            service: "_wled",
+            protocol: "_tcp",
+            port: 80,
+            service_subtypes: &[],
+            txt_kvs: &[("mac", &mac)],
+        },
+    ];
+
     info!("Starting mDNS server");
 
-    mdns.run(HostAnswersMdnsHandler::new(&host)).await.unwrap();
+    mdns.run(HostAnswersMdnsHandler::new(ServicesAnswers {
+        host: &host,
+        services: &services,
+    }))
+    .await
 }
 
 #[embassy_executor::task]
@@ -0,0 +1,279 @@
+//! Settings for the USB network, read from flash at boot. Changes are saved
+//! straight away but only take effect after a restart, as the network tasks
+//! are set up once.
+
+use core::fmt::Write as _;
+use core::net::Ipv4Addr;
+
+use heapless::String;
+
+use crate::validation::{Reason, Validate, ValidationError};
+
+pub const MAX_HOSTNAME_LEN: usize = 32;
+/// How many hosts the DHCP server can keep leases for at once
+pub const MAX_DHCP_LEASES: usize = 8;
+static DHCP_POOL_SIZE_REASON: Reason = Reason::between(1, MAX_DHCP_LEASES as u32);
+
+/// Long enough for `http://255.255.255.255/`
+pub type PortalUrl = String<24>;
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, PartialEq)]
+pub struct NetworkConfig {
+    /// Our address on the USB link, which is also the DHCP server's
+    pub address: Ipv4Addr,
+    pub prefix_len: u8,
+    /// Advertised over mDNS as `<hostname>.local`, and the root of our MQTT
+    /// topics
+    pub hostname: String<MAX_HOSTNAME_LEN>,
+    /// The first address handed out to hosts, followed by the rest of the pool
+    pub dhcp_pool_start: Ipv4Addr,
+    pub dhcp_pool_size: u8,
+    pub dhcp_lease_secs: u32,
+    /// Answer every DNS query with our address and hand ourselves out as the
+    /// gateway, so the host's captive portal check opens the control panel
+    /// when the cable is plugged in
+    pub captive_portal: bool,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            address: Ipv4Addr::new(10, 42, 0, 1),
+            prefix_len: 24,
+            hostname: String::try_from("road").unwrap(),
+            dhcp_pool_start: Ipv4Addr::new(10, 42, 0, 50),
+            dhcp_pool_size: MAX_DHCP_LEASES as u8,
+            dhcp_lease_secs: 7200,
+            captive_portal: false,
+        }
+    }
+}
+
+impl NetworkConfig {
+    pub fn netmask(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.mask())
+    }
+
+    /// The last address in the DHCP pool.
+    pub fn dhcp_pool_end(&self) -> Ipv4Addr {
+        let size = self.dhcp_pool_size.max(1) as u32;
+        Ipv4Addr::from(u32::from(self.dhcp_pool_start).saturating_add(size - 1))
+    }
+
+    /// Where captive portal checks are sent.
+    pub fn portal_url(&self) -> PortalUrl {
+        let mut url = PortalUrl::new();
+        let _ = write!(url, "http://{}/", self.address);
+        url
+    }
+
+    fn mask(&self) -> u32 {
+        u32::MAX
+            .checked_shl(32 - self.prefix_len.min(32) as u32)
+            .unwrap_or(0)
+    }
+
+    /// Whether `address` is a host on our subnet, rather than its network or
+    /// broadcast address.
+    fn is_host(&self, address: Ipv4Addr) -> bool {
+        let mask = self.mask();
+        let address = u32::from(address);
+        let host = address & !mask;
+        address & mask == u32::from(self.address) & mask && host != 0 && host != !mask
+    }
+}
+
+impl Validate for NetworkConfig {
+    fn validate(&self) -> Result<(), ValidationError> {
+        if !(8..=30).contains(&self.prefix_len) {
+            return Err(ValidationError::new(
+                "prefix_len",
+                "must be between 8 and 30",
+            ));
+        }
+        let address = self.address;
+        if address.is_unspecified()
+            || address.is_loopback()
+            || address.is_multicast()
+            || address.is_broadcast()
+            || !self.is_host(address)
+        {
+            return Err(ValidationError::new(
+                "address",
+                "must be a host address on its subnet",
+            ));
+        }
+
+        let valid_label = self
+            .hostname
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-');
+        if self.hostname.is_empty()
+            || !valid_label
+            || self.hostname.starts_with('-')
+            || self.hostname.ends_with('-')
+        {
+            return Err(ValidationError::new(
+                "hostname",
+                "must be letters, digits and hyphens, not starting or ending with a hyphen",
+            ));
+        }
+
+        if self.dhcp_pool_size == 0 || self.dhcp_pool_size as usize > MAX_DHCP_LEASES {
+            return Err(ValidationError::new(
+                "dhcp_pool_size",
+                DHCP_POOL_SIZE_REASON.as_str(),
+            ));
+        }
+        let (start, end) = (self.dhcp_pool_start, self.dhcp_pool_end());
+        if !self.is_host(start) || !self.is_host(end) {
+            return Err(ValidationError::new(
+                "dhcp_pool_start",
+                "must leave the whole pool on our subnet",
+            ));
+        }
+        if (start..=end).contains(&address) {
+            return Err(ValidationError::new(
+                "dhcp_pool_start",
+                "must leave our address out of the pool",
+            ));
+        }
+        if self.dhcp_lease_secs < 60 {
+            return Err(ValidationError::new(
+                "dhcp_lease_secs",
+                "must be at least 60",
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(update: impl FnOnce(&mut NetworkConfig)) -> NetworkConfig {
+        let mut config = NetworkConfig::default();
+        update(&mut config);
+        config
+    }
+
+    fn invalid_field(config: NetworkConfig) -> &'static str {
+        config.validate().unwrap_err().field
+    }
+
+    #[test]
+    fn default_config_is_valid() {
+        assert!(NetworkConfig::default().validate().is_ok());
+    }
+
+    #[test]
+    fn ends_the_pool_after_its_size() {
+        assert_eq!(
+            NetworkConfig::default().dhcp_pool_end(),
+            Ipv4Addr::new(10, 42, 0, 57)
+        );
+        let single = config(|config| config.dhcp_pool_size = 1);
+        assert_eq!(single.dhcp_pool_end(), single.dhcp_pool_start);
+        // An empty pool is rejected, but mustn't underflow
+        let empty = config(|config| config.dhcp_pool_size = 0);
+        assert_eq!(empty.dhcp_pool_end(), empty.dhcp_pool_start);
+        let last = config(|config| config.dhcp_pool_start = Ipv4Addr::BROADCAST);
+        assert_eq!(last.dhcp_pool_end(), Ipv4Addr::BROADCAST);
+    }
+
+    #[test]
+    fn bounds_the_prefix_length() {
+        assert_eq!(
+            invalid_field(config(|config| config.prefix_len = 7)),
+            "prefix_len"
+        );
+        assert_eq!(
+            invalid_field(config(|config| config.prefix_len = 31)),
+            "prefix_len"
+        );
+        assert!(config(|config| config.prefix_len = 8).validate().is_ok());
+        let smallest = config(|config| {
+            config.prefix_len = 30;
+            config.dhcp_pool_start = Ipv4Addr::new(10, 42, 0, 2);
+            config.dhcp_pool_size = 1;
+        });
+        assert!(smallest.validate().is_ok());
+    }
+
+    #[test]
+    fn needs_a_host_address() {
+        for address in [
+            Ipv4Addr::new(10, 42, 0, 0),
+            Ipv4Addr::new(10, 42, 0, 255),
+            Ipv4Addr::UNSPECIFIED,
+            Ipv4Addr::LOCALHOST,
+            Ipv4Addr::new(224, 0, 0, 1),
+        ] {
+            assert_eq!(
+                invalid_field(config(|config| config.address = address)),
+                "address"
+            );
+        }
+    }
+
+    #[test]
+    fn needs_a_dns_label_for_a_hostname() {
+        for hostname in ["", "-road", "road-", "road.local", "road_1"] {
+            let invalid = config(|config| config.hostname = String::try_from(hostname).unwrap());
+            assert_eq!(invalid_field(invalid), "hostname");
+        }
+        let valid = config(|config| config.hostname = String::try_from("Road-2").unwrap());
+        assert!(valid.validate().is_ok());
+    }
+
+    #[test]
+    fn bounds_the_pool_size() {
+        for size in [0, MAX_DHCP_LEASES as u8 + 1] {
+            let err = config(|config| config.dhcp_pool_size = size)
+                .validate()
+                .unwrap_err();
+            assert_eq!(err.field, "dhcp_pool_size");
+            assert_eq!(err.reason, "must be between 1 and 8");
+        }
+    }
+
+    #[test]
+    fn keeps_the_pool_on_the_subnet() {
+        // Runs past the end of the subnet
+        let past_end = config(|config| config.dhcp_pool_start = Ipv4Addr::new(10, 42, 0, 250));
+        assert_eq!(invalid_field(past_end), "dhcp_pool_start");
+        // Ends on the broadcast address
+        let broadcast = config(|config| config.dhcp_pool_start = Ipv4Addr::new(10, 42, 0, 248));
+        assert_eq!(invalid_field(broadcast), "dhcp_pool_start");
+        assert!(
+            config(|config| config.dhcp_pool_start = Ipv4Addr::new(10, 42, 0, 247))
+                .validate()
+                .is_ok()
+        );
+        // Starts on the network address
+        let network = config(|config| config.dhcp_pool_start = Ipv4Addr::new(10, 42, 0, 0));
+        assert_eq!(invalid_field(network), "dhcp_pool_start");
+        let elsewhere = config(|config| config.dhcp_pool_start = Ipv4Addr::new(10, 43, 0, 50));
+        assert_eq!(invalid_field(elsewhere), "dhcp_pool_start");
+    }
+
+    #[test]
+    fn keeps_our_address_out_of_the_pool() {
+        let overlapping = config(|config| config.dhcp_pool_start = Ipv4Addr::new(10, 42, 0, 1));
+        let err = overlapping.validate().unwrap_err();
+        assert_eq!(err.field, "dhcp_pool_start");
+        assert_eq!(err.reason, "must leave our address out of the pool");
+    }
+
+    #[test]
+    fn sets_a_minimum_lease() {
+        assert_eq!(
+            invalid_field(config(|config| config.dhcp_lease_secs = 59)),
+            "dhcp_lease_secs"
+        );
+        assert!(config(|config| config.dhcp_lease_secs = 60)
+            .validate()
+            .is_ok());
+    }
+}
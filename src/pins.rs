@@ -1,3 +1,5 @@
+use smart_leds::RGB8;
+
 pub trait GpioPin {
     fn set_high(&mut self);
     fn set_low(&mut self);
@@ -7,3 +9,59 @@ pub trait PwmPin {
     fn set_duty(&mut self, duty: u16);
     fn set_enabled(&mut self, enabled: bool);
 }
+
+pub trait LedStrip<const N: usize> {
+    async fn write(&mut self, colours: &[RGB8; N]);
+}
+
+/// A lamp output, driven by PWM where the pin has a channel and by plain
+/// on/off switching where it doesn't.
+pub enum LampPin<P, G> {
+    Pwm(P),
+    /// Any non-zero duty turns the lamp fully on.
+    Gpio {
+        pin: G,
+        duty: u16,
+        enabled: bool,
+    },
+}
+
+impl<P, G: GpioPin> LampPin<P, G> {
+    pub fn gpio(pin: G) -> Self {
+        Self::Gpio {
+            pin,
+            duty: 0,
+            enabled: true,
+        }
+    }
+}
+
+impl<P: PwmPin, G: GpioPin> PwmPin for LampPin<P, G> {
+    fn set_duty(&mut self, new_duty: u16) {
+        match self {
+            Self::Pwm(pin) => pin.set_duty(new_duty),
+            Self::Gpio { pin, duty, enabled } => {
+                *duty = new_duty;
+                set_gpio_lamp(pin, *duty, *enabled);
+            }
+        }
+    }
+
+    fn set_enabled(&mut self, new_enabled: bool) {
+        match self {
+            Self::Pwm(pin) => pin.set_enabled(new_enabled),
+            Self::Gpio { pin, duty, enabled } => {
+                *enabled = new_enabled;
+                set_gpio_lamp(pin, *duty, *enabled);
+            }
+        }
+    }
+}
+
+fn set_gpio_lamp<G: GpioPin>(pin: &mut G, duty: u16, enabled: bool) {
+    if enabled && duty > 0 {
+        pin.set_high();
+    } else {
+        pin.set_low();
+    }
+}
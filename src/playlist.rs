@@ -0,0 +1,447 @@
+use core::str::FromStr;
+
+use defmt::{info, Format};
+use embassy_futures::select::{select, Either};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, mutex::Mutex};
+use embassy_time::{Duration, Timer};
+use heapless::Vec;
+use rand::RngCore;
+
+use crate::state::{SharedState, SharedStateWatch};
+use crate::storage::{PresetName, Storage};
+use crate::streetlamps::StreetlampMode;
+use crate::underpass_lights::{Crossfade, LightingState, MAX_TRANSITION_MS, TRANSITION_MS_REASON};
+use crate::validation::{Validate, ValidationError};
+
+pub const MAX_PLAYLIST_ENTRIES: usize = 8;
+
+/// The parts of `SharedState` a scene changes; anything left out keeps its
+/// current value.
+#[derive(serde::Deserialize, serde::Serialize, Format, Clone, PartialEq, Default)]
+pub struct SceneState {
+    pub streetlamps_enabled: Option<bool>,
+    pub streetlamps_brightness: Option<u8>,
+    pub streetlamps_modes: Option<[StreetlampMode; 6]>,
+    pub underpass_lights_state: Option<LightingState>,
+}
+
+impl SceneState {
+    fn apply(&self, state: &mut SharedState) {
+        if let Some(enabled) = self.streetlamps_enabled {
+            state.streetlamps_enabled = enabled;
+        }
+        if let Some(brightness) = self.streetlamps_brightness {
+            state.streetlamps_brightness = brightness;
+        }
+        if let Some(modes) = self.streetlamps_modes {
+            state.streetlamps_modes = modes;
+        }
+        if let Some(lighting) = self.underpass_lights_state {
+            state.underpass_lights_state = lighting;
+        }
+    }
+}
+
+impl Validate for SceneState {
+    fn validate(&self) -> Result<(), ValidationError> {
+        for (i, mode) in self.streetlamps_modes.iter().flatten().enumerate() {
+            mode.validate().map_err(|err| err.at(i))?;
+        }
+        match &self.underpass_lights_state {
+            Some(lighting) => lighting.validate(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Format, Clone, PartialEq)]
+pub enum Scene {
+    /// Recall a saved preset
+    Preset(PresetName),
+    State(SceneState),
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Format, Clone, PartialEq)]
+pub struct PlaylistEntry {
+    pub scene: Scene,
+    /// How long to stay on this scene before moving to the next
+    pub duration_secs: u16,
+    /// How long the underpass lights take to fade into this scene, in place
+    /// of `SharedState::underpass_transition_ms`, which is left as it is
+    #[serde(default)]
+    pub crossfade_ms: u16,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Format, Clone, PartialEq, Default)]
+pub struct Playlist {
+    pub entries: Vec<PlaylistEntry, MAX_PLAYLIST_ENTRIES>,
+    /// Start again from the top after the last entry instead of stopping
+    #[serde(rename = "loop")]
+    pub looping: bool,
+    /// Play the entries in a random order, reshuffled on every pass
+    pub shuffle: bool,
+    /// Start playing as soon as the diorama powers up
+    pub autoplay: bool,
+}
+
+impl Validate for Playlist {
+    fn validate(&self) -> Result<(), ValidationError> {
+        for (i, entry) in self.entries.iter().enumerate() {
+            if entry.crossfade_ms > MAX_TRANSITION_MS {
+                return Err(ValidationError::new(
+                    "entries.crossfade_ms",
+                    TRANSITION_MS_REASON.as_str(),
+                )
+                .at(i));
+            }
+            if entry.duration_secs == 0 {
+                return Err(
+                    ValidationError::new("entries.duration_secs", "must be at least 1").at(i),
+                );
+            }
+            if let Scene::State(scene) = &entry.scene {
+                scene.validate().map_err(|err| err.at(i))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(serde::Serialize, Format, Clone, Copy, PartialEq, Default)]
+pub struct PlaylistStatus {
+    pub playing: bool,
+    /// Index into `Playlist::entries` of the scene on show
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entry: Option<usize>,
+}
+
+#[derive(Format, Clone, Copy, PartialEq)]
+pub enum PlaylistCommand {
+    /// Play from the top, restarting if already playing
+    Start,
+    Stop,
+    /// Skip straight to the next scene
+    Next,
+    /// The playlist has been replaced
+    Reload,
+}
+
+/// Commands accepted from `/playlist/{command}`
+impl FromStr for PlaylistCommand {
+    type Err = ();
+
+    fn from_str(command: &str) -> Result<Self, Self::Err> {
+        match command {
+            "start" => Ok(PlaylistCommand::Start),
+            "stop" => Ok(PlaylistCommand::Stop),
+            "next" => Ok(PlaylistCommand::Next),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Playlist shared between the web API and `PlaylistRunner`.
+pub struct PlaylistControl {
+    playlist: Mutex<CriticalSectionRawMutex, Playlist>,
+    status: Mutex<CriticalSectionRawMutex, PlaylistStatus>,
+    commands: Channel<CriticalSectionRawMutex, PlaylistCommand, 4>,
+}
+
+impl PlaylistControl {
+    pub fn new(playlist: Playlist) -> Self {
+        Self {
+            playlist: Mutex::new(playlist),
+            status: Mutex::new(PlaylistStatus::default()),
+            commands: Channel::new(),
+        }
+    }
+
+    pub async fn playlist(&self) -> Playlist {
+        self.playlist.lock().await.clone()
+    }
+
+    pub async fn set_playlist(&self, playlist: Playlist) {
+        *self.playlist.lock().await = playlist;
+        self.commands.send(PlaylistCommand::Reload).await;
+    }
+
+    pub async fn status(&self) -> PlaylistStatus {
+        *self.status.lock().await
+    }
+
+    pub async fn send(&self, command: PlaylistCommand) {
+        self.commands.send(command).await;
+    }
+}
+
+/// Order to play `len` entries in for one pass of the playlist.
+fn play_order(
+    rng: &mut impl RngCore,
+    len: usize,
+    shuffle: bool,
+) -> Vec<usize, MAX_PLAYLIST_ENTRIES> {
+    let mut order: Vec<usize, MAX_PLAYLIST_ENTRIES> = (0..len).collect();
+    if shuffle {
+        for i in (1..order.len()).rev() {
+            let j = rng.next_u32() as usize % (i + 1);
+            order.swap(i, j);
+        }
+    }
+    order
+}
+
+/// How far through a playlist playback has got.
+struct Playback {
+    order: Vec<usize, MAX_PLAYLIST_ENTRIES>,
+    position: usize,
+}
+
+impl Playback {
+    fn start(playlist: &Playlist, rng: &mut impl RngCore) -> Self {
+        Self {
+            order: play_order(rng, playlist.entries.len(), playlist.shuffle),
+            position: 0,
+        }
+    }
+
+    /// Index into `Playlist::entries` of the scene to show.
+    fn entry(&self) -> usize {
+        self.order[self.position]
+    }
+
+    /// Move on to the next entry, starting another pass after the last one if
+    /// the playlist loops. Returns `false` once playback has finished.
+    fn advance(&mut self, playlist: &Playlist, rng: &mut impl RngCore) -> bool {
+        self.position += 1;
+        if self.position < self.order.len() {
+            return true;
+        }
+        if !playlist.looping {
+            return false;
+        }
+        *self = Playback::start(playlist, rng);
+        true
+    }
+}
+
+pub struct PlaylistRunner<R: RngCore> {
+    rng: R,
+    shared_state: SharedStateWatch,
+    storage: &'static Storage,
+    control: &'static PlaylistControl,
+    crossfade: &'static Crossfade,
+}
+
+impl<R: RngCore> PlaylistRunner<R> {
+    pub fn new(
+        rng: R,
+        shared_state: SharedStateWatch,
+        storage: &'static Storage,
+        control: &'static PlaylistControl,
+        crossfade: &'static Crossfade,
+    ) -> Self {
+        Self {
+            rng,
+            shared_state,
+            storage,
+            control,
+            crossfade,
+        }
+    }
+
+    async fn show(&mut self, entry: &PlaylistEntry) {
+        let mut state = self.shared_state.get();
+        let lighting = state.underpass_lights_state;
+        match &entry.scene {
+            Scene::State(scene) => scene.apply(&mut state),
+            Scene::Preset(name) => match self.storage.load_preset(name).await {
+                Ok(preset) if preset.validate().is_ok() => state = preset,
+                _ => {
+                    info!("Playlist skipping missing or invalid preset {:?}", name);
+                    return;
+                }
+            },
+        }
+        // Only a change of lighting takes the crossfade, so it can't be left
+        // waiting for some later change
+        if state.underpass_lights_state != lighting {
+            self.crossfade.signal(entry.crossfade_ms);
+        }
+        self.shared_state.set(state);
+    }
+
+    async fn set_status(&self, status: PlaylistStatus) {
+        *self.control.status.lock().await = status;
+    }
+
+    pub async fn run(mut self) -> ! {
+        let mut playing = self.control.playlist().await.autoplay;
+        loop {
+            if !playing {
+                self.set_status(PlaylistStatus::default()).await;
+                playing = self.control.commands.receive().await == PlaylistCommand::Start;
+                continue;
+            }
+
+            playing = self.play().await;
+        }
+    }
+
+    /// Play the playlist from the top, returning whether playback should
+    /// restart (`true`) or stop (`false`).
+    async fn play(&mut self) -> bool {
+        let playlist = self.control.playlist().await;
+        if playlist.entries.is_empty() {
+            return false;
+        }
+
+        let mut playback = Playback::start(&playlist, &mut self.rng);
+        loop {
+            let index = playback.entry();
+            let entry = &playlist.entries[index];
+            self.set_status(PlaylistStatus {
+                playing: true,
+                entry: Some(index),
+            })
+            .await;
+            self.show(entry).await;
+
+            let duration = Duration::from_secs(entry.duration_secs as u64);
+            match select(Timer::after(duration), self.control.commands.receive()).await {
+                Either::First(()) | Either::Second(PlaylistCommand::Next) => {}
+                Either::Second(PlaylistCommand::Stop) => return false,
+                Either::Second(PlaylistCommand::Start | PlaylistCommand::Reload) => return true,
+            }
+
+            if !playback.advance(&playlist, &mut self.rng) {
+                return false;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::mock::StepRng;
+
+    use super::*;
+
+    fn entry(duration_secs: u16) -> PlaylistEntry {
+        PlaylistEntry {
+            scene: Scene::State(SceneState::default()),
+            duration_secs,
+            crossfade_ms: 0,
+        }
+    }
+
+    fn playlist(len: usize, looping: bool, shuffle: bool) -> Playlist {
+        Playlist {
+            entries: (1..=len as u16).map(entry).collect(),
+            looping,
+            shuffle,
+            autoplay: false,
+        }
+    }
+
+    fn is_permutation(order: &[usize], len: usize) -> bool {
+        let mut sorted: Vec<usize, MAX_PLAYLIST_ENTRIES> = order.iter().copied().collect();
+        sorted.sort_unstable();
+        sorted.iter().copied().eq(0..len)
+    }
+
+    #[test]
+    fn accepts_a_valid_playlist() {
+        assert!(playlist(3, true, true).validate().is_ok());
+    }
+
+    #[test]
+    fn reports_the_entry_with_a_long_crossfade() {
+        let mut playlist = playlist(3, false, false);
+        playlist.entries[1].crossfade_ms = MAX_TRANSITION_MS + 1;
+        let err = playlist.validate().unwrap_err();
+        assert_eq!(err.field, "entries.crossfade_ms");
+        assert_eq!(err.index, Some(1));
+        assert_eq!(err.reason, "must be at most 10000");
+    }
+
+    #[test]
+    fn reports_the_entry_with_no_duration() {
+        let mut playlist = playlist(3, false, false);
+        playlist.entries[2].duration_secs = 0;
+        let err = playlist.validate().unwrap_err();
+        assert_eq!(err.field, "entries.duration_secs");
+        assert_eq!(err.index, Some(2));
+    }
+
+    #[test]
+    fn reports_the_entry_with_an_invalid_scene() {
+        let mut playlist = playlist(3, false, false);
+        playlist.entries[0].scene = Scene::State(SceneState {
+            streetlamps_modes: Some([StreetlampMode::Flickering { chance: 101 }; 6]),
+            ..SceneState::default()
+        });
+        let err = playlist.validate().unwrap_err();
+        assert_eq!(err.field, "streetlamps_modes.Flickering.chance");
+        assert_eq!(err.index, Some(0));
+    }
+
+    #[test]
+    fn plays_in_order_without_shuffle() {
+        let order = play_order(&mut StepRng::new(7, 13), 5, false);
+        assert_eq!(order.as_slice(), &[0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn shuffles_into_a_permutation() {
+        for (initial, increment) in [(0, 0), (1, 1), (u64::MAX, 3), (12345, 0x9E37_79B9)] {
+            let mut rng = StepRng::new(initial, increment);
+            for len in 0..=MAX_PLAYLIST_ENTRIES {
+                let order = play_order(&mut rng, len, true);
+                assert!(is_permutation(&order, len), "{:?}", order.as_slice());
+            }
+        }
+    }
+
+    #[test]
+    fn stops_after_one_pass_without_loop() {
+        let playlist = playlist(3, false, false);
+        let mut rng = StepRng::new(0, 0);
+        let mut playback = Playback::start(&playlist, &mut rng);
+        let mut shown = Vec::<usize, 8>::new();
+        loop {
+            shown.push(playback.entry()).unwrap();
+            if !playback.advance(&playlist, &mut rng) {
+                break;
+            }
+        }
+        assert_eq!(shown.as_slice(), &[0, 1, 2]);
+    }
+
+    #[test]
+    fn wraps_around_when_looping() {
+        let playlist = playlist(3, true, false);
+        let mut rng = StepRng::new(0, 0);
+        let mut playback = Playback::start(&playlist, &mut rng);
+        let mut shown = Vec::<usize, 8>::new();
+        for _ in 0..7 {
+            shown.push(playback.entry()).unwrap();
+            assert!(playback.advance(&playlist, &mut rng));
+        }
+        assert_eq!(shown.as_slice(), &[0, 1, 2, 0, 1, 2, 0]);
+    }
+
+    #[test]
+    fn reshuffles_every_pass() {
+        let playlist = playlist(4, true, true);
+        let mut rng = StepRng::new(3, 0x9E37_79B9);
+        let mut playback = Playback::start(&playlist, &mut rng);
+        for _ in 0..3 {
+            let mut pass = Vec::<usize, 8>::new();
+            for _ in 0..4 {
+                pass.push(playback.entry()).unwrap();
+                assert!(playback.advance(&playlist, &mut rng));
+            }
+            assert!(is_permutation(&pass, 4), "{:?}", pass.as_slice());
+        }
+    }
+}
@@ -0,0 +1,364 @@
+//! Pixel streaming from tools like xLights, LedFx and Hyperion, over DDP and
+//! WLED's UDP realtime protocols. Streamed frames take over the underpass
+//! lights through `LiveInputs`, and the lights go back to their effect once the
+//! stream times out.
+
+use embassy_time::Duration;
+use smart_leds::RGB8;
+
+use crate::live::LiveInputs;
+use crate::underpass_lights::NUM_LEDS;
+
+pub const DDP_PORT: u16 = 4048;
+pub const WLED_REALTIME_PORT: u16 = 21324;
+
+/// Room for a full-size packet from either protocol
+pub const MAX_PACKET_LEN: usize = 1500;
+
+/// DDP doesn't say how long a frame lasts, so use WLED's default
+const DDP_TIMEOUT: Duration = Duration::from_millis(2500);
+
+const DDP_HEADER_LEN: usize = 10;
+const DDP_VERSION_MASK: u8 = 0xC0;
+const DDP_VERSION_1: u8 = 0x40;
+const DDP_FLAG_TIMECODE: u8 = 0x10;
+const DDP_FLAG_QUERY: u8 = 0x02;
+const DDP_FLAG_PUSH: u8 = 0x01;
+const DDP_TYPE_UNDEFINED: u8 = 0x00;
+/// What older senders use for 8 bit RGB
+const DDP_TYPE_RGB_LEGACY: u8 = 0x01;
+const DDP_TYPE_RGB8: u8 = 0x0B;
+const DDP_ID_DISPLAY: u8 = 1;
+const DDP_ID_ALL: u8 = 255;
+
+const WLED_WARLS: u8 = 1;
+const WLED_DRGB: u8 = 2;
+const WLED_DNRGB: u8 = 4;
+/// A timeout of 255 means stay in realtime mode until told otherwise
+const WLED_NO_TIMEOUT: u8 = 255;
+
+/// What a packet asks to be done with the frame buffer.
+enum Update {
+    /// Pixels were written, but more are on the way before the frame is shown
+    Partial,
+    Show(Duration),
+}
+
+/// Writes DDP or WLED realtime packets into a frame buffer and passes
+/// finished frames to `LiveInputs`. Pixels a packet doesn't mention keep
+/// their last value, so each port needs its own receiver.
+#[derive(Clone)]
+pub struct RealtimeReceiver {
+    live: &'static LiveInputs,
+    frame: [RGB8; NUM_LEDS],
+}
+
+impl RealtimeReceiver {
+    pub fn new(live: &'static LiveInputs) -> Self {
+        Self {
+            live,
+            frame: [RGB8::default(); NUM_LEDS],
+        }
+    }
+
+    /// Handle a packet that arrived on `port`.
+    pub fn handle(&mut self, port: u16, packet: &[u8]) {
+        let update = match port {
+            DDP_PORT => self.handle_ddp(packet),
+            WLED_REALTIME_PORT => self.handle_wled(packet),
+            _ => None,
+        };
+        if let Some(Update::Show(timeout)) = update {
+            self.live.underpass.send(self.frame, timeout);
+        }
+    }
+
+    /// Write `rgb` triples into the frame from LED `start` onwards.
+    fn write_rgb(&mut self, start: usize, rgb: &[u8]) {
+        let leds = self.frame.iter_mut().skip(start);
+        for (led, rgb) in leds.zip(rgb.chunks_exact(3)) {
+            *led = RGB8::new(rgb[0], rgb[1], rgb[2]);
+        }
+    }
+
+    fn handle_ddp(&mut self, packet: &[u8]) -> Option<Update> {
+        let header = packet.get(..DDP_HEADER_LEN)?;
+        let flags = header[0];
+        if flags & DDP_VERSION_MASK != DDP_VERSION_1 || flags & DDP_FLAG_QUERY != 0 {
+            return None;
+        }
+        if !matches!(
+            header[2],
+            DDP_TYPE_UNDEFINED | DDP_TYPE_RGB_LEGACY | DDP_TYPE_RGB8
+        ) {
+            return None;
+        }
+        if !matches!(header[3], DDP_ID_DISPLAY | DDP_ID_ALL) {
+            return None;
+        }
+
+        let offset = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let length = u16::from_be_bytes([header[8], header[9]]) as usize;
+        let data_start = match flags & DDP_FLAG_TIMECODE {
+            0 => DDP_HEADER_LEN,
+            _ => DDP_HEADER_LEN + 4,
+        };
+        let data = packet.get(data_start..data_start + length)?;
+
+        // The offset is in bytes, which may not land on the start of a pixel
+        let skip = (3 - offset % 3) % 3;
+        self.write_rgb(offset.div_ceil(3), data.get(skip..)?);
+
+        Some(match flags & DDP_FLAG_PUSH {
+            0 => Update::Partial,
+            _ => Update::Show(DDP_TIMEOUT),
+        })
+    }
+
+    fn handle_wled(&mut self, packet: &[u8]) -> Option<Update> {
+        let (&[protocol, timeout_secs], data) = packet.split_first_chunk()?;
+        match protocol {
+            WLED_WARLS => {
+                for pixel in data.chunks_exact(4) {
+                    self.write_rgb(pixel[0] as usize, &pixel[1..]);
+                }
+            }
+            WLED_DRGB => self.write_rgb(0, data),
+            WLED_DNRGB => {
+                let (&start, data) = data.split_first_chunk()?;
+                self.write_rgb(u16::from_be_bytes(start) as usize, data);
+            }
+            _ => return None,
+        }
+
+        let timeout = match timeout_secs {
+            WLED_NO_TIMEOUT => Duration::from_secs(u32::MAX as u64),
+            secs => Duration::from_secs(secs as u64),
+        };
+        Some(Update::Show(timeout))
+    }
+
+    /// Receive packets on `port` for as long as it can be listened on.
+    #[cfg(target_os = "none")]
+    pub async fn run(
+        mut self,
+        stack: embassy_net::Stack<'static>,
+        port: u16,
+    ) -> Result<(), embassy_net::udp::BindError> {
+        use embassy_net::udp::{PacketMetadata, UdpSocket};
+
+        let mut rx_meta = [PacketMetadata::EMPTY; 4];
+        let mut rx_buffer = [0; 2 * MAX_PACKET_LEN];
+        let mut tx_meta = [PacketMetadata::EMPTY; 1];
+        let mut tx_buffer = [0; 64];
+        let mut socket = UdpSocket::new(
+            stack,
+            &mut rx_meta,
+            &mut rx_buffer,
+            &mut tx_meta,
+            &mut tx_buffer,
+        );
+        socket.bind(port)?;
+
+        let mut packet = [0; MAX_PACKET_LEN];
+        loop {
+            if let Ok((len, _)) = socket.recv_from(&mut packet).await {
+                self.handle(port, &packet[..len]);
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "none")]
+#[embassy_executor::task(pool_size = 2)]
+pub async fn realtime_task(
+    stack: embassy_net::Stack<'static>,
+    port: u16,
+    receiver: RealtimeReceiver,
+) -> ! {
+    use crate::supervisor::{supervise, Service};
+
+    let service = match port {
+        DDP_PORT => Service::Ddp,
+        _ => Service::WledRealtime,
+    };
+    // Each start gets a blank frame, as the failed one's is dropped with it
+    supervise(service, || receiver.clone().run(stack, port)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn receiver() -> RealtimeReceiver {
+        RealtimeReceiver::new(Box::leak(Box::new(LiveInputs::new())))
+    }
+
+    /// A DDP packet for the display, with `data` from byte `offset`.
+    fn ddp(flags: u8, offset: u32, data: &[u8]) -> heapless::Vec<u8, MAX_PACKET_LEN> {
+        let mut packet = heapless::Vec::new();
+        packet
+            .extend_from_slice(&[DDP_VERSION_1 | flags, 0, DDP_TYPE_RGB8, DDP_ID_DISPLAY])
+            .unwrap();
+        packet.extend_from_slice(&offset.to_be_bytes()).unwrap();
+        packet
+            .extend_from_slice(&(data.len() as u16).to_be_bytes())
+            .unwrap();
+        packet.extend_from_slice(data).unwrap();
+        packet
+    }
+
+    fn shown(receiver: &RealtimeReceiver) -> Option<[RGB8; NUM_LEDS]> {
+        receiver
+            .live
+            .underpass
+            .try_take()
+            .map(|stream| stream.value)
+    }
+
+    #[test]
+    fn shows_ddp_frames_on_push() {
+        let mut receiver = receiver();
+        receiver.handle(DDP_PORT, &ddp(DDP_FLAG_PUSH, 0, &[1, 2, 3, 4, 5, 6]));
+        let frame = shown(&receiver).unwrap();
+        assert_eq!(frame[0], RGB8::new(1, 2, 3));
+        assert_eq!(frame[1], RGB8::new(4, 5, 6));
+        assert_eq!(frame[2], RGB8::default());
+    }
+
+    #[test]
+    fn holds_ddp_frames_until_pushed() {
+        let mut receiver = receiver();
+        receiver.handle(DDP_PORT, &ddp(0, 0, &[1, 2, 3]));
+        assert!(shown(&receiver).is_none());
+
+        // The second half of the frame, by byte offset
+        receiver.handle(DDP_PORT, &ddp(DDP_FLAG_PUSH, 3, &[4, 5, 6]));
+        let frame = shown(&receiver).unwrap();
+        assert_eq!(frame[0], RGB8::new(1, 2, 3));
+        assert_eq!(frame[1], RGB8::new(4, 5, 6));
+    }
+
+    #[test]
+    fn skips_to_the_next_whole_ddp_pixel() {
+        let mut receiver = receiver();
+        receiver.handle(DDP_PORT, &ddp(DDP_FLAG_PUSH, 4, &[9, 9, 7, 8, 9]));
+        let frame = shown(&receiver).unwrap();
+        assert_eq!(frame[1], RGB8::default());
+        assert_eq!(frame[2], RGB8::new(7, 8, 9));
+    }
+
+    #[test]
+    fn skips_the_ddp_timecode() {
+        let mut receiver = receiver();
+        let mut packet = ddp(DDP_FLAG_PUSH | DDP_FLAG_TIMECODE, 0, &[]);
+        packet[9] = 3;
+        packet.extend_from_slice(&[0xaa; 4]).unwrap();
+        packet.extend_from_slice(&[1, 2, 3]).unwrap();
+        receiver.handle(DDP_PORT, &packet);
+        assert_eq!(shown(&receiver).unwrap()[0], RGB8::new(1, 2, 3));
+    }
+
+    #[test]
+    fn ignores_ddp_leds_past_the_end() {
+        let mut receiver = receiver();
+        let offset = 3 * (NUM_LEDS as u32 - 1);
+        receiver.handle(DDP_PORT, &ddp(DDP_FLAG_PUSH, offset, &[1, 2, 3, 4, 5, 6]));
+        let frame = shown(&receiver).unwrap();
+        assert_eq!(frame[NUM_LEDS - 1], RGB8::new(1, 2, 3));
+
+        receiver.handle(DDP_PORT, &ddp(DDP_FLAG_PUSH, u32::MAX, &[1, 2, 3]));
+        assert!(shown(&receiver).is_some());
+    }
+
+    #[test]
+    fn rejects_other_ddp_packets() {
+        let mut receiver = receiver();
+        // Query
+        receiver.handle(
+            DDP_PORT,
+            &ddp(DDP_FLAG_PUSH | DDP_FLAG_QUERY, 0, &[1, 2, 3]),
+        );
+        // Version 2
+        let mut packet = ddp(DDP_FLAG_PUSH, 0, &[1, 2, 3]);
+        packet[0] = 0x80 | DDP_FLAG_PUSH;
+        receiver.handle(DDP_PORT, &packet);
+        // Another device's config
+        let mut packet = ddp(DDP_FLAG_PUSH, 0, &[1, 2, 3]);
+        packet[3] = 250;
+        receiver.handle(DDP_PORT, &packet);
+        // Shorter than it says
+        let packet = ddp(DDP_FLAG_PUSH, 0, &[1, 2, 3]);
+        receiver.handle(DDP_PORT, &packet[..packet.len() - 1]);
+        receiver.handle(DDP_PORT, &packet[..DDP_HEADER_LEN - 1]);
+
+        assert!(shown(&receiver).is_none());
+        assert_eq!(receiver.frame, [RGB8::default(); NUM_LEDS]);
+    }
+
+    #[test]
+    fn writes_warls_pixels_by_index() {
+        let mut receiver = receiver();
+        receiver.handle(
+            WLED_REALTIME_PORT,
+            &[WLED_WARLS, 1, 3, 1, 2, 3, 0, 4, 5, 6, 200, 7, 8, 9],
+        );
+        let frame = shown(&receiver).unwrap();
+        assert_eq!(frame[0], RGB8::new(4, 5, 6));
+        assert_eq!(frame[3], RGB8::new(1, 2, 3));
+        assert_eq!(frame[1], RGB8::default());
+    }
+
+    #[test]
+    fn writes_drgb_from_the_first_led() {
+        let mut receiver = receiver();
+        let mut packet = [0; 2 + 3 * (NUM_LEDS + 1)];
+        packet[..2].copy_from_slice(&[WLED_DRGB, 1]);
+        for (i, byte) in packet[2..].iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        receiver.handle(WLED_REALTIME_PORT, &packet);
+        let frame = shown(&receiver).unwrap();
+        assert_eq!(frame[0], RGB8::new(0, 1, 2));
+        assert_eq!(frame[NUM_LEDS - 1], RGB8::new(45, 46, 47));
+    }
+
+    #[test]
+    fn writes_dnrgb_from_its_start_index() {
+        let mut receiver = receiver();
+        receiver.handle(WLED_REALTIME_PORT, &[WLED_DNRGB, 1, 0, 5, 1, 2, 3]);
+        let frame = shown(&receiver).unwrap();
+        assert_eq!(frame[4], RGB8::default());
+        assert_eq!(frame[5], RGB8::new(1, 2, 3));
+
+        // The start index is 16 bits, so this is LED 261
+        receiver.handle(WLED_REALTIME_PORT, &[WLED_DNRGB, 1, 1, 5, 4, 5, 6]);
+        let frame = shown(&receiver).unwrap();
+        assert_eq!(frame[5], RGB8::new(1, 2, 3));
+        assert!(!frame.contains(&RGB8::new(4, 5, 6)));
+    }
+
+    #[test]
+    fn uses_the_wled_timeout() {
+        let mut receiver = receiver();
+        assert!(matches!(
+            receiver.handle_wled(&[WLED_DRGB, 3, 1, 2, 3]),
+            Some(Update::Show(timeout)) if timeout == Duration::from_secs(3)
+        ));
+        // 255 stays in realtime mode until told otherwise
+        assert!(matches!(
+            receiver.handle_wled(&[WLED_DRGB, WLED_NO_TIMEOUT, 1, 2, 3]),
+            Some(Update::Show(timeout)) if timeout >= Duration::from_secs(365 * 24 * 60 * 60)
+        ));
+    }
+
+    #[test]
+    fn rejects_other_wled_packets() {
+        let mut receiver = receiver();
+        // WLED's notifier protocol
+        receiver.handle(WLED_REALTIME_PORT, &[0, 1, 2, 3, 4]);
+        receiver.handle(WLED_REALTIME_PORT, &[WLED_DRGB]);
+        receiver.handle(WLED_REALTIME_PORT, &[WLED_DNRGB, 1, 0]);
+        assert!(shown(&receiver).is_none());
+    }
+}
@@ -1,6 +1,10 @@
 // RP chip implementations
-use crate::pins::GpioPin;
+use crate::pins::{GpioPin, LedStrip, PwmPin};
 use embassy_rp::gpio::Output;
+use embassy_rp::pio::Instance;
+use embassy_rp::pio_programs::ws2812::PioWs2812;
+use embassy_rp::pwm::{Pwm, PwmOutput, SetDutyCycle};
+use smart_leds::RGB8;
 
 impl GpioPin for Output<'_> {
     fn set_high(&mut self) {
@@ -11,3 +15,62 @@ impl GpioPin for Output<'_> {
         self.set_low();
     }
 }
+
+impl<P: Instance, const S: usize, const N: usize> LedStrip<N> for PioWs2812<'_, P, S, N> {
+    async fn write(&mut self, colours: &[RGB8; N]) {
+        PioWs2812::write(self, colours).await;
+    }
+}
+
+/// One channel of an RP2040 PWM slice.
+///
+/// The slice counter wraps at `top`, so the full `u16` duty range passed to
+/// [`PwmPin::set_duty`] is scaled onto `0..=top` when it is written out.
+pub struct PwmChannel<'d> {
+    output: PwmOutput<'d>,
+    duty: u16,
+    enabled: bool,
+}
+
+impl<'d> PwmChannel<'d> {
+    pub fn new(output: PwmOutput<'d>) -> Self {
+        let mut channel = Self {
+            output,
+            duty: 0,
+            enabled: true,
+        };
+        channel.apply();
+        channel
+    }
+
+    /// Split a slice configured with both outputs into its A and B channels.
+    pub fn split(pwm: Pwm<'d>) -> (Self, Self) {
+        let (a, b) = pwm.split();
+        (
+            Self::new(a.expect("PWM slice has no A output")),
+            Self::new(b.expect("PWM slice has no B output")),
+        )
+    }
+
+    fn apply(&mut self) {
+        let duty = if self.enabled { self.duty } else { 0 };
+        // Scaling onto `top` always lands within range, so this can't fail
+        let _ = self.output.set_duty_cycle_fraction(duty, u16::MAX);
+    }
+}
+
+impl PwmPin for PwmChannel<'_> {
+    fn set_duty(&mut self, duty: u16) {
+        if duty != self.duty {
+            self.duty = duty;
+            self.apply();
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        if enabled != self.enabled {
+            self.enabled = enabled;
+            self.apply();
+        }
+    }
+}
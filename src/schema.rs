@@ -0,0 +1,362 @@
+//! Layout of `SharedState`, the playlist and the settings in flash.
+//!
+//! State is stored as `[SCHEMA_MAGIC, version, payload..]`, where the payload
+//! is the bincode encoding of that version's struct. Firmware from before the
+//! state was versioned wrote a bare version 1 payload, which is recognised by
+//! its first byte: version 1 starts with the `streetlamps_enabled` bool, which
+//! bincode writes as 0 or 1, so it can never be `SCHEMA_MAGIC`.
+//!
+//! When the stored layout changes, bump `CURRENT_VERSION`, move a frozen copy
+//! of the outgoing types into a `vN` module here and add a `From<vN::..>`
+//! migration so older payloads are decoded as what they are and then upgraded
+//! one version at a time in `decode_version`.
+//!
+//! Playlists and the DMX, MQTT, network and MIDI configs use the same header
+//! with their own `PLAYLIST_VERSION`, `DMX_CONFIG_VERSION`,
+//! `MQTT_CONFIG_VERSION`, `NETWORK_CONFIG_VERSION` and `MIDI_CONFIG_VERSION`,
+//! and have no unversioned form.
+
+use bincode::serde::{decode_from_slice, encode_into_slice};
+use sequential_storage::map::SerializationError;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::dmx::DmxConfig;
+use crate::midi::MidiConfig;
+use crate::mqtt::MqttConfig;
+use crate::network_config::NetworkConfig;
+use crate::playlist::Playlist;
+use crate::state::{default_transition_ms, SharedState};
+use crate::streetlamps::StreetlampMode;
+use crate::underpass_lights::LightingState;
+
+/// Layout before `underpass_transition_ms` was added
+mod v1 {
+    use smart_leds::RGB8;
+
+    #[derive(serde::Deserialize)]
+    pub enum StreetlampMode {
+        Off,
+        On,
+        Flickering { chance: u32 },
+    }
+
+    #[derive(serde::Deserialize)]
+    pub enum LightingState {
+        Off,
+        SingleColour(RGB8),
+        RainbowCycle,
+        Cars {
+            default_color: RGB8,
+            min_interval: u16,
+            max_interval: u16,
+            speed_limit_kph: u32,
+        },
+    }
+
+    #[derive(serde::Deserialize)]
+    pub struct SharedState {
+        pub streetlamps_enabled: bool,
+        pub streetlamps_brightness: u8,
+        pub streetlamps_modes: [StreetlampMode; 6],
+        pub underpass_lights_state: LightingState,
+    }
+}
+
+impl From<v1::StreetlampMode> for StreetlampMode {
+    fn from(mode: v1::StreetlampMode) -> Self {
+        match mode {
+            v1::StreetlampMode::Off => StreetlampMode::Off,
+            v1::StreetlampMode::On => StreetlampMode::On,
+            v1::StreetlampMode::Flickering { chance } => StreetlampMode::Flickering { chance },
+        }
+    }
+}
+
+impl From<v1::LightingState> for LightingState {
+    fn from(state: v1::LightingState) -> Self {
+        match state {
+            v1::LightingState::Off => LightingState::Off,
+            v1::LightingState::SingleColour(colour) => LightingState::SingleColour(colour),
+            v1::LightingState::RainbowCycle => LightingState::RainbowCycle,
+            v1::LightingState::Cars {
+                default_color,
+                min_interval,
+                max_interval,
+                speed_limit_kph,
+            } => LightingState::Cars {
+                default_color,
+                min_interval,
+                max_interval,
+                speed_limit_kph,
+            },
+        }
+    }
+}
+
+impl From<v1::SharedState> for SharedState {
+    fn from(state: v1::SharedState) -> Self {
+        Self {
+            streetlamps_enabled: state.streetlamps_enabled,
+            streetlamps_brightness: state.streetlamps_brightness,
+            streetlamps_modes: state.streetlamps_modes.map(StreetlampMode::from),
+            underpass_lights_state: state.underpass_lights_state.into(),
+            underpass_transition_ms: default_transition_ms(),
+        }
+    }
+}
+
+/// Network config layout before the address, hostname and DHCP pool could be
+/// changed
+mod network_v1 {
+    #[derive(serde::Deserialize)]
+    pub struct NetworkConfig {
+        pub captive_portal: bool,
+    }
+}
+
+impl From<network_v1::NetworkConfig> for NetworkConfig {
+    fn from(config: network_v1::NetworkConfig) -> Self {
+        Self {
+            captive_portal: config.captive_portal,
+            ..Default::default()
+        }
+    }
+}
+
+const SCHEMA_MAGIC: u8 = 0xA5;
+const HEADER_LEN: usize = 2;
+
+/// Version of the layout written by this firmware
+pub const CURRENT_VERSION: u8 = 2;
+/// Version of the playlist layout written by this firmware
+pub const PLAYLIST_VERSION: u8 = 1;
+/// Version of the DMX config layout written by this firmware
+pub const DMX_CONFIG_VERSION: u8 = 1;
+/// Version of the MQTT config layout written by this firmware
+pub const MQTT_CONFIG_VERSION: u8 = 1;
+/// Version of the network config layout written by this firmware
+pub const NETWORK_CONFIG_VERSION: u8 = 2;
+/// Version of the MIDI config layout written by this firmware
+pub const MIDI_CONFIG_VERSION: u8 = 1;
+
+fn decode_payload<T: DeserializeOwned>(payload: &[u8]) -> Result<T, SerializationError> {
+    decode_from_slice::<T, _>(payload, bincode::config::standard())
+        .map_err(|_| SerializationError::InvalidData)
+        .map(|(value, _)| value)
+}
+
+/// Decode a payload written with layout `version` and migrate it to the
+/// current `SharedState`.
+fn decode_version(version: u8, payload: &[u8]) -> Result<SharedState, SerializationError> {
+    match version {
+        1 => decode_payload::<v1::SharedState>(payload).map(SharedState::from),
+        CURRENT_VERSION => decode_payload::<SharedState>(payload),
+        // Written by newer firmware that has since been downgraded
+        _ => Err(SerializationError::InvalidData),
+    }
+}
+
+fn encode_versioned<T: Serialize>(
+    value: &T,
+    version: u8,
+    buffer: &mut [u8],
+) -> Result<usize, SerializationError> {
+    if buffer.len() < HEADER_LEN {
+        return Err(SerializationError::BufferTooSmall);
+    }
+    buffer[0] = SCHEMA_MAGIC;
+    buffer[1] = version;
+
+    encode_into_slice(
+        value,
+        &mut buffer[HEADER_LEN..],
+        bincode::config::standard(),
+    )
+    .map_err(|_| SerializationError::BufferTooSmall)
+    .map(|len| HEADER_LEN + len)
+}
+
+pub fn encode(state: &SharedState, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+    encode_versioned(state, CURRENT_VERSION, buffer)
+}
+
+pub fn decode(buffer: &[u8]) -> Result<SharedState, SerializationError> {
+    match buffer {
+        [SCHEMA_MAGIC, version, payload @ ..] => decode_version(*version, payload),
+        // Unversioned state from before the schema was introduced
+        legacy => decode_version(1, legacy),
+    }
+}
+
+pub fn encode_playlist(
+    playlist: &Playlist,
+    buffer: &mut [u8],
+) -> Result<usize, SerializationError> {
+    encode_versioned(playlist, PLAYLIST_VERSION, buffer)
+}
+
+pub fn decode_playlist(buffer: &[u8]) -> Result<Playlist, SerializationError> {
+    match buffer {
+        [SCHEMA_MAGIC, PLAYLIST_VERSION, payload @ ..] => decode_payload(payload),
+        _ => Err(SerializationError::InvalidData),
+    }
+}
+
+pub fn encode_dmx_config(
+    config: &DmxConfig,
+    buffer: &mut [u8],
+) -> Result<usize, SerializationError> {
+    encode_versioned(config, DMX_CONFIG_VERSION, buffer)
+}
+
+pub fn decode_dmx_config(buffer: &[u8]) -> Result<DmxConfig, SerializationError> {
+    match buffer {
+        [SCHEMA_MAGIC, DMX_CONFIG_VERSION, payload @ ..] => decode_payload(payload),
+        _ => Err(SerializationError::InvalidData),
+    }
+}
+
+pub fn encode_mqtt_config(
+    config: &MqttConfig,
+    buffer: &mut [u8],
+) -> Result<usize, SerializationError> {
+    encode_versioned(config, MQTT_CONFIG_VERSION, buffer)
+}
+
+pub fn decode_mqtt_config(buffer: &[u8]) -> Result<MqttConfig, SerializationError> {
+    match buffer {
+        [SCHEMA_MAGIC, MQTT_CONFIG_VERSION, payload @ ..] => decode_payload(payload),
+        _ => Err(SerializationError::InvalidData),
+    }
+}
+
+pub fn encode_network_config(
+    config: &NetworkConfig,
+    buffer: &mut [u8],
+) -> Result<usize, SerializationError> {
+    encode_versioned(config, NETWORK_CONFIG_VERSION, buffer)
+}
+
+pub fn decode_network_config(buffer: &[u8]) -> Result<NetworkConfig, SerializationError> {
+    match buffer {
+        [SCHEMA_MAGIC, 1, payload @ ..] => {
+            decode_payload::<network_v1::NetworkConfig>(payload).map(NetworkConfig::from)
+        }
+        [SCHEMA_MAGIC, NETWORK_CONFIG_VERSION, payload @ ..] => decode_payload(payload),
+        _ => Err(SerializationError::InvalidData),
+    }
+}
+
+pub fn encode_midi_config(
+    config: &MidiConfig,
+    buffer: &mut [u8],
+) -> Result<usize, SerializationError> {
+    encode_versioned(config, MIDI_CONFIG_VERSION, buffer)
+}
+
+pub fn decode_midi_config(buffer: &[u8]) -> Result<MidiConfig, SerializationError> {
+    match buffer {
+        [SCHEMA_MAGIC, MIDI_CONFIG_VERSION, payload @ ..] => decode_payload(payload),
+        _ => Err(SerializationError::InvalidData),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smart_leds::RGB8;
+
+    use super::*;
+
+    /// State as firmware from before the schema wrote it: bincode
+    /// writes enum variants and integers above u8 as varints, where 251 marks
+    /// a little-endian u16 following.
+    const V1_STATE: &[u8] = &[
+        1,   // streetlamps_enabled
+        200, // streetlamps_brightness
+        1, 1, 1, 1, 0, // On, On, On, On, Off
+        2, 30, // Flickering { chance: 30 }
+        3, 40, 20, 2, // Cars { default_color
+        20, 251, 0xF4, 0x01, 100, // min_interval, max_interval, speed_limit_kph }
+    ];
+
+    fn expected_state() -> SharedState {
+        SharedState {
+            streetlamps_brightness: 200,
+            streetlamps_modes: [
+                StreetlampMode::On,
+                StreetlampMode::On,
+                StreetlampMode::On,
+                StreetlampMode::On,
+                StreetlampMode::Off,
+                StreetlampMode::Flickering { chance: 30 },
+            ],
+            ..SharedState::default()
+        }
+    }
+
+    #[test]
+    fn decodes_unversioned_v1_state() {
+        let state = decode(V1_STATE).unwrap();
+        assert!(state == expected_state());
+    }
+
+    #[test]
+    fn decodes_v1_state_with_header() {
+        let mut buffer = [SCHEMA_MAGIC, 1].to_vec();
+        buffer.extend_from_slice(V1_STATE);
+        assert!(decode(&buffer).unwrap() == expected_state());
+    }
+
+    #[test]
+    fn decodes_v2_state() {
+        let mut buffer = [SCHEMA_MAGIC, 2].to_vec();
+        buffer.extend_from_slice(V1_STATE);
+        // underpass_transition_ms
+        buffer.extend_from_slice(&[251, 0x88, 0x13]);
+        let state = decode(&buffer).unwrap();
+        assert!(
+            state
+                == SharedState {
+                    underpass_transition_ms: 5000,
+                    ..expected_state()
+                }
+        );
+    }
+
+    #[test]
+    fn decodes_v1_lighting_states() {
+        let mut buffer = V1_STATE[..9].to_vec();
+        buffer.extend_from_slice(&[1, 255, 0, 128]);
+        assert!(
+            decode(&buffer).unwrap().underpass_lights_state
+                == LightingState::SingleColour(RGB8::new(255, 0, 128))
+        );
+
+        buffer.truncate(9);
+        buffer.push(2);
+        assert!(decode(&buffer).unwrap().underpass_lights_state == LightingState::RainbowCycle);
+    }
+
+    #[test]
+    fn encodes_the_current_version() {
+        let mut buffer = [0; 64];
+        let len = encode(&expected_state(), &mut buffer).unwrap();
+        assert_eq!(buffer[..2], [SCHEMA_MAGIC, CURRENT_VERSION]);
+        assert!(decode(&buffer[..len]).unwrap() == expected_state());
+    }
+
+    #[test]
+    fn rejects_newer_and_truncated_state() {
+        let mut buffer = [SCHEMA_MAGIC, CURRENT_VERSION + 1].to_vec();
+        buffer.extend_from_slice(V1_STATE);
+        assert!(decode(&buffer).is_err());
+        assert!(decode(&V1_STATE[..V1_STATE.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn decodes_v1_network_config() {
+        let config = decode_network_config(&[SCHEMA_MAGIC, 1, 1]).unwrap();
+        assert!(config.captive_portal);
+        assert_eq!(config.address, NetworkConfig::default().address);
+    }
+}
@@ -0,0 +1,577 @@
+//! Host simulator for the diorama.
+//!
+//! Runs the real streetlamp and underpass runners against mock pins and a
+//! virtual WS2812 strip, serves the web app on localhost, listens for E1.31,
+//! Art-Net, DDP and WLED realtime packets on their usual ports, connects to an
+//! MQTT broker on localhost, serves the serial console's shell on TCP port
+//! 2323 (try `nc localhost 2323`), takes USB MIDI event packets as UDP
+//! datagrams on port 7001 and HID control reports on port 7002, and draws
+//! the lights in the terminal. Start it
+//! with `cargo sim`, optionally passing `--port <n>` and `--gpio-lamps` to
+//! drive the lamps through the on/off fallback path.
+
+use std::cell::{Cell, RefCell};
+use std::io::Write;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::rc::Rc;
+use std::time::Duration;
+
+use embedded_storage_async::nor_flash::{
+    ErrorType, MultiwriteNorFlash, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+use picoserve::make_static;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use smart_leds::RGB8;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+use crate::{
+    console::Console,
+    device_id::DeviceId,
+    dmx::{self, DmxControl, DmxReceiver},
+    hid,
+    live::LiveInputs,
+    midi::{MidiControl, MidiReceiver},
+    mqtt::{Connect, MqttClient, MqttConfig, MqttControl, MqttError},
+    network_config::NetworkConfig,
+    pins::{GpioPin, LampPin, LedStrip, PwmPin},
+    playlist::{PlaylistControl, PlaylistRunner},
+    realtime::{self, RealtimeReceiver},
+    state::{AppState, SharedState, SharedStateWatch, StateWatch},
+    storage::Storage,
+    streetlamps::StreetlampsRunner,
+    underpass_lights::{Crossfade, UnderpassLightsRunner, NUM_LEDS},
+    web, DEVICE_NAME,
+};
+
+const NUM_LAMPS: usize = 6;
+const DEFAULT_PORT: u16 = 8080;
+const FRAME_INTERVAL: Duration = Duration::from_millis(50);
+/// Stands in for the USB serial port
+const CONSOLE_PORT: u16 = 2323;
+/// Stands in for the USB MIDI port
+const MIDI_PORT: u16 = 7001;
+const HID_PORT: u16 = 7002;
+/// Stands in for the flash chip's unique ID
+const SIM_UNIQUE_ID: [u8; 8] = *b"SIMULATE";
+
+/// What the simulated hardware is currently outputting.
+struct Outputs {
+    lamps: [Cell<u16>; NUM_LAMPS],
+    strip: RefCell<[RGB8; NUM_LEDS]>,
+}
+
+/// A lamp pin that records its output level instead of driving hardware.
+struct SimLamp {
+    outputs: Rc<Outputs>,
+    index: usize,
+    duty: u16,
+    enabled: bool,
+}
+
+impl SimLamp {
+    fn new(outputs: Rc<Outputs>, index: usize) -> Self {
+        Self {
+            outputs,
+            index,
+            duty: 0,
+            enabled: true,
+        }
+    }
+
+    fn apply(&self) {
+        let duty = if self.enabled { self.duty } else { 0 };
+        self.outputs.lamps[self.index].set(duty);
+    }
+}
+
+impl GpioPin for SimLamp {
+    fn set_high(&mut self) {
+        self.outputs.lamps[self.index].set(u16::MAX);
+    }
+
+    fn set_low(&mut self) {
+        self.outputs.lamps[self.index].set(0);
+    }
+}
+
+impl PwmPin for SimLamp {
+    fn set_duty(&mut self, duty: u16) {
+        self.duty = duty;
+        self.apply();
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.apply();
+    }
+}
+
+struct VirtualStrip {
+    outputs: Rc<Outputs>,
+}
+
+impl LedStrip<NUM_LEDS> for VirtualStrip {
+    async fn write(&mut self, colours: &[RGB8; NUM_LEDS]) {
+        *self.outputs.strip.borrow_mut() = *colours;
+    }
+}
+
+/// In-memory stand-in for the RP2040's flash, so presets and state persist
+/// for as long as the simulator runs.
+pub struct RamFlash {
+    data: Vec<u8>,
+}
+
+impl RamFlash {
+    pub(crate) const SIZE: usize = 128 * 1024;
+
+    pub(crate) fn new() -> Self {
+        Self {
+            data: vec![0xFF; Self::SIZE],
+        }
+    }
+
+    fn range(&self, offset: u32, len: usize) -> Result<std::ops::Range<usize>, RamFlashError> {
+        let start = offset as usize;
+        match start.checked_add(len) {
+            Some(end) if end <= self.data.len() => Ok(start..end),
+            _ => Err(RamFlashError),
+        }
+    }
+}
+
+#[derive(Debug, defmt::Format)]
+pub struct RamFlashError;
+
+impl NorFlashError for RamFlashError {
+    fn kind(&self) -> NorFlashErrorKind {
+        NorFlashErrorKind::OutOfBounds
+    }
+}
+
+impl ErrorType for RamFlash {
+    type Error = RamFlashError;
+}
+
+impl ReadNorFlash for RamFlash {
+    const READ_SIZE: usize = 1;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let range = self.range(offset, bytes.len())?;
+        bytes.copy_from_slice(&self.data[range]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl NorFlash for RamFlash {
+    // Same as the RP2040's flash
+    const WRITE_SIZE: usize = 1;
+    const ERASE_SIZE: usize = 4096;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        let range = self.range(from, to.saturating_sub(from) as usize)?;
+        self.data[range].fill(0xFF);
+        Ok(())
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        let range = self.range(offset, bytes.len())?;
+        // Like real NOR flash, writing can only clear bits
+        for (cell, byte) in self.data[range].iter_mut().zip(bytes) {
+            *cell &= byte;
+        }
+        Ok(())
+    }
+}
+
+impl MultiwriteNorFlash for RamFlash {}
+
+/// A tokio TCP stream with the `embedded_io_async` traits the MQTT client uses.
+struct TokioStream(TcpStream);
+
+impl embedded_io_async::ErrorType for TokioStream {
+    type Error = embedded_io_async::ErrorKind;
+}
+
+impl embedded_io_async::Read for TokioStream {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.0
+            .read(buf)
+            .await
+            .map_err(|_| embedded_io_async::ErrorKind::Other)
+    }
+}
+
+impl embedded_io_async::Write for TokioStream {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.0
+            .write(buf)
+            .await
+            .map_err(|_| embedded_io_async::ErrorKind::Other)
+    }
+}
+
+struct TokioConnector;
+
+impl Connect for TokioConnector {
+    type Connection<'a> = TokioStream;
+
+    async fn connect(&mut self, broker: SocketAddrV4) -> Result<TokioStream, MqttError> {
+        match TcpStream::connect(broker).await {
+            Ok(stream) => Ok(TokioStream(stream)),
+            Err(_) => Err(MqttError::Io),
+        }
+    }
+}
+
+struct Options {
+    port: u16,
+    gpio_lamps: bool,
+}
+
+fn parse_args() -> Options {
+    let mut options = Options {
+        port: DEFAULT_PORT,
+        gpio_lamps: false,
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--port" => {
+                options.port = args
+                    .next()
+                    .and_then(|port| port.parse().ok())
+                    .expect("--port needs a port number");
+            }
+            "--gpio-lamps" => options.gpio_lamps = true,
+            _ => panic!("unknown argument: {arg}"),
+        }
+    }
+    options
+}
+
+pub fn run() {
+    let options = parse_args();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("failed to start tokio runtime");
+    tokio::task::LocalSet::new().block_on(&runtime, simulate(options));
+}
+
+async fn simulate(options: Options) {
+    let shared_state = SharedStateWatch(make_static!(
+        StateWatch,
+        StateWatch::new_with(SharedState::default())
+    ));
+    let storage = make_static!(
+        Storage,
+        Storage::new(RamFlash::new(), 0..RamFlash::SIZE as u32)
+    );
+    let playlist = make_static!(PlaylistControl, PlaylistControl::new(Default::default()));
+    let dmx = make_static!(DmxControl, DmxControl::new(Default::default()));
+    let mqtt = make_static!(
+        MqttControl,
+        MqttControl::new(MqttConfig {
+            enabled: true,
+            broker: Ipv4Addr::LOCALHOST,
+            ..Default::default()
+        })
+    );
+    let midi = make_static!(MidiControl, MidiControl::new(Default::default()));
+    // Only the hostname is used here, as the root of the MQTT topics
+    let network = make_static!(NetworkConfig, NetworkConfig::default());
+    let device = make_static!(DeviceId, DeviceId::new(SIM_UNIQUE_ID));
+    let live = make_static!(LiveInputs, LiveInputs::new());
+    let crossfade = make_static!(Crossfade, Crossfade::new());
+
+    let outputs = Rc::new(Outputs {
+        lamps: Default::default(),
+        strip: RefCell::new([RGB8::default(); NUM_LEDS]),
+    });
+
+    let lamp_pins: [LampPin<SimLamp, SimLamp>; NUM_LAMPS] = core::array::from_fn(|i| {
+        let lamp = SimLamp::new(outputs.clone(), i);
+        if options.gpio_lamps {
+            LampPin::gpio(lamp)
+        } else {
+            LampPin::Pwm(lamp)
+        }
+    });
+    let mut streetlamps_runner = StreetlampsRunner::new(
+        lamp_pins,
+        StdRng::from_entropy(),
+        shared_state.receiver().unwrap(),
+        &live.streetlamps,
+    );
+    tokio::task::spawn_local(async move { streetlamps_runner.run().await });
+
+    let underpass_lights_runner = UnderpassLightsRunner::new(
+        VirtualStrip {
+            outputs: outputs.clone(),
+        },
+        StdRng::from_entropy(),
+        shared_state.receiver().unwrap(),
+        &live.underpass,
+        crossfade,
+    );
+    tokio::task::spawn_local(underpass_lights_runner.run());
+
+    let playlist_runner = PlaylistRunner::new(
+        StdRng::from_entropy(),
+        shared_state,
+        storage,
+        playlist,
+        crossfade,
+    );
+    tokio::task::spawn_local(playlist_runner.run());
+
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, options.port))
+        .await
+        .expect("failed to bind web port");
+    println!(
+        "{} simulator: control panel at http://{}/\n",
+        DEVICE_NAME,
+        listener.local_addr().unwrap()
+    );
+    let app_state = AppState {
+        shared: shared_state,
+        storage,
+        playlist,
+        dmx,
+        mqtt,
+        midi,
+        network,
+        device,
+        live,
+    };
+    tokio::task::spawn_local(serve_web(listener, app_state));
+
+    tokio::task::spawn_local(serve_console(app_state));
+    tokio::task::spawn_local(serve_midi(MidiReceiver::new(app_state)));
+    tokio::task::spawn_local(serve_hid(app_state));
+
+    let mqtt_client = MqttClient::new(app_state, shared_state.receiver().unwrap());
+    tokio::task::spawn_local(mqtt_client.run(TokioConnector));
+
+    for port in [dmx::E131_PORT, dmx::ARTNET_PORT] {
+        let receiver = DmxReceiver::new(dmx, live);
+        tokio::task::spawn_local(serve_dmx(port, receiver, dmx.config().universe));
+    }
+    for port in [realtime::DDP_PORT, realtime::WLED_REALTIME_PORT] {
+        tokio::task::spawn_local(serve_realtime(port, RealtimeReceiver::new(live)));
+    }
+
+    draw_outputs(&outputs).await;
+}
+
+async fn serve_web(listener: TcpListener, state: AppState) {
+    let (app, config) = web::make_web_app();
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                eprintln!("accept error: {err}");
+                continue;
+            }
+        };
+
+        tokio::task::spawn_local(async move {
+            let mut http_buffer = [0; 2048];
+            if let Err(err) =
+                picoserve::serve_with_state(app, config, &mut http_buffer, stream, &state).await
+            {
+                eprintln!("web error: {err:?}");
+            }
+        });
+    }
+}
+
+/// Run a console session for each connection, without echo as `nc` and
+/// telnet show what's typed themselves.
+async fn serve_console(app: AppState) {
+    let listener = match TcpListener::bind((Ipv4Addr::LOCALHOST, CONSOLE_PORT)).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            eprintln!("no console on port {CONSOLE_PORT}: {err}");
+            return;
+        }
+    };
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let console = Console::new(TokioStream(stream), app, || true, false);
+                tokio::task::spawn_local(console.run());
+            }
+            Err(err) => eprintln!("console accept error: {err}"),
+        }
+    }
+}
+
+/// Feed datagrams arriving on `MIDI_PORT` to `receiver` as if they were USB
+/// packets, e.g. `0x09 0x90 60 100` for middle C.
+async fn serve_midi(receiver: MidiReceiver) {
+    let socket = match UdpSocket::bind((Ipv4Addr::LOCALHOST, MIDI_PORT)).await {
+        Ok(socket) => socket,
+        Err(err) => {
+            eprintln!("not listening for MIDI on port {MIDI_PORT}: {err}");
+            return;
+        }
+    };
+    let mut packet = [0; 64];
+    loop {
+        match socket.recv_from(&mut packet).await {
+            Ok((len, _)) => receiver.handle_usb_packet(&packet[..len]).await,
+            Err(err) => eprintln!("MIDI receive error: {err}"),
+        }
+    }
+}
+
+/// Answer each datagram arriving on `HID_PORT` as an output report, sending
+/// the input report back to where it came from.
+async fn serve_hid(app: AppState) {
+    let socket = match UdpSocket::bind((Ipv4Addr::LOCALHOST, HID_PORT)).await {
+        Ok(socket) => socket,
+        Err(err) => {
+            eprintln!("not listening for HID reports on port {HID_PORT}: {err}");
+            return;
+        }
+    };
+    let mut request = [0; hid::REPORT_LEN];
+    loop {
+        // A short datagram leaves the rest of the report zeroed
+        request.fill(0);
+        let peer = match socket.recv_from(&mut request).await {
+            Ok((_, peer)) => peer,
+            Err(err) => {
+                eprintln!("HID receive error: {err}");
+                continue;
+            }
+        };
+        let response = hid::handle_request(&app, &request).await;
+        if let Err(err) = socket.send_to(&response, peer).await {
+            eprintln!("HID send error: {err}");
+        }
+    }
+}
+
+/// Feed packets arriving on `port` to `receiver`. Only the E1.31 multicast
+/// group for the universe configured at startup is joined.
+async fn serve_dmx(port: u16, receiver: DmxReceiver, universe: u16) {
+    let socket = match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)).await {
+        Ok(socket) => socket,
+        Err(err) => {
+            eprintln!("not listening for DMX on port {port}: {err}");
+            return;
+        }
+    };
+    if port == dmx::E131_PORT {
+        let group = dmx::e131_multicast_group(universe);
+        if let Err(err) = socket.join_multicast_v4(group, Ipv4Addr::UNSPECIFIED) {
+            eprintln!("couldn't join E1.31 multicast group {group}: {err}");
+        }
+    }
+    let mut packet = [0; dmx::MAX_PACKET_LEN];
+    loop {
+        match socket.recv_from(&mut packet).await {
+            Ok((len, _)) => receiver.handle(port, &packet[..len]),
+            Err(err) => eprintln!("DMX receive error: {err}"),
+        }
+    }
+}
+
+/// Feed packets arriving on `port` to `receiver`.
+async fn serve_realtime(port: u16, mut receiver: RealtimeReceiver) {
+    let socket = match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)).await {
+        Ok(socket) => socket,
+        Err(err) => {
+            eprintln!("not listening for realtime pixels on port {port}: {err}");
+            return;
+        }
+    };
+    let mut packet = [0; realtime::MAX_PACKET_LEN];
+    loop {
+        match socket.recv_from(&mut packet).await {
+            Ok((len, _)) => receiver.handle(port, &packet[..len]),
+            Err(err) => eprintln!("realtime receive error: {err}"),
+        }
+    }
+}
+
+/// Map a linear LED output level onto a terminal colour channel, which is
+/// gamma encoded, so dim LEDs look roughly as dim as they would in person.
+fn perceived(level: f32) -> u8 {
+    (level.clamp(0.0, 1.0).sqrt() * 255.0) as u8
+}
+
+fn swatch(out: &mut String, colour: RGB8, glyph: &str) {
+    let channel = |c: u8| perceived(c as f32 / 255.0);
+    out.push_str(&format!(
+        "\x1b[38;2;{};{};{}m{}\x1b[0m",
+        channel(colour.r),
+        channel(colour.g),
+        channel(colour.b),
+        glyph
+    ));
+}
+
+async fn draw_outputs(outputs: &Outputs) -> ! {
+    let mut stdout = std::io::stdout();
+    let mut first = true;
+    loop {
+        let mut frame = String::new();
+        if !first {
+            // Move back up over the previous frame and redraw it in place
+            frame.push_str("\x1b[3F");
+        }
+        first = false;
+
+        frame.push_str("Streetlamps  ");
+        for lamp in &outputs.lamps {
+            let level = (lamp.get() as u32 * 255 / u16::MAX as u32) as u8;
+            swatch(&mut frame, RGB8::new(level, level, level / 2), " ● ");
+        }
+        frame.push('\n');
+
+        // The second lane runs back the other way along the underpass
+        let strip = *outputs.strip.borrow();
+        let (lane0, lane1) = strip.split_at(NUM_LEDS / 2);
+        frame.push_str("Underpass  → ");
+        for &colour in lane0 {
+            swatch(&mut frame, colour, "██ ");
+        }
+        frame.push('\n');
+        frame.push_str("           ← ");
+        for &colour in lane1.iter().rev() {
+            swatch(&mut frame, colour, "██ ");
+        }
+        frame.push('\n');
+
+        let _ = stdout.write_all(frame.as_bytes());
+        let _ = stdout.flush();
+        tokio::time::sleep(FRAME_INTERVAL).await;
+    }
+}
+
+// defmt needs a global logger and panic handler to link; the simulator prints
+// its own status instead, so log frames are dropped.
+#[defmt::global_logger]
+struct Logger;
+
+unsafe impl defmt::Logger for Logger {
+    fn acquire() {}
+    unsafe fn flush() {}
+    unsafe fn release() {}
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
+#[defmt::panic_handler]
+fn defmt_panic() -> ! {
+    panic!("defmt panic")
+}
@@ -1,10 +1,23 @@
-use bincode::serde::{decode_from_slice, encode_into_slice};
 use defmt::Format;
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex,
+    watch::{Receiver, Watch},
+};
 use sequential_storage::map::Value;
+use smart_leds::RGB8;
 
+use crate::device_id::DeviceId;
+use crate::dmx::DmxControl;
+use crate::live::LiveInputs;
+use crate::midi::MidiControl;
+use crate::mqtt::MqttControl;
+use crate::network_config::NetworkConfig;
+use crate::playlist::PlaylistControl;
+use crate::schema;
+use crate::storage::Storage;
 use crate::streetlamps::StreetlampMode;
-use crate::underpass_lights::LightingState;
+use crate::underpass_lights::{LightingState, MAX_TRANSITION_MS, TRANSITION_MS_REASON};
+use crate::validation::{Validate, ValidationError};
 
 #[derive(serde::Deserialize, serde::Serialize, Clone, Format, PartialEq)]
 pub struct SharedState {
@@ -12,28 +25,126 @@ pub struct SharedState {
     pub streetlamps_brightness: u8,
     pub streetlamps_modes: [StreetlampMode; 6],
     pub underpass_lights_state: LightingState,
+    /// How long the underpass lights take to fade into a new
+    /// `underpass_lights_state`, 0 to switch instantly
+    #[serde(default = "default_transition_ms")]
+    pub underpass_transition_ms: u16,
+}
+
+pub const fn default_transition_ms() -> u16 {
+    1000
+}
+
+impl Default for SharedState {
+    fn default() -> Self {
+        Self {
+            streetlamps_enabled: true,
+            streetlamps_brightness: 255,
+            streetlamps_modes: [StreetlampMode::On; 6],
+            underpass_lights_state: LightingState::cars(RGB8::new(40, 20, 2)),
+            underpass_transition_ms: default_transition_ms(),
+        }
+    }
 }
 
+impl Validate for SharedState {
+    fn validate(&self) -> Result<(), ValidationError> {
+        for (i, mode) in self.streetlamps_modes.iter().enumerate() {
+            mode.validate().map_err(|err| err.at(i))?;
+        }
+        if self.underpass_transition_ms > MAX_TRANSITION_MS {
+            return Err(ValidationError::new(
+                "underpass_transition_ms",
+                TRANSITION_MS_REASON.as_str(),
+            ));
+        }
+        self.underpass_lights_state.validate()
+    }
+}
+
+/// How many tasks can wait on state changes at once: the runners, the flash
+/// writer, the MQTT client and every open WebSocket each hold a receiver.
+pub const MAX_STATE_WATCHERS: usize = 10;
+
+pub type StateWatch = Watch<CriticalSectionRawMutex, SharedState, MAX_STATE_WATCHERS>;
+pub type StateReceiver =
+    Receiver<'static, CriticalSectionRawMutex, SharedState, MAX_STATE_WATCHERS>;
+
+/// The shared state, which notifies its receivers whenever it changes.
 #[derive(Clone, Copy)]
-pub struct SharedStateMutex(pub &'static Mutex<CriticalSectionRawMutex, SharedState>);
+pub struct SharedStateWatch(pub &'static StateWatch);
+
+impl SharedStateWatch {
+    pub fn get(&self) -> SharedState {
+        // The watch is always created with a value
+        self.0.try_get().unwrap_or_default()
+    }
+
+    /// Replace the state, notifying receivers if it's any different.
+    pub fn set(&self, state: SharedState) {
+        self.0.sender().send_if_modified(|current| {
+            if current.as_ref() == Some(&state) {
+                return false;
+            }
+            *current = Some(state.clone());
+            true
+        });
+    }
+
+    /// Change the state in place. Nothing awaits between reading and writing
+    /// it back, so no other task can change it in between.
+    pub fn modify<T>(&self, f: impl FnOnce(&mut SharedState) -> T) -> T {
+        let mut state = self.get();
+        let result = f(&mut state);
+        self.set(state);
+        result
+    }
+
+    /// Subscribe to changes, or `None` if `MAX_STATE_WATCHERS` are already
+    /// subscribed.
+    pub fn receiver(&self) -> Option<StateReceiver> {
+        self.0.receiver()
+    }
+}
 
+#[derive(Clone, Copy)]
 pub struct AppState {
-    pub shared: SharedStateMutex,
+    pub shared: SharedStateWatch,
+    pub storage: &'static Storage,
+    pub playlist: &'static PlaylistControl,
+    pub dmx: &'static DmxControl,
+    pub mqtt: &'static MqttControl,
+    pub midi: &'static MidiControl,
+    /// The network settings in use since boot, which may since have been
+    /// changed in flash
+    pub network: &'static NetworkConfig,
+    pub device: &'static DeviceId,
+    /// Lighting streamed in over DMX or the realtime protocols
+    pub live: &'static LiveInputs,
 }
-impl picoserve::extract::FromRef<AppState> for SharedStateMutex {
+impl picoserve::extract::FromRef<AppState> for SharedStateWatch {
     fn from_ref(state: &AppState) -> Self {
         state.shared
     }
 }
+impl picoserve::extract::FromRef<AppState> for &'static Storage {
+    fn from_ref(state: &AppState) -> Self {
+        state.storage
+    }
+}
+impl picoserve::extract::FromRef<AppState> for &'static PlaylistControl {
+    fn from_ref(state: &AppState) -> Self {
+        state.playlist
+    }
+}
 
 impl<'a> Value<'a> for SharedState {
     fn serialize_into(
         &self,
         buffer: &mut [u8],
     ) -> Result<usize, sequential_storage::map::SerializationError> {
-        // Serialise with Serde
-        encode_into_slice(self, buffer, bincode::config::standard())
-            .map_err(|_| sequential_storage::map::SerializationError::BufferTooSmall)
+        // Versioned so later firmware can migrate it, see schema.rs
+        schema::encode(self, buffer)
     }
 
     fn deserialize_from(
@@ -42,20 +153,85 @@ impl<'a> Value<'a> for SharedState {
     where
         Self: Sized,
     {
-        decode_from_slice::<Self, _>(buffer, bincode::config::standard())
-            .map_err(|_| sequential_storage::map::SerializationError::InvalidData)
-            .map(|(state, _)| state)
+        schema::decode(buffer)
     }
 }
 
-const _: () = {
-    // This check should be a bit smaller than the buffer allocated
-    // as bincode may use more space than the Rust representation
-    check_size::<SharedState, 100>();
-};
+// This check should be a bit smaller than the buffer allocated
+// as bincode may use more space than the Rust representation
+const _: () = assert!(
+    core::mem::size_of::<SharedState>() <= 100,
+    "the size of type shouldn't be so big"
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(state: &SharedState) -> ValidationError {
+        state.validate().unwrap_err()
+    }
+
+    #[test]
+    fn default_state_is_valid() {
+        assert!(SharedState::default().validate().is_ok());
+    }
+
+    #[test]
+    fn reports_the_streetlamp_index() {
+        let mut state = SharedState::default();
+        state.streetlamps_modes[3] = StreetlampMode::Flickering { chance: 101 };
+        let err = error(&state);
+        assert_eq!(err.field, "streetlamps_modes.Flickering.chance");
+        assert_eq!(err.index, Some(3));
+        assert_eq!(err.reason, "must be between 0 and 100");
+    }
+
+    #[test]
+    fn rejects_long_transitions() {
+        let mut state = SharedState {
+            underpass_transition_ms: MAX_TRANSITION_MS,
+            ..SharedState::default()
+        };
+        assert!(state.validate().is_ok());
+        state.underpass_transition_ms = MAX_TRANSITION_MS + 1;
+        let err = error(&state);
+        assert_eq!(err.field, "underpass_transition_ms");
+        assert_eq!(err.index, None);
+        assert_eq!(err.reason, "must be at most 10000");
+    }
+
+    #[test]
+    fn rejects_inverted_car_intervals() {
+        let state = SharedState {
+            underpass_lights_state: LightingState::Cars {
+                default_color: RGB8::default(),
+                min_interval: 100,
+                max_interval: 99,
+                speed_limit_kph: 100,
+            },
+            ..SharedState::default()
+        };
+        let err = error(&state);
+        assert_eq!(err.field, "underpass_lights_state.Cars.min_interval");
+        assert_eq!(err.index, None);
+    }
 
-const fn check_size<T, const N: usize>() {
-    if core::mem::size_of::<T>() > N {
-        panic!("the size of type shouldn't be so big")
+    #[test]
+    fn rejects_speed_limits_out_of_range() {
+        for speed_limit_kph in [0, 301] {
+            let state = SharedState {
+                underpass_lights_state: LightingState::Cars {
+                    default_color: RGB8::default(),
+                    min_interval: 20,
+                    max_interval: 500,
+                    speed_limit_kph,
+                },
+                ..SharedState::default()
+            };
+            let err = error(&state);
+            assert_eq!(err.field, "underpass_lights_state.Cars.speed_limit_kph");
+            assert_eq!(err.reason, "must be between 1 and 300");
+        }
     }
 }
@@ -0,0 +1,462 @@
+//! Everything the diorama keeps in the `sequential_storage` map at the end of
+//! flash: the live state under `STATE_KEY`, the playlist under `PLAYLIST_KEY`,
+//! the DMX config under `DMX_CONFIG_KEY`, the MQTT config under
+//! `MQTT_CONFIG_KEY`, the network config under `NETWORK_CONFIG_KEY`, the MIDI
+//! mapping under `MIDI_CONFIG_KEY` and named presets in the keys from
+//! `PRESET_KEY_BASE` onwards.
+
+use core::ops::Range;
+use core::str::FromStr;
+
+use defmt::Format;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use heapless::{String, Vec};
+use sequential_storage::{
+    cache::NoCache,
+    map::{fetch_item, remove_item, store_item, SerializationError, Value},
+};
+
+use crate::dmx::DmxConfig;
+use crate::midi::MidiConfig;
+use crate::mqtt::MqttConfig;
+use crate::network_config::NetworkConfig;
+use crate::playlist::Playlist;
+use crate::schema;
+use crate::state::SharedState;
+
+#[cfg(target_os = "none")]
+pub type StorageFlash = embassy_rp::flash::Flash<
+    'static,
+    embassy_rp::peripherals::FLASH,
+    embassy_rp::flash::Async,
+    { crate::FLASH_SIZE },
+>;
+#[cfg(not(target_os = "none"))]
+pub type StorageFlash = crate::simulator::RamFlash;
+
+const STATE_KEY: u8 = 1;
+const PLAYLIST_KEY: u8 = 2;
+const DMX_CONFIG_KEY: u8 = 3;
+const MQTT_CONFIG_KEY: u8 = 4;
+const NETWORK_CONFIG_KEY: u8 = 5;
+const MIDI_CONFIG_KEY: u8 = 6;
+const PRESET_KEY_BASE: u8 = 16;
+
+pub const MAX_PRESETS: usize = 8;
+pub const MAX_PRESET_NAME_LEN: usize = 24;
+
+#[derive(Format, Clone, Copy, PartialEq)]
+pub enum StorageError {
+    NotFound,
+    AlreadyExists,
+    Full,
+    /// The flash holds something `sequential_storage` can't make sense of
+    Corrupt,
+    /// An item was read but couldn't be decoded, such as one saved by newer
+    /// firmware
+    Undecodable,
+    Flash,
+}
+
+impl StorageError {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            StorageError::NotFound => "No such preset",
+            StorageError::AlreadyExists => "Preset name already in use",
+            StorageError::Full => "No room in flash",
+            StorageError::Corrupt => "Flash is corrupted",
+            StorageError::Undecodable => "Stored data is invalid",
+            StorageError::Flash => "Flash error",
+        }
+    }
+}
+
+impl<E> From<sequential_storage::Error<E>> for StorageError {
+    fn from(err: sequential_storage::Error<E>) -> Self {
+        match err {
+            sequential_storage::Error::Corrupted { .. } => StorageError::Corrupt,
+            sequential_storage::Error::SerializationError(_) => StorageError::Undecodable,
+            sequential_storage::Error::FullStorage => StorageError::Full,
+            _ => StorageError::Flash,
+        }
+    }
+}
+
+/// Name of a preset, as used in its URL.
+#[derive(serde::Serialize, serde::Deserialize, Clone, PartialEq)]
+#[serde(try_from = "String<MAX_PRESET_NAME_LEN>")]
+pub struct PresetName(String<MAX_PRESET_NAME_LEN>);
+
+impl PresetName {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl TryFrom<String<MAX_PRESET_NAME_LEN>> for PresetName {
+    type Error = &'static str;
+
+    fn try_from(name: String<MAX_PRESET_NAME_LEN>) -> Result<Self, Self::Error> {
+        if name.trim().is_empty() {
+            return Err("preset name must not be empty");
+        }
+        Ok(PresetName(name))
+    }
+}
+
+impl FromStr for PresetName {
+    type Err = &'static str;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        String::from_str(name)
+            .map_err(|_| "preset name is too long")
+            .and_then(PresetName::try_from)
+    }
+}
+
+impl Format for PresetName {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "{=str}", self.as_str())
+    }
+}
+
+/// A preset as stored in flash: `[name length, name.., state]`, where the
+/// state uses the same versioned layout as the live state.
+struct Preset {
+    name: PresetName,
+    state: SharedState,
+}
+
+impl<'a> Value<'a> for Preset {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        let name = self.name.as_str().as_bytes();
+        let header_len = 1 + name.len();
+        if buffer.len() < header_len {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        buffer[0] = name.len() as u8;
+        buffer[1..header_len].copy_from_slice(name);
+
+        schema::encode(&self.state, &mut buffer[header_len..]).map(|len| header_len + len)
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<Self, SerializationError>
+    where
+        Self: Sized,
+    {
+        let (&name_len, rest) = buffer
+            .split_first()
+            .ok_or(SerializationError::InvalidData)?;
+        let (name, state) = rest
+            .split_at_checked(name_len as usize)
+            .ok_or(SerializationError::InvalidData)?;
+        let name = core::str::from_utf8(name)
+            .ok()
+            .and_then(|name| name.parse().ok())
+            .ok_or(SerializationError::InvalidData)?;
+
+        Ok(Preset {
+            name,
+            state: schema::decode(state)?,
+        })
+    }
+}
+
+impl<'a> Value<'a> for Playlist {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        schema::encode_playlist(self, buffer)
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<Self, SerializationError>
+    where
+        Self: Sized,
+    {
+        schema::decode_playlist(buffer)
+    }
+}
+
+impl<'a> Value<'a> for DmxConfig {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        schema::encode_dmx_config(self, buffer)
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<Self, SerializationError>
+    where
+        Self: Sized,
+    {
+        schema::decode_dmx_config(buffer)
+    }
+}
+
+impl<'a> Value<'a> for MqttConfig {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        schema::encode_mqtt_config(self, buffer)
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<Self, SerializationError>
+    where
+        Self: Sized,
+    {
+        schema::decode_mqtt_config(buffer)
+    }
+}
+
+impl<'a> Value<'a> for NetworkConfig {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        schema::encode_network_config(self, buffer)
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<Self, SerializationError>
+    where
+        Self: Sized,
+    {
+        schema::decode_network_config(buffer)
+    }
+}
+
+impl<'a> Value<'a> for MidiConfig {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        schema::encode_midi_config(self, buffer)
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<Self, SerializationError>
+    where
+        Self: Sized,
+    {
+        schema::decode_midi_config(buffer)
+    }
+}
+
+struct Inner {
+    flash: StorageFlash,
+    range: Range<u32>,
+    data_buffer: [u8; 512],
+}
+
+impl Inner {
+    async fn fetch<V: for<'d> Value<'d>>(&mut self, key: u8) -> Result<Option<V>, StorageError> {
+        Ok(fetch_item::<u8, V, _>(
+            &mut self.flash,
+            self.range.clone(),
+            &mut NoCache::new(),
+            &mut self.data_buffer,
+            &key,
+        )
+        .await?)
+    }
+
+    async fn store<V: for<'d> Value<'d>>(
+        &mut self,
+        key: u8,
+        value: &V,
+    ) -> Result<(), StorageError> {
+        Ok(store_item::<u8, V, _>(
+            &mut self.flash,
+            self.range.clone(),
+            &mut NoCache::new(),
+            &mut self.data_buffer,
+            &key,
+            value,
+        )
+        .await?)
+    }
+
+    async fn remove(&mut self, key: u8) -> Result<(), StorageError> {
+        Ok(remove_item::<u8, _>(
+            &mut self.flash,
+            self.range.clone(),
+            &mut NoCache::new(),
+            &mut self.data_buffer,
+            &key,
+        )
+        .await?)
+    }
+
+    /// The preset in slot `key`. A preset that can't be decoded, such as one
+    /// saved by newer firmware, can't be loaded or named, so its slot is
+    /// treated as unused and gets overwritten by the next preset saved.
+    /// Corrupted flash is still an error, as writing over it won't help.
+    async fn fetch_preset(&mut self, key: u8) -> Result<Option<Preset>, StorageError> {
+        match self.fetch::<Preset>(key).await {
+            Err(StorageError::Undecodable) => Ok(None),
+            result => result,
+        }
+    }
+
+    /// Look through the preset slots for `name`, returning the key it's stored
+    /// under and the first unused key.
+    async fn find_preset(
+        &mut self,
+        name: &PresetName,
+    ) -> Result<(Option<u8>, Option<u8>), StorageError> {
+        let mut free = None;
+        for key in preset_keys() {
+            match self.fetch_preset(key).await? {
+                Some(preset) if preset.name == *name => return Ok((Some(key), free)),
+                Some(_) => {}
+                None => {
+                    free = free.or(Some(key));
+                }
+            }
+        }
+        Ok((None, free))
+    }
+}
+
+fn preset_keys() -> Range<u8> {
+    PRESET_KEY_BASE..PRESET_KEY_BASE + MAX_PRESETS as u8
+}
+
+/// Flash storage shared between the state writer and the web API.
+pub struct Storage {
+    inner: Mutex<CriticalSectionRawMutex, Inner>,
+}
+
+impl Storage {
+    pub fn new(flash: StorageFlash, range: Range<u32>) -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                flash,
+                range,
+                data_buffer: [0; 512],
+            }),
+        }
+    }
+
+    pub async fn load_state(&self) -> Result<Option<SharedState>, StorageError> {
+        self.inner.lock().await.fetch(STATE_KEY).await
+    }
+
+    pub async fn save_state(&self, state: &SharedState) -> Result<(), StorageError> {
+        self.inner.lock().await.store(STATE_KEY, state).await
+    }
+
+    pub async fn load_playlist(&self) -> Result<Option<Playlist>, StorageError> {
+        self.inner.lock().await.fetch(PLAYLIST_KEY).await
+    }
+
+    pub async fn save_playlist(&self, playlist: &Playlist) -> Result<(), StorageError> {
+        self.inner.lock().await.store(PLAYLIST_KEY, playlist).await
+    }
+
+    pub async fn load_dmx_config(&self) -> Result<Option<DmxConfig>, StorageError> {
+        self.inner.lock().await.fetch(DMX_CONFIG_KEY).await
+    }
+
+    pub async fn save_dmx_config(&self, config: &DmxConfig) -> Result<(), StorageError> {
+        self.inner.lock().await.store(DMX_CONFIG_KEY, config).await
+    }
+
+    pub async fn load_mqtt_config(&self) -> Result<Option<MqttConfig>, StorageError> {
+        self.inner.lock().await.fetch(MQTT_CONFIG_KEY).await
+    }
+
+    pub async fn save_mqtt_config(&self, config: &MqttConfig) -> Result<(), StorageError> {
+        self.inner.lock().await.store(MQTT_CONFIG_KEY, config).await
+    }
+
+    pub async fn load_network_config(&self) -> Result<Option<NetworkConfig>, StorageError> {
+        self.inner.lock().await.fetch(NETWORK_CONFIG_KEY).await
+    }
+
+    pub async fn save_network_config(&self, config: &NetworkConfig) -> Result<(), StorageError> {
+        self.inner
+            .lock()
+            .await
+            .store(NETWORK_CONFIG_KEY, config)
+            .await
+    }
+
+    pub async fn load_midi_config(&self) -> Result<Option<MidiConfig>, StorageError> {
+        self.inner.lock().await.fetch(MIDI_CONFIG_KEY).await
+    }
+
+    pub async fn save_midi_config(&self, config: &MidiConfig) -> Result<(), StorageError> {
+        self.inner.lock().await.store(MIDI_CONFIG_KEY, config).await
+    }
+
+    pub async fn presets(&self) -> Result<Vec<PresetName, MAX_PRESETS>, StorageError> {
+        let mut inner = self.inner.lock().await;
+        let mut names = Vec::new();
+        for key in preset_keys() {
+            if let Some(preset) = inner.fetch_preset(key).await? {
+                // There are only as many names as keys, so this can't overflow
+                let _ = names.push(preset.name);
+            }
+        }
+        Ok(names)
+    }
+
+    pub async fn load_preset(&self, name: &PresetName) -> Result<SharedState, StorageError> {
+        let mut inner = self.inner.lock().await;
+        let (Some(key), _) = inner.find_preset(name).await? else {
+            return Err(StorageError::NotFound);
+        };
+        match inner.fetch_preset(key).await? {
+            Some(preset) => Ok(preset.state),
+            None => Err(StorageError::NotFound),
+        }
+    }
+
+    /// Save `state` as the preset `name`, replacing any preset already using it.
+    pub async fn save_preset(
+        &self,
+        name: PresetName,
+        state: SharedState,
+    ) -> Result<(), StorageError> {
+        let mut inner = self.inner.lock().await;
+        let key = match inner.find_preset(&name).await? {
+            (Some(key), _) | (None, Some(key)) => key,
+            (None, None) => return Err(StorageError::Full),
+        };
+        inner.store(key, &Preset { name, state }).await
+    }
+
+    pub async fn rename_preset(
+        &self,
+        name: &PresetName,
+        new_name: PresetName,
+    ) -> Result<(), StorageError> {
+        let mut inner = self.inner.lock().await;
+        if new_name != *name && inner.find_preset(&new_name).await?.0.is_some() {
+            return Err(StorageError::AlreadyExists);
+        }
+        let (Some(key), _) = inner.find_preset(name).await? else {
+            return Err(StorageError::NotFound);
+        };
+        let Some(preset) = inner.fetch_preset(key).await? else {
+            return Err(StorageError::NotFound);
+        };
+        let preset = Preset {
+            name: new_name,
+            state: preset.state,
+        };
+        inner.store(key, &preset).await
+    }
+
+    pub async fn delete_preset(&self, name: &PresetName) -> Result<(), StorageError> {
+        let mut inner = self.inner.lock().await;
+        let (Some(key), _) = inner.find_preset(name).await? else {
+            return Err(StorageError::NotFound);
+        };
+        inner.remove(key).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Error = sequential_storage::Error<()>;
+
+    #[test]
+    fn keeps_corruption_apart_from_undecodable_data() {
+        assert!(StorageError::from(Error::Corrupted {}) == StorageError::Corrupt);
+        assert!(
+            StorageError::from(Error::SerializationError(SerializationError::InvalidData))
+                == StorageError::Undecodable
+        );
+        assert!(StorageError::from(Error::FullStorage) == StorageError::Full);
+        assert!(StorageError::from(Error::Storage { value: () }) == StorageError::Flash);
+    }
+}
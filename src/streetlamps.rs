@@ -1,8 +1,20 @@
 use defmt::Format;
-use embassy_time::{Duration, Timer};
+use embassy_futures::select::{select3, Either3};
+use embassy_time::{Duration, Instant, Timer};
 use rand::RngCore;
 
-use crate::{pins::GpioPin, state::SharedStateMutex};
+use crate::{
+    live::{LiveInput, Streamed},
+    pins::PwmPin,
+    state::{SharedState, StateReceiver},
+    validation::{Reason, Validate, ValidationError},
+};
+
+pub const NUM_STREETLAMPS: usize = 6;
+
+/// Flicker chance is a percentage of ticks the lamp is lit for
+const MAX_FLICKER_CHANCE: u32 = 100;
+static FLICKER_CHANCE_REASON: Reason = Reason::between(0, MAX_FLICKER_CHANCE);
 
 #[derive(serde::Deserialize, serde::Serialize, Format, Clone, Copy, PartialEq)]
 pub enum StreetlampMode {
@@ -11,52 +23,114 @@ pub enum StreetlampMode {
     Flickering { chance: u32 },
 }
 
+impl Validate for StreetlampMode {
+    fn validate(&self) -> Result<(), ValidationError> {
+        match self {
+            StreetlampMode::Flickering { chance } if *chance > MAX_FLICKER_CHANCE => {
+                Err(ValidationError::new(
+                    "streetlamps_modes.Flickering.chance",
+                    FLICKER_CHANCE_REASON.as_str(),
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
 pub struct StreetlampsRunner<T, R, const L: usize>
 where
-    T: GpioPin,
+    T: PwmPin,
     R: RngCore,
 {
     rng: R,
-    shared_state: SharedStateMutex,
+    state_changes: StateReceiver,
+    live: &'static LiveInput<[u8; L]>,
     lamp_pins: [T; L],
 }
 
-impl<T: GpioPin, R: RngCore, const L: usize> StreetlampsRunner<T, R, L> {
-    pub fn new(lamp_pins: [T; L], rng: R, shared_state: SharedStateMutex) -> Self {
+/// Convert a 0-255 brightness into a PWM duty, squaring it as a rough gamma
+/// correction so that low brightness values are still distinguishable.
+fn brightness_to_duty(brightness: u8) -> u16 {
+    let level = brightness as u32 * 257;
+    (level * level / u16::MAX as u32) as u16
+}
+
+impl<T: PwmPin, R: RngCore, const L: usize> StreetlampsRunner<T, R, L> {
+    pub fn new(
+        lamp_pins: [T; L],
+        rng: R,
+        state_changes: StateReceiver,
+        live: &'static LiveInput<[u8; L]>,
+    ) -> Self {
         Self {
             rng,
-            shared_state,
+            state_changes,
+            live,
             lamp_pins,
         }
     }
 
-    pub async fn run(&mut self) -> ! {
-        loop {
-            Timer::after(Duration::from_millis(100)).await;
-
-            {
-                let SharedStateMutex(mutex) = self.shared_state;
-                let state = mutex.lock().await;
-                for i in 0..L {
-                    let pin = &mut self.lamp_pins[i];
-                    let mode = if state.streetlamps_enabled {
-                        &state.streetlamps_modes[i]
+    fn update(&mut self, state: &SharedState) {
+        let full_duty = brightness_to_duty(state.streetlamps_brightness);
+        for i in 0..L {
+            let pin = &mut self.lamp_pins[i];
+            pin.set_enabled(state.streetlamps_enabled);
+
+            let duty = match &state.streetlamps_modes[i] {
+                StreetlampMode::Off => 0,
+                StreetlampMode::On => full_duty,
+                StreetlampMode::Flickering { chance } => {
+                    if self.rng.next_u32() % 100 < *chance {
+                        full_duty
                     } else {
-                        &StreetlampMode::Off
-                    };
-
-                    match mode {
-                        StreetlampMode::Off => pin.set_low(),
-                        StreetlampMode::On => pin.set_high(),
-                        StreetlampMode::Flickering { chance } => {
-                            if self.rng.next_u32() % 100 < *chance {
-                                pin.set_high();
-                            } else {
-                                pin.set_low();
-                            }
-                        }
+                        0
                     }
                 }
+            };
+            pin.set_duty(duty);
+        }
+    }
+
+    /// Show brightnesses streamed in over the network, ignoring the state.
+    fn show_live(&mut self, brightnesses: &[u8; L]) {
+        for (pin, brightness) in self.lamp_pins.iter_mut().zip(brightnesses) {
+            pin.set_enabled(true);
+            pin.set_duty(brightness_to_duty(*brightness));
+        }
+    }
+
+    pub async fn run(&mut self) -> ! {
+        let mut state = self.state_changes.get().await;
+        let mut live: Option<Streamed<[u8; L]>> = None;
+        loop {
+            let wake_at = match &live {
+                Some(stream) => {
+                    self.show_live(&stream.value);
+                    Some(stream.until)
+                }
+                None => {
+                    self.update(&state);
+                    // Only flickering lamps change by themselves, otherwise
+                    // there's nothing to do until the state does
+                    let flickering = state.streetlamps_enabled
+                        && state
+                            .streetlamps_modes
+                            .iter()
+                            .any(|mode| matches!(mode, StreetlampMode::Flickering { .. }));
+                    flickering.then(|| Instant::now() + Duration::from_millis(100))
+                }
+            };
+
+            let wake = async {
+                match wake_at {
+                    Some(at) => Timer::at(at).await,
+                    None => core::future::pending().await,
+                }
+            };
+            match select3(self.state_changes.changed(), self.live.wait(), wake).await {
+                Either3::First(changed) => state = changed,
+                Either3::Second(stream) => live = Some(stream),
+                Either3::Third(()) => live = live.filter(Streamed::is_current),
             }
         }
     }
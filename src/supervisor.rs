@@ -0,0 +1,216 @@
+//! Keeps the network services (DHCP, mDNS, captive DNS and the DMX and
+//! realtime listeners) running. A service that fails is logged and restarted
+//! after a backoff, rather than panicking and halting the board, so the lights
+//! carry on regardless. How each service is doing is kept for the API and the
+//! serial console.
+
+use core::cell::RefCell;
+
+use defmt::Format;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use heapless::String;
+
+pub const MAX_ERROR_LEN: usize = 48;
+/// Ends an error that was cut short to fit in `MAX_ERROR_LEN`
+#[cfg(any(target_os = "none", test))]
+const TRUNCATED: &str = "...";
+
+#[cfg(target_os = "none")]
+const MIN_BACKOFF: embassy_time::Duration = embassy_time::Duration::from_secs(1);
+#[cfg(target_os = "none")]
+const MAX_BACKOFF: embassy_time::Duration = embassy_time::Duration::from_secs(64);
+/// A service that ran this long before failing is restarted without waiting
+/// out the backoff built up by earlier failures
+#[cfg(target_os = "none")]
+const HEALTHY_AFTER: embassy_time::Duration = embassy_time::Duration::from_secs(60);
+
+#[derive(serde::Serialize, Format, Clone, Copy, PartialEq)]
+pub enum Service {
+    Dhcp,
+    Mdns,
+    CaptiveDns,
+    E131,
+    ArtNet,
+    Ddp,
+    WledRealtime,
+}
+
+impl Service {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Service::Dhcp => "DHCP",
+            Service::Mdns => "mDNS",
+            Service::CaptiveDns => "captive DNS",
+            Service::E131 => "E1.31",
+            Service::ArtNet => "Art-Net",
+            Service::Ddp => "DDP",
+            Service::WledRealtime => "WLED realtime",
+        }
+    }
+}
+
+#[derive(serde::Serialize, Format, Clone, Copy, PartialEq)]
+// The simulator doesn't run the services, so they're never started there
+#[cfg_attr(not(target_os = "none"), allow(dead_code))]
+pub enum ServiceState {
+    /// Never started, such as captive DNS with the portal turned off
+    Disabled,
+    Running,
+    /// Failed, and waiting out the backoff before starting again
+    Restarting,
+}
+
+#[derive(serde::Serialize, Clone)]
+pub struct ServiceStatus {
+    pub service: Service,
+    pub state: ServiceState,
+    /// How many times it has failed since boot
+    pub failures: u32,
+    /// Why it last failed
+    pub last_error: Option<String<MAX_ERROR_LEN>>,
+    /// Seconds since boot when it last failed
+    pub failed_at: Option<u64>,
+}
+
+impl ServiceStatus {
+    const fn new(service: Service) -> Self {
+        Self {
+            service,
+            state: ServiceState::Disabled,
+            failures: 0,
+            last_error: None,
+            failed_at: None,
+        }
+    }
+}
+
+pub type ServiceStatuses = [ServiceStatus; 7];
+
+static STATUSES: Mutex<CriticalSectionRawMutex, RefCell<ServiceStatuses>> =
+    Mutex::new(RefCell::new([
+        ServiceStatus::new(Service::Dhcp),
+        ServiceStatus::new(Service::Mdns),
+        ServiceStatus::new(Service::CaptiveDns),
+        ServiceStatus::new(Service::E131),
+        ServiceStatus::new(Service::ArtNet),
+        ServiceStatus::new(Service::Ddp),
+        ServiceStatus::new(Service::WledRealtime),
+    ]));
+
+#[cfg(target_os = "none")]
+fn update(service: Service, f: impl FnOnce(&mut ServiceStatus)) {
+    STATUSES.lock(|statuses| {
+        if let Some(status) = statuses
+            .borrow_mut()
+            .iter_mut()
+            .find(|status| status.service == service)
+        {
+            f(status);
+        }
+    });
+}
+
+/// Format an error to keep, cutting it short with `TRUNCATED` if it's too
+/// long.
+#[cfg(any(target_os = "none", test))]
+fn error_text(args: core::fmt::Arguments) -> String<MAX_ERROR_LEN> {
+    use core::fmt::Write;
+
+    struct Text(String<MAX_ERROR_LEN>);
+
+    impl Write for Text {
+        fn write_str(&mut self, s: &str) -> core::fmt::Result {
+            for c in s.chars() {
+                self.0.push(c).map_err(|_| core::fmt::Error)?;
+            }
+            Ok(())
+        }
+    }
+
+    let mut text = Text(String::new());
+    if text.write_fmt(args).is_err() {
+        while text.0.len() > MAX_ERROR_LEN - TRUNCATED.len() {
+            text.0.pop();
+        }
+        let _ = text.0.push_str(TRUNCATED);
+    }
+    text.0
+}
+
+/// A copy of every service's status.
+pub fn statuses() -> ServiceStatuses {
+    STATUSES.lock(|statuses| statuses.borrow().clone())
+}
+
+/// Run a service, starting it again whenever it stops. Each failure in a row
+/// doubles the wait before the next start.
+#[cfg(target_os = "none")]
+pub async fn supervise<F, E>(service: Service, mut run: impl FnMut() -> F) -> !
+where
+    F: core::future::Future<Output = Result<(), E>>,
+    E: core::fmt::Debug,
+{
+    use defmt::info;
+    use embassy_time::{Instant, Timer};
+
+    use crate::event_log;
+
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        update(service, |status| status.state = ServiceState::Running);
+        let started = Instant::now();
+        let result = run().await;
+
+        // The servers only return on errors, but a service that stops is as
+        // good as failed
+        let error = match result {
+            Ok(()) => error_text(format_args!("stopped")),
+            Err(err) => error_text(format_args!("{:?}", err)),
+        };
+        if started.elapsed() >= HEALTHY_AFTER {
+            backoff = MIN_BACKOFF;
+        }
+        info!(
+            "{} failed ({}), restarting in {} s",
+            service.name(),
+            error.as_str(),
+            backoff.as_secs()
+        );
+        event_log::record(format_args!(
+            "{} failed ({}), restarting in {} s",
+            service.name(),
+            error,
+            backoff.as_secs()
+        ));
+        update(service, |status| {
+            status.state = ServiceState::Restarting;
+            status.failures += 1;
+            status.last_error = Some(error);
+            status.failed_at = Some(Instant::now().as_secs());
+        });
+
+        Timer::after(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_short_errors_whole() {
+        assert_eq!(
+            error_text(format_args!("{:?}", "timed out")),
+            "\"timed out\""
+        );
+    }
+
+    #[test]
+    fn marks_long_errors_as_cut_short() {
+        let text = error_text(format_args!("{}", "é".repeat(MAX_ERROR_LEN)));
+        assert!(text.ends_with(TRUNCATED));
+        assert!(text.len() <= MAX_ERROR_LEN);
+        assert!(text.len() > MAX_ERROR_LEN - TRUNCATED.len() - 2);
+    }
+}
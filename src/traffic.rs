@@ -0,0 +1,313 @@
+use rand::RngCore;
+use smart_leds::RGB8;
+
+use crate::underpass_lights::{LED_POSITIONS, NUM_LANES, NUM_LEDS, NUM_LEDS_PER_LANE};
+
+const MAX_CARS: usize = 10;
+const MAX_CAR_DISTANCE: i32 = 30000;
+
+/// Distance between a car's headlights and its taillights
+const CAR_LENGTH: i32 = 2000;
+/// Brightness of a car's lights on an LED directly underneath them
+const CAR_LIGHT_POWER: i32 = 80;
+
+// 1km/h is 0.27777m/s, or 277.77mm/s
+// 1km/h real scale = 4.34mm/s at 1/64 scale
+
+/// Parameters of the `LightingState::Cars` effect that drive the simulation.
+#[derive(Clone, Copy)]
+pub struct TrafficConfig {
+    /// Minimum ticks between cars entering the underpass
+    pub min_interval: u16,
+    /// Maximum ticks between cars entering the underpass
+    pub max_interval: u16,
+    pub speed_limit_kph: u32,
+}
+
+#[derive(Clone, Copy, defmt::Format)]
+struct CarState {
+    position: i32,
+    speed: i32,
+    lane: u8,
+}
+
+/// Cars driving through the underpass, advanced one tick at a time.
+pub struct TrafficSim<R: RngCore> {
+    rng: R,
+    cars: [Option<CarState>; MAX_CARS],
+    next_car_spawn_delay: u16,
+}
+
+fn add_rgb_saturating(a: &mut RGB8, b: RGB8) {
+    a.r = a.r.saturating_add(b.r);
+    a.g = a.g.saturating_add(b.g);
+    a.b = a.b.saturating_add(b.b);
+}
+
+fn clamp(val: i32, min: i32, max: i32) -> i32 {
+    if val < min {
+        min
+    } else if val > max {
+        max
+    } else {
+        val
+    }
+}
+
+/// Light cast on an LED by a car light `dist` away: `(direct, falloff)`, where
+/// `falloff` is used once the light has passed the LED and drops off faster.
+fn light_power(dist: i32) -> (u8, u8) {
+    let power =
+        CAR_LIGHT_POWER * clamp(MAX_CAR_DISTANCE - dist, 0, MAX_CAR_DISTANCE) / MAX_CAR_DISTANCE;
+    let falloff_power = CAR_LIGHT_POWER * clamp(MAX_CAR_DISTANCE - dist * 4, 0, MAX_CAR_DISTANCE)
+        / MAX_CAR_DISTANCE;
+    (power as u8, falloff_power as u8)
+}
+
+/// Light from a single car on the LED at `led_pos` in its lane.
+fn car_light_at(car: &CarState, led_pos: i32) -> RGB8 {
+    let mut light = RGB8::default();
+    let car_front = car.position;
+    let car_back = car.position - CAR_LENGTH;
+
+    // Front (white)
+    if car_front > led_pos - MAX_CAR_DISTANCE && car_front < led_pos + MAX_CAR_DISTANCE {
+        let dist = (car_front - led_pos).abs().min(MAX_CAR_DISTANCE);
+        let (power, falloff_power) = light_power(dist);
+        let p = if car_front > led_pos {
+            power
+        } else {
+            falloff_power
+        };
+        add_rgb_saturating(&mut light, RGB8::new(p, p, p / 3));
+    }
+
+    // Back (red)
+    if car_back > led_pos - MAX_CAR_DISTANCE && car_back < led_pos + MAX_CAR_DISTANCE {
+        let dist = (car_back - led_pos).abs().min(MAX_CAR_DISTANCE);
+        let (power, falloff_power) = light_power(dist);
+        let p = if car_back < led_pos {
+            falloff_power
+        } else {
+            power
+        };
+        add_rgb_saturating(&mut light, RGB8::new(p, 0, 0));
+    }
+
+    light
+}
+
+impl<R: RngCore> TrafficSim<R> {
+    pub fn new(rng: R) -> Self {
+        Self {
+            rng,
+            cars: [None; MAX_CARS],
+            next_car_spawn_delay: 10,
+        }
+    }
+
+    /// Advance the simulation by `ticks` ticks.
+    pub fn step(&mut self, config: &TrafficConfig, ticks: u32) {
+        for _ in 0..ticks {
+            self.tick(config);
+        }
+    }
+
+    /// Move every car and spawn a new one when it's due. A car that has left
+    /// the underpass is only removed on the tick after, so it's still drawn
+    /// where it ended up.
+    fn tick(&mut self, config: &TrafficConfig) {
+        let despawn_position = LED_POSITIONS[NUM_LEDS_PER_LANE - 1] as i32 + MAX_CAR_DISTANCE;
+        for car_state in self.cars.iter_mut() {
+            if car_state.is_some_and(|car| car.position > despawn_position) {
+                *car_state = None;
+            }
+            if let Some(car) = car_state {
+                car.position += car.speed;
+            }
+        }
+
+        if self.next_car_spawn_delay == 0 {
+            self.spawn_car(config);
+
+            // Set spawn delay to a random number between min_interval and max_interval
+            let range = config.max_interval.saturating_sub(config.min_interval) as u32 + 1;
+            self.next_car_spawn_delay = config.min_interval + (self.rng.next_u32() % range) as u16;
+        }
+
+        self.next_car_spawn_delay = self.next_car_spawn_delay.saturating_sub(1);
+    }
+
+    fn spawn_car(&mut self, config: &TrafficConfig) {
+        if let Some(slot) = self.cars.iter_mut().find(|car| car.is_none()) {
+            // Add a random amount to speed_limit_kph from 0 to 10
+            let extra_kph = self.rng.next_u32() % 11;
+            let car_kph = config.speed_limit_kph + extra_kph;
+            let mm_per_tick = (434 * car_kph) / 100;
+            *slot = Some(CarState {
+                position: -MAX_CAR_DISTANCE,
+                speed: mm_per_tick as i32,
+                lane: (self.rng.next_u32() % NUM_LANES as u32) as u8,
+            });
+        }
+    }
+
+    /// Draw the cars' lights on top of the underpass' `default_color`.
+    pub fn render(&self, default_color: RGB8) -> [RGB8; NUM_LEDS] {
+        let mut data = [default_color; NUM_LEDS];
+        for car in self.cars.iter().flatten() {
+            // Only affect LEDs in the car's lane
+            let led_offset = car.lane as usize * NUM_LEDS_PER_LANE;
+            for (i, &led_pos) in LED_POSITIONS.iter().enumerate() {
+                add_rgb_saturating(&mut data[led_offset + i], car_light_at(car, led_pos as i32));
+            }
+        }
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::mock::StepRng;
+
+    use super::*;
+
+    const CONFIG: TrafficConfig = TrafficConfig {
+        min_interval: 3,
+        max_interval: 7,
+        speed_limit_kph: 50,
+    };
+
+    const DESPAWN_POSITION: i32 = LED_POSITIONS[NUM_LEDS_PER_LANE - 1] as i32 + MAX_CAR_DISTANCE;
+
+    fn car_count<R: RngCore>(sim: &TrafficSim<R>) -> usize {
+        sim.cars.iter().flatten().count()
+    }
+
+    /// A simulation with one car in it that won't spawn any more.
+    fn with_car(car: CarState) -> TrafficSim<StepRng> {
+        let mut sim = TrafficSim::new(StepRng::new(0, 0));
+        sim.cars[0] = Some(car);
+        sim.next_car_spawn_delay = u16::MAX;
+        sim
+    }
+
+    #[test]
+    fn first_car_spawns_after_ten_ticks() {
+        let mut sim = TrafficSim::new(StepRng::new(0, 0));
+        sim.step(&CONFIG, 10);
+        assert_eq!(car_count(&sim), 0);
+        sim.step(&CONFIG, 1);
+        assert_eq!(car_count(&sim), 1);
+        let car = sim.cars[0].unwrap();
+        assert_eq!(car.position, -MAX_CAR_DISTANCE);
+        // 50 km/h at 1/64 scale
+        assert_eq!(car.speed, 217);
+    }
+
+    #[test]
+    fn cars_spawn_between_min_and_max_interval() {
+        let mut sim = TrafficSim::new(StepRng::new(0, 1));
+        let mut gaps = [0; MAX_CARS - 1];
+        let mut last_spawn = None;
+        let mut spawned = 0;
+        for tick in 0u16.. {
+            let before = car_count(&sim);
+            sim.step(&CONFIG, 1);
+            if car_count(&sim) > before {
+                if let Some(last_spawn) = last_spawn {
+                    gaps[spawned - 1] = tick - last_spawn;
+                }
+                last_spawn = Some(tick);
+                spawned += 1;
+                if spawned == MAX_CARS {
+                    break;
+                }
+            }
+        }
+        for gap in gaps {
+            assert!((CONFIG.min_interval..=CONFIG.max_interval).contains(&gap));
+        }
+        // The generator counts up, so the intervals shouldn't all be the same
+        assert!(gaps.iter().any(|&gap| gap != gaps[0]));
+    }
+
+    #[test]
+    fn cars_wait_for_a_free_slot() {
+        let config = TrafficConfig {
+            min_interval: 0,
+            max_interval: 0,
+            speed_limit_kph: 1,
+        };
+        let mut sim = TrafficSim::new(StepRng::new(0, 0));
+        sim.step(&config, 100);
+        assert_eq!(car_count(&sim), MAX_CARS);
+    }
+
+    #[test]
+    fn cars_are_spawned_in_a_random_lane() {
+        // Each car takes three numbers, for its speed, lane and the interval
+        // after it, so the lanes come from 1, 4, 7...
+        let mut sim = TrafficSim::new(StepRng::new(0, 1));
+        sim.step(&CONFIG, 100);
+        let lanes: heapless::Vec<u8, MAX_CARS> =
+            sim.cars.iter().flatten().map(|car| car.lane).collect();
+        assert!(lanes.len() > 2);
+        for (i, lane) in lanes.into_iter().enumerate() {
+            assert_eq!(lane as u32, (3 * i as u32 + 1) % NUM_LANES as u32);
+        }
+    }
+
+    #[test]
+    fn cars_only_light_their_own_lane() {
+        let sim = with_car(CarState {
+            position: LED_POSITIONS[3] as i32,
+            speed: 0,
+            lane: 1,
+        });
+        let data = sim.render(RGB8::default());
+        assert!(data[..NUM_LEDS_PER_LANE]
+            .iter()
+            .all(|&led| led == RGB8::default()));
+        assert_ne!(data[NUM_LEDS_PER_LANE + 3], RGB8::default());
+    }
+
+    #[test]
+    fn cars_despawn_after_leaving_the_underpass() {
+        let mut sim = with_car(CarState {
+            position: DESPAWN_POSITION - 100,
+            speed: 100,
+            lane: 0,
+        });
+        sim.step(&CONFIG, 1);
+        assert_eq!(sim.cars[0].unwrap().position, DESPAWN_POSITION);
+        // Past the end, but still drawn for this tick
+        sim.step(&CONFIG, 1);
+        assert_eq!(sim.cars[0].unwrap().position, DESPAWN_POSITION + 100);
+        sim.step(&CONFIG, 1);
+        assert_eq!(car_count(&sim), 0);
+    }
+
+    #[test]
+    fn headlights_and_taillights() {
+        // Front at the second LED, back 2000 before it
+        let sim = with_car(CarState {
+            position: LED_POSITIONS[1] as i32,
+            speed: 0,
+            lane: 0,
+        });
+        let data = sim.render(RGB8::new(1, 2, 3));
+
+        // At the second LED, the headlights have just reached it and the
+        // taillights are still coming
+        assert_eq!(data[1], RGB8::new(1 + 80 + 58, 2 + 80, 3 + 26));
+        // The first LED is 5500 behind the headlights and 3500 behind the
+        // taillights
+        assert_eq!(data[0], RGB8::new(1 + 65 + 70, 2 + 65, 3 + 21));
+        // The third LED is 4000 and 6000 ahead, where the lights fall off
+        // faster
+        assert_eq!(data[2], RGB8::new(1 + 37 + 16, 2 + 37, 3 + 12));
+        // and the last is too far away to be lit
+        assert_eq!(data[NUM_LEDS_PER_LANE - 1], RGB8::new(1, 2, 3));
+    }
+}
@@ -1,33 +1,37 @@
-use core::cmp::max;
-
-use defmt::{debug, info, Format};
-use embassy_rp::dma::Channel;
-use embassy_rp::gpio::Pin;
-use embassy_rp::pio::PioPin;
-use embassy_rp::Peripheral;
-use embassy_time::{Duration, Ticker};
+use defmt::Format;
+use embassy_futures::select::{select, Either};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant, Ticker};
 use rand::RngCore;
 
-use embassy_rp::peripherals::{DMA_CH0, PIO0};
-use embassy_rp::pio::Pio;
-use embassy_rp::pio_programs::ws2812::{PioWs2812, PioWs2812Program};
 use smart_leds::RGB8;
 
-use crate::state::SharedStateMutex;
+use crate::live::{LiveInput, Streamed};
+use crate::pins::LedStrip;
+use crate::state::StateReceiver;
+use crate::traffic::{TrafficConfig, TrafficSim};
+use crate::validation::{Reason, Validate, ValidationError};
 
-const NUM_LEDS_PER_LANE: usize = 8;
-const NUM_LANES: usize = 2;
-const NUM_LEDS: usize = NUM_LANES * NUM_LEDS_PER_LANE;
-const LED_POSITIONS: [u16; NUM_LEDS_PER_LANE] = [
+pub const NUM_LEDS_PER_LANE: usize = 8;
+pub const NUM_LANES: usize = 2;
+pub const NUM_LEDS: usize = NUM_LANES * NUM_LEDS_PER_LANE;
+pub const LED_POSITIONS: [u16; NUM_LEDS_PER_LANE] = [
     // Left and right lanes are the same in reverse
     2000, 7500, 11500, 17000, 21300, 26800, 30800, 36300,
 ];
 
-const MAX_CARS: usize = 10;
-const MAX_CAR_DISTANCE: i32 = 30000;
+/// Longest fade between two `LightingState`s
+pub const MAX_TRANSITION_MS: u16 = 10_000;
+pub static TRANSITION_MS_REASON: Reason = Reason::at_most(MAX_TRANSITION_MS as u32);
+
+/// How long to fade into the next `LightingState`, in place of
+/// `SharedState::underpass_transition_ms`. Used for the playlist's crossfades,
+/// which shouldn't change the stored transition.
+pub type Crossfade = Signal<CriticalSectionRawMutex, u16>;
 
-// 1km/h is 0.27777m/s, or 277.77mm/s
-// 1km/h real scale = 4.34mm/s at 1/64 scale
+pub const MIN_SPEED_LIMIT_KPH: u32 = 1;
+pub const MAX_SPEED_LIMIT_KPH: u32 = 300;
+static SPEED_LIMIT_REASON: Reason = Reason::between(MIN_SPEED_LIMIT_KPH, MAX_SPEED_LIMIT_KPH);
 
 // Positions of LEDs: 20mm, 75mm, 115mm, 170mm, 213mm, 268mm, 308mm, 363mm
 
@@ -44,242 +48,239 @@ pub enum LightingState {
     },
 }
 
-pub struct UnderpassLightsRunner<R, T>
-where
-    R: RngCore,
-    T: PioPin,
-{
-    pio: Pio<'static, PIO0>,
-    data_pin: T,
-    dma: DMA_CH0,
-    rng: R,
-    shared_state: SharedStateMutex,
-}
+impl LightingState {
+    /// Traffic at a comfortable density and speed
+    pub const fn cars(default_color: RGB8) -> Self {
+        LightingState::Cars {
+            default_color,
+            min_interval: 20,
+            max_interval: 500,
+            speed_limit_kph: 100,
+        }
+    }
 
-#[derive(Clone, Copy, defmt::Format)]
-struct CarState {
-    position: i32,
-    speed: i32,
-    lane: u8,
-}
+    /// The colour the effect is drawn in, if it has one
+    pub const fn colour(&self) -> Option<RGB8> {
+        match *self {
+            LightingState::SingleColour(colour) => Some(colour),
+            LightingState::Cars { default_color, .. } => Some(default_color),
+            LightingState::Off | LightingState::RainbowCycle => None,
+        }
+    }
 
-fn add_rgb_saturating(a: &mut RGB8, b: RGB8) {
-    a.r = a.r.saturating_add(b.r);
-    a.g = a.g.saturating_add(b.g);
-    a.b = a.b.saturating_add(b.b);
+    fn traffic_config(&self) -> Option<TrafficConfig> {
+        match *self {
+            LightingState::Cars {
+                min_interval,
+                max_interval,
+                speed_limit_kph,
+                ..
+            } => Some(TrafficConfig {
+                min_interval,
+                max_interval,
+                speed_limit_kph,
+            }),
+            _ => None,
+        }
+    }
 }
 
-fn clamp(val: i32, min: i32, max: i32) -> i32 {
-    if val < min {
-        min
-    } else if val > max {
-        max
-    } else {
-        val
+impl Validate for LightingState {
+    fn validate(&self) -> Result<(), ValidationError> {
+        if let LightingState::Cars {
+            min_interval,
+            max_interval,
+            speed_limit_kph,
+            ..
+        } = self
+        {
+            if min_interval > max_interval {
+                return Err(ValidationError::new(
+                    "underpass_lights_state.Cars.min_interval",
+                    "must not be greater than max_interval",
+                ));
+            }
+            if !(MIN_SPEED_LIMIT_KPH..=MAX_SPEED_LIMIT_KPH).contains(speed_limit_kph) {
+                return Err(ValidationError::new(
+                    "underpass_lights_state.Cars.speed_limit_kph",
+                    SPEED_LIMIT_REASON.as_str(),
+                ));
+            }
+        }
+        Ok(())
     }
 }
 
-impl<R: RngCore, T: PioPin> UnderpassLightsRunner<R, T> {
+pub struct UnderpassLightsRunner<R, S>
+where
+    R: RngCore,
+    S: LedStrip<NUM_LEDS>,
+{
+    strip: S,
+    traffic: TrafficSim<R>,
+    state_changes: StateReceiver,
+    live: &'static LiveInput<[RGB8; NUM_LEDS]>,
+    crossfade: &'static Crossfade,
+}
+
+impl<R: RngCore, S: LedStrip<NUM_LEDS>> UnderpassLightsRunner<R, S> {
     pub fn new(
-        pio: Pio<'static, PIO0>,
-        data_pin: T,
-        dma: DMA_CH0,
+        strip: S,
         rng: R,
-        shared_state: SharedStateMutex,
+        state_changes: StateReceiver,
+        live: &'static LiveInput<[RGB8; NUM_LEDS]>,
+        crossfade: &'static Crossfade,
     ) -> Self {
         Self {
-            pio,
-            data_pin,
-            dma,
-            rng,
-            shared_state,
+            strip,
+            traffic: TrafficSim::new(rng),
+            state_changes,
+            live,
+            crossfade,
         }
     }
 
-    pub async fn run(mut self) -> ! {
-        let Pio {
-            mut common, sm0, ..
-        } = self.pio;
+    /// Render one frame of `state`. `cycle` advances by one every tick.
+    fn render(&self, state: &LightingState, cycle: u16) -> [RGB8; NUM_LEDS] {
+        match *state {
+            LightingState::Off => [RGB8::default(); NUM_LEDS],
+            LightingState::SingleColour(colour) => [colour; NUM_LEDS],
+            LightingState::RainbowCycle => core::array::from_fn(|i| {
+                wheel(((((i * 256) as u16 / NUM_LEDS as u16).wrapping_add(cycle)) & 255) as u8)
+            }),
+            LightingState::Cars { default_color, .. } => self.traffic.render(default_color),
+        }
+    }
 
+    pub async fn run(mut self) -> ! {
         let mut data = [RGB8::default(); NUM_LEDS];
 
         let mut cycle: u16 = 0;
 
-        let mut cars: [Option<CarState>; MAX_CARS] = [None; MAX_CARS];
-        let mut car_light: [RGB8; NUM_LEDS];
-
-        let program = PioWs2812Program::new(&mut common);
-        let mut ws2812 = PioWs2812::new(&mut common, sm0, self.dma, self.data_pin, &program);
         let mut ticker = Ticker::every(Duration::from_millis(10));
-        let mut dirty = true;
         let mut last_state = LightingState::Off;
-        let mut next_car_spawn_delay: u16 = 10;
+        let mut transition: Option<Transition> = None;
+        let mut state = self.state_changes.get().await;
+        let mut live: Option<Streamed<[RGB8; NUM_LEDS]>> = None;
         loop {
-            let SharedStateMutex(mutex) = self.shared_state;
-            {
-                let state = mutex.lock().await;
-
-                match state.underpass_lights_state {
-                    LightingState::Off => {
-                        if last_state != state.underpass_lights_state {
-                            for i in 0..NUM_LEDS {
-                                data[i] = RGB8::default();
-                            }
-                            dirty = true;
-                        }
-                    }
-                    LightingState::SingleColour(colour) => {
-                        if last_state != state.underpass_lights_state {
-                            for i in 0..NUM_LEDS {
-                                data[i] = colour;
-                            }
-                            dirty = true;
-                        }
-                    }
-                    LightingState::RainbowCycle => {
-                        for i in 0..NUM_LEDS {
-                            data[i] = wheel(
-                                ((((i * 256) as u16 / NUM_LEDS as u16).wrapping_add(cycle)) & 255)
-                                    as u8,
-                            );
-                        }
-                        dirty = true;
-                    }
-                    LightingState::Cars {
-                        default_color,
-                        min_interval,
-                        max_interval,
-                        speed_limit_kph,
-                    } => {
-                        car_light = [RGB8::default(); NUM_LEDS];
-
-                        for car_state in cars.iter_mut() {
-                            if let Some(car) = car_state {
-                                car.position += car.speed as i32;
-
-                                // Only affect LEDs in the car's lane
-                                let lane = car.lane as usize;
-                                let led_offset = lane * NUM_LEDS_PER_LANE;
-                                for i in 0..NUM_LEDS_PER_LANE {
-                                    let led_pos = LED_POSITIONS[i] as i32;
-                                    let led_idx = led_offset + i;
-                                    // Represent car as two points: front and back (2000 units apart)
-                                    let car_front = car.position;
-                                    let car_back = car.position - 2000;
-
-                                    // Front (white)
-                                    if car_front > led_pos - MAX_CAR_DISTANCE
-                                        && car_front < led_pos + MAX_CAR_DISTANCE
-                                    {
-                                        let dist =
-                                            (car_front - led_pos).abs().min(MAX_CAR_DISTANCE);
-                                        let power: u8 = (80
-                                            * clamp(MAX_CAR_DISTANCE - dist, 0, MAX_CAR_DISTANCE)
-                                            / MAX_CAR_DISTANCE)
-                                            as u8;
-                                        let falloff_power: u8 =
-                                            (80 * clamp(
-                                                MAX_CAR_DISTANCE - dist * 4,
-                                                0,
-                                                MAX_CAR_DISTANCE,
-                                            ) / MAX_CAR_DISTANCE)
-                                                as u8;
-                                        //info!("car_front: {} led_pos: {} dist: {} power: {}", car_front, led_pos, dist, power);
-                                        if car_front > led_pos {
-                                            let c = RGB8::new(power, power, power / 3);
-                                            add_rgb_saturating(&mut car_light[led_idx], c);
-                                        } else {
-                                            let c = RGB8::new(
-                                                falloff_power,
-                                                falloff_power,
-                                                falloff_power / 3,
-                                            );
-                                            add_rgb_saturating(&mut car_light[led_idx], c);
-                                        }
-                                    }
-
-                                    // Back (red)
-                                    if car_back > led_pos - MAX_CAR_DISTANCE
-                                        && car_back < led_pos + MAX_CAR_DISTANCE
-                                    {
-                                        let dist = (car_back - led_pos).abs().min(MAX_CAR_DISTANCE);
-                                        let power: u8 = (80
-                                            * clamp(MAX_CAR_DISTANCE - dist, 0, MAX_CAR_DISTANCE)
-                                            / MAX_CAR_DISTANCE)
-                                            as u8;
-                                        let falloff_power: u8 =
-                                            (80 * clamp(
-                                                MAX_CAR_DISTANCE - dist * 4,
-                                                0,
-                                                MAX_CAR_DISTANCE,
-                                            ) / MAX_CAR_DISTANCE)
-                                                as u8;
-                                        if car_back < led_pos {
-                                            let c = RGB8::new(falloff_power, 0, 0);
-                                            add_rgb_saturating(&mut car_light[led_idx], c);
-                                        } else {
-                                            let c = RGB8::new(power, 0, 0);
-                                            add_rgb_saturating(&mut car_light[led_idx], c);
-                                        }
-                                    }
-                                }
-
-                                if car.position
-                                    > LED_POSITIONS[NUM_LEDS_PER_LANE - 1] as i32 + MAX_CAR_DISTANCE
-                                {
-                                    *car_state = None;
-                                }
-                            }
-                        }
+            if let Some(stream) = self.live.try_take() {
+                live = Some(stream);
+            }
+            let (lighting_state, transition_ms) =
+                (state.underpass_lights_state, state.underpass_transition_ms);
+
+            if lighting_state != last_state {
+                // If a transition is interrupted, fade on from wherever it got to
+                let from = match transition {
+                    Some(_) => Outgoing::Frame(data),
+                    None => Outgoing::Effect(last_state),
+                };
+                let duration_ms = self.crossfade.try_take().unwrap_or(transition_ms);
+                transition = Transition::new(from, duration_ms);
+                last_state = lighting_state;
+            }
 
-                        for i in 0..NUM_LEDS {
-                            data[i] = default_color;
-                            add_rgb_saturating(&mut data[i], car_light[i]);
-                        }
-                        dirty = true;
+            // Once a stream stops, fade back from the last frame it sent
+            if let Some(stream) = live.take_if(|stream| !stream.is_current()) {
+                transition = Transition::new(Outgoing::Frame(stream.value), transition_ms);
+            }
 
-                        if next_car_spawn_delay == 0 {
-                            //debug!("Car: {}", cars[0]);
-                            for i in 0..MAX_CARS {
-                                if cars[i].is_none() {
-                                    // Add a random amount to speed_limit_kph from 0 to 10
-                                    let extra_kph = (self.rng.next_u32() % 11) as u32;
-                                    let car_kph = speed_limit_kph + extra_kph;
-                                    let mm_per_tick = (434 * (car_kph)) / 100;
-                                    let speed = mm_per_tick as i32;
-                                    cars[i] = Some(CarState {
-                                        position: -MAX_CAR_DISTANCE,
-                                        speed,
-                                        lane: (self.rng.next_u32() % NUM_LANES as u32) as u8,
-                                    });
-                                    break;
-                                }
-                            }
+            // Keep the traffic moving while it's fading in or out
+            let traffic = lighting_state.traffic_config().or(match &transition {
+                Some(Transition {
+                    from: Outgoing::Effect(from),
+                    ..
+                }) => from.traffic_config(),
+                _ => None,
+            });
+            if let Some(config) = traffic {
+                self.traffic.step(&config, 1);
+            }
 
-                            // Set spawn delay to a random number between min_interval and max_interval
-                            let interval = min_interval
-                                + (self.rng.next_u32() % ((max_interval - min_interval + 1) as u32))
-                                    as u16;
-                            next_car_spawn_delay = interval;
+            data = match &live {
+                Some(stream) => stream.value,
+                None => self.render(&lighting_state, cycle),
+            };
+            if let (None, Some(current)) = (&live, &transition) {
+                match current.progress() {
+                    Some(progress) => {
+                        let from = match &current.from {
+                            Outgoing::Effect(state) => self.render(state, cycle),
+                            Outgoing::Frame(frame) => *frame,
+                        };
+                        for (led, from) in data.iter_mut().zip(from) {
+                            *led = blend(from, *led, progress);
                         }
-
-                        next_car_spawn_delay = next_car_spawn_delay.saturating_sub(1);
                     }
+                    None => transition = None,
                 }
-
-                last_state = state.underpass_lights_state;
             }
 
             cycle = cycle.wrapping_add(1);
-            if dirty {
-                ws2812.write(&data).await;
+            self.strip.write(&data).await;
+
+            // A still frame stays as it is until the state changes
+            let animated = live.is_some()
+                || transition.is_some()
+                || matches!(
+                    lighting_state,
+                    LightingState::RainbowCycle | LightingState::Cars { .. }
+                );
+            if !animated {
+                match select(self.state_changes.changed(), self.live.wait()).await {
+                    Either::First(changed) => state = changed,
+                    Either::Second(stream) => live = Some(stream),
+                }
+                ticker.reset();
+                continue;
             }
-
             ticker.next().await;
+            if let Some(changed) = self.state_changes.try_changed() {
+                state = changed;
+            }
         }
     }
 }
 
+/// What the strip is fading out from.
+enum Outgoing {
+    Effect(LightingState),
+    /// A frame captured part way through an interrupted transition
+    Frame([RGB8; NUM_LEDS]),
+}
+
+struct Transition {
+    from: Outgoing,
+    start: Instant,
+    duration: Duration,
+}
+
+impl Transition {
+    fn new(from: Outgoing, duration_ms: u16) -> Option<Self> {
+        (duration_ms > 0).then(|| Transition {
+            from,
+            start: Instant::now(),
+            duration: Duration::from_millis(duration_ms as u64),
+        })
+    }
+
+    /// How far through the transition we are, out of 256, or `None` once it
+    /// has finished.
+    fn progress(&self) -> Option<u32> {
+        let elapsed = self.start.elapsed();
+        if elapsed >= self.duration {
+            return None;
+        }
+        Some((elapsed.as_micros() * 256 / self.duration.as_micros()) as u32)
+    }
+}
+
+/// Mix `progress`/256 of `to` into `from`.
+fn blend(from: RGB8, to: RGB8, progress: u32) -> RGB8 {
+    let mix = |a: u8, b: u8| ((a as u32 * (256 - progress) + b as u32 * progress) >> 8) as u8;
+    RGB8::new(mix(from.r, to.r), mix(from.g, to.g), mix(from.b, to.b))
+}
+
 /// Input a value 0 to 255 to get a color value
 /// The colours are a transition r - g - b - back to r.
 fn wheel(mut wheel_pos: u8) -> RGB8 {
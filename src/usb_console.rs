@@ -0,0 +1,101 @@
+use embassy_net::Stack;
+use embassy_usb::{
+    class::cdc_acm::{CdcAcmClass, State},
+    driver::{Driver, EndpointError},
+    Builder,
+};
+use embedded_io_async::{ErrorKind, ErrorType, Read, Write};
+use static_cell::StaticCell;
+
+use crate::console::Console;
+use crate::state::AppState;
+
+const MAX_PACKET_SIZE: usize = 64;
+
+pub(crate) fn make_usb_console<D>(builder: &mut Builder<'static, D>) -> CdcAcmClass<'static, D>
+where
+    D: Driver<'static>,
+{
+    static STATE: StaticCell<State> = StaticCell::new();
+    CdcAcmClass::new(builder, STATE.init(State::new()), MAX_PACKET_SIZE as u16)
+}
+
+/// A CDC-ACM port as a byte stream rather than USB packets.
+struct AcmStream<'a, D: Driver<'static>> {
+    class: &'a mut CdcAcmClass<'static, D>,
+    rx_buffer: [u8; MAX_PACKET_SIZE],
+    rx_start: usize,
+    rx_end: usize,
+    /// The last packet sent was full, so the host is waiting for more
+    needs_zlp: bool,
+}
+
+impl<D: Driver<'static>> ErrorType for AcmStream<'_, D> {
+    type Error = ErrorKind;
+}
+
+fn endpoint_error(err: EndpointError) -> ErrorKind {
+    match err {
+        EndpointError::BufferOverflow => ErrorKind::OutOfMemory,
+        EndpointError::Disabled => ErrorKind::NotConnected,
+    }
+}
+
+impl<D: Driver<'static>> Read for AcmStream<'_, D> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        // An empty read would look like the end of the stream
+        while self.rx_start == self.rx_end {
+            self.rx_end = self
+                .class
+                .read_packet(&mut self.rx_buffer)
+                .await
+                .map_err(endpoint_error)?;
+            self.rx_start = 0;
+        }
+        let len = buf.len().min(self.rx_end - self.rx_start);
+        buf[..len].copy_from_slice(&self.rx_buffer[self.rx_start..self.rx_start + len]);
+        self.rx_start += len;
+        Ok(len)
+    }
+}
+
+impl<D: Driver<'static>> Write for AcmStream<'_, D> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let len = buf.len().min(MAX_PACKET_SIZE);
+        self.class
+            .write_packet(&buf[..len])
+            .await
+            .map_err(endpoint_error)?;
+        self.needs_zlp = len == MAX_PACKET_SIZE;
+        Ok(len)
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        if core::mem::take(&mut self.needs_zlp) {
+            self.class.write_packet(&[]).await.map_err(endpoint_error)?;
+        }
+        Ok(())
+    }
+}
+
+#[embassy_executor::task]
+pub async fn console_task(
+    mut class: CdcAcmClass<'static, embassy_rp::usb::Driver<'static, embassy_rp::peripherals::USB>>,
+    app: AppState,
+    stack: Stack<'static>,
+) -> ! {
+    loop {
+        class.wait_connection().await;
+        let stream = AcmStream {
+            class: &mut class,
+            rx_buffer: [0; MAX_PACKET_SIZE],
+            rx_start: 0,
+            rx_end: 0,
+            needs_zlp: false,
+        };
+        // Ends when the host goes away, so wait for it to come back
+        let _ = Console::new(stream, app, || stack.is_link_up(), true)
+            .run()
+            .await;
+    }
+}
@@ -1,11 +1,16 @@
 use embassy_usb::driver::Driver;
+use embassy_usb::msos::windows_version;
 use embassy_usb::Builder;
 
 use static_cell::StaticCell;
 
+use crate::device_id::DeviceId;
 use crate::DEVICE_NAME;
 
-pub fn get_usb_builder<D>(usb_driver: D) -> Builder<'static, D>
+/// Request code Windows uses to fetch the MS OS 2.0 descriptor set
+const MSOS_VENDOR_CODE: u8 = 0x01;
+
+pub fn get_usb_builder<D>(usb_driver: D, device: &'static DeviceId) -> Builder<'static, D>
 where
     D: Driver<'static>,
 {
@@ -13,7 +18,7 @@ where
         let mut config = embassy_usb::Config::new(0xc0de, 0xcafe);
         config.manufacturer = Some("Kaze");
         config.product = Some(DEVICE_NAME);
-        config.serial_number = Some("12345678");
+        config.serial_number = Some(&device.serial);
         config.max_power = 500;
         config.max_packet_size_0 = 64;
 
@@ -26,18 +31,22 @@ where
     };
 
     let builder = {
-        static CONFIG_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+        static CONFIG_DESCRIPTOR: StaticCell<[u8; 512]> = StaticCell::new();
         static BOS_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+        static MSOS_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
         static CONTROL_BUF: StaticCell<[u8; 64]> = StaticCell::new();
 
-        let builder = embassy_usb::Builder::new(
+        let mut builder = embassy_usb::Builder::new(
             usb_driver,
             config,
-            CONFIG_DESCRIPTOR.init([0; 256]),
+            CONFIG_DESCRIPTOR.init([0; 512]),
             BOS_DESCRIPTOR.init([0; 256]),
-            &mut [], // no msos descriptors
+            MSOS_DESCRIPTOR.init([0; 256]),
             CONTROL_BUF.init([0; 64]),
         );
+        // Lets Windows pick drivers for our functions without an INF file,
+        // see usb_ethernet.rs
+        builder.msos_descriptor(windows_version::WIN8_1, MSOS_VENDOR_CODE);
         builder
     };
 
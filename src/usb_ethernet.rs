@@ -5,31 +5,46 @@ use embassy_usb::{
         CdcNcmClass,
     },
     driver::Driver,
+    msos::CompatibleIdFeatureDescriptor,
+    types::InterfaceNumber,
     Builder,
 };
 use static_cell::StaticCell;
 
+use crate::device_id::DeviceId;
+
 const MTU: usize = 1514;
+/// NCM is the first function added to the builder, so starts at interface 0
+const NCM_INTERFACE: InterfaceNumber = InterfaceNumber(0);
 
 pub(crate) fn make_usb_ethernet_device<D>(
     builder: &mut Builder<'static, D>,
+    device: &DeviceId,
 ) -> (Runner<'static, D, MTU>, Device<'static, MTU>)
 where
     D: Driver<'static>,
 {
-    let our_mac_addr = [0xe2, 0x58, 0xb1, 0xe7, 0xfb, 0x12];
-    let host_mac_addr = [0x82, 0x88, 0x88, 0x88, 0x88, 0x88];
-
     // Create classes on the builder.
     let cdc_ncm_class = {
         static STATE: StaticCell<cdc_ncm::State> = StaticCell::new();
         let state = STATE.init(cdc_ncm::State::new());
-        CdcNcmClass::new(builder, state, host_mac_addr, 64)
+        CdcNcmClass::new(builder, state, device.host_mac, 64)
     };
 
+    // Windows 10 and later have a built in NCM driver, but only bind it to
+    // functions that ask for it. The class adds its own function, so its
+    // subset is written by hand.
+    let msos = builder.msos_writer();
+    if !msos.is_in_config_subset() {
+        msos.configuration(0);
+    }
+    msos.function(NCM_INTERFACE);
+    msos.function_feature(CompatibleIdFeatureDescriptor::new("WINNCM", ""));
+    msos.end_function();
+
     static NET_STATE: StaticCell<State<MTU, 4, 4>> = StaticCell::new();
     let (runner, device) = cdc_ncm_class
-        .into_embassy_net_device::<MTU, 4, 4>(NET_STATE.init(State::new()), our_mac_addr);
+        .into_embassy_net_device::<MTU, 4, 4>(NET_STATE.init(State::new()), device.mac);
 
     (runner, device)
 }
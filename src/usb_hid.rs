@@ -0,0 +1,81 @@
+//! Vendor defined HID interface for host tools, for machines that won't bring
+//! up a new network adapter. HID needs no driver or network setup, so a tool
+//! can open it through hidraw or the OS's HID API straight away. The reports
+//! carry the protocol in hid.rs.
+
+use embassy_usb::{
+    class::hid::{Config, HidReaderWriter, ReadError, State},
+    driver::Driver,
+    Builder,
+};
+use static_cell::StaticCell;
+
+use crate::hid::{handle_request, REPORT_LEN};
+use crate::state::AppState;
+
+/// One application collection with a `REPORT_LEN` byte input report and
+/// output report of opaque bytes, in a vendor defined usage page
+#[rustfmt::skip]
+const REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0x00, 0xFF,               // Usage Page (Vendor Defined 0xFF00)
+    0x09, 0x01,                     // Usage (0x01)
+    0xA1, 0x01,                     // Collection (Application)
+    0x09, 0x01,                     //   Usage (0x01)
+    0x15, 0x00,                     //   Logical Minimum (0)
+    0x26, 0xFF, 0x00,               //   Logical Maximum (255)
+    0x75, 0x08,                     //   Report Size (8)
+    0x95, REPORT_LEN as u8,         //   Report Count (REPORT_LEN)
+    0x81, 0x02,                     //   Input (Data, Variable, Absolute)
+    0x09, 0x02,                     //   Usage (0x02)
+    0x15, 0x00,                     //   Logical Minimum (0)
+    0x26, 0xFF, 0x00,               //   Logical Maximum (255)
+    0x75, 0x08,                     //   Report Size (8)
+    0x95, REPORT_LEN as u8,         //   Report Count (REPORT_LEN)
+    0x91, 0x02,                     //   Output (Data, Variable, Absolute)
+    0xC0,                           // End Collection
+];
+
+pub(crate) fn make_usb_hid<D>(
+    builder: &mut Builder<'static, D>,
+) -> HidReaderWriter<'static, D, REPORT_LEN, REPORT_LEN>
+where
+    D: Driver<'static>,
+{
+    static STATE: StaticCell<State> = StaticCell::new();
+    let config = Config {
+        report_descriptor: REPORT_DESCRIPTOR,
+        request_handler: None,
+        poll_ms: 10,
+        max_packet_size: REPORT_LEN as u16,
+    };
+    HidReaderWriter::new(builder, STATE.init(State::new()), config)
+}
+
+#[embassy_executor::task]
+pub async fn hid_task(
+    hid: HidReaderWriter<
+        'static,
+        embassy_rp::usb::Driver<'static, embassy_rp::peripherals::USB>,
+        REPORT_LEN,
+        REPORT_LEN,
+    >,
+    app: AppState,
+) -> ! {
+    let (mut reader, mut writer) = hid.split();
+    let mut request = [0; REPORT_LEN];
+    loop {
+        // Short reports are zero padded, like the host would have sent
+        request.fill(0);
+        match reader.read(&mut request).await {
+            Ok(_) => {}
+            Err(ReadError::Disabled) => {
+                reader.ready().await;
+                continue;
+            }
+            Err(_) => continue,
+        }
+        let response = handle_request(&app, &request).await;
+        // A host that stopped listening misses the reply, which is fine
+        let _ = writer.write(&response).await;
+    }
+}
@@ -0,0 +1,27 @@
+use embassy_usb::{class::midi::MidiClass, driver::Driver, Builder};
+
+use crate::midi::MidiReceiver;
+
+const MAX_PACKET_SIZE: usize = 64;
+
+pub(crate) fn make_usb_midi<D>(builder: &mut Builder<'static, D>) -> MidiClass<'static, D>
+where
+    D: Driver<'static>,
+{
+    // One jack each way, though nothing is sent back yet
+    MidiClass::new(builder, 1, 1, MAX_PACKET_SIZE as u16)
+}
+
+#[embassy_executor::task]
+pub async fn midi_task(
+    mut class: MidiClass<'static, embassy_rp::usb::Driver<'static, embassy_rp::peripherals::USB>>,
+    receiver: MidiReceiver,
+) -> ! {
+    let mut packet = [0; MAX_PACKET_SIZE];
+    loop {
+        class.wait_connection().await;
+        while let Ok(len) = class.read_packet(&mut packet).await {
+            receiver.handle_usb_packet(&packet[..len]).await;
+        }
+    }
+}
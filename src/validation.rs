@@ -0,0 +1,114 @@
+use defmt::Format;
+
+/// Why a piece of state was rejected, reported back to API clients.
+#[derive(serde::Serialize, Format, Clone, Copy, PartialEq)]
+pub struct ValidationError {
+    /// Path to the offending field within `SharedState`,
+    /// e.g. `underpass_lights_state.Cars.min_interval`
+    pub field: &'static str,
+    /// Position of the offending entry when the field is an array
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index: Option<usize>,
+    pub reason: &'static str,
+}
+
+impl ValidationError {
+    pub const fn new(field: &'static str, reason: &'static str) -> Self {
+        Self {
+            field,
+            index: None,
+            reason,
+        }
+    }
+
+    pub const fn at(self, index: usize) -> Self {
+        Self {
+            index: Some(index),
+            ..self
+        }
+    }
+}
+
+pub trait Validate {
+    fn validate(&self) -> Result<(), ValidationError>;
+}
+
+const MAX_REASON_LEN: usize = 48;
+
+/// A `ValidationError::reason` that names a limit, written out at compile time
+/// from the limit's constant so the two can't drift apart. Kept in a `static`
+/// so `as_str` lives as long as the error needs.
+pub struct Reason {
+    bytes: [u8; MAX_REASON_LEN],
+    len: usize,
+}
+
+impl Reason {
+    pub const fn at_most(max: u32) -> Self {
+        Self::new().text("must be at most ").number(max)
+    }
+
+    pub const fn between(min: u32, max: u32) -> Self {
+        Self::new()
+            .text("must be between ")
+            .number(min)
+            .text(" and ")
+            .number(max)
+    }
+
+    const fn new() -> Self {
+        Self {
+            bytes: [0; MAX_REASON_LEN],
+            len: 0,
+        }
+    }
+
+    const fn text(mut self, text: &str) -> Self {
+        let text = text.as_bytes();
+        let mut i = 0;
+        while i < text.len() {
+            self.bytes[self.len] = text[i];
+            self.len += 1;
+            i += 1;
+        }
+        self
+    }
+
+    const fn number(mut self, mut number: u32) -> Self {
+        let mut digits = [0; 10];
+        let mut count = 0;
+        loop {
+            digits[count] = b'0' + (number % 10) as u8;
+            count += 1;
+            number /= 10;
+            if number == 0 {
+                break;
+            }
+        }
+        while count > 0 {
+            count -= 1;
+            self.bytes[self.len] = digits[count];
+            self.len += 1;
+        }
+        self
+    }
+
+    pub fn as_str(&'static self) -> &'static str {
+        // Only ever built from `str`s and ASCII digits
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static AT_MOST: Reason = Reason::at_most(10_000);
+    static BETWEEN: Reason = Reason::between(1, u32::MAX);
+
+    #[test]
+    fn writes_limits_into_reasons() {
+        assert_eq!(AT_MOST.as_str(), "must be at most 10000");
+        assert_eq!(BETWEEN.as_str(), "must be between 1 and 4294967295");
+    }
+}
@@ -1,37 +1,251 @@
+use core::cell::Cell;
+use core::pin::pin;
+use core::str::FromStr;
+#[cfg(target_os = "none")]
 use embassy_net::Stack;
-use embassy_time::Duration;
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
 use picoserve::{
-    extract::{self, State},
+    extract::{FromRequest, JsonRejection, State},
+    io::{Read, Write},
     make_static,
-    response::{json, File, IntoResponse},
+    request::{RequestBody, RequestParts},
+    response::{
+        json,
+        ws::{Message, SocketRx, SocketTx, WebSocketCallback},
+        Connection, File, IntoResponse, ResponseWriter, StatusCode, WebSocketUpgrade,
+    },
     routing::{get, get_service, parse_path_segment, post, put},
-    AppRouter, AppWithStateBuilder, Config,
+    AppRouter, AppWithStateBuilder, ResponseSent,
 };
 
 use crate::{
-    state::{AppState, SharedState, SharedStateMutex},
-    streetlamps,
+    api::{self, ApiError, Command, ErrorReply, Reply},
+    dmx::DmxConfig,
+    midi::MidiConfig,
+    mqtt::MqttConfigUpdate,
+    network_config::{NetworkConfig, PortalUrl},
+    playlist::{Playlist, PlaylistCommand},
+    state::{AppState, SharedState},
+    storage::{PresetName, StorageError},
+    validation::ValidationError,
+    wled::{self, WledStateUpdate, EFFECTS},
 };
 
 const INDEX_HTML: &str = include_str!("../static/index.html");
 const STYLE_CSS: &[u8] = include_bytes!("../static/pico.slate.min.css.gz");
 const SCRIPT_JS: &str = include_str!("../static/script.js");
 
+// picoserve's timer comes from embassy on the device and from tokio in the simulator
+#[cfg(target_os = "none")]
+pub type Duration = embassy_time::Duration;
+#[cfg(not(target_os = "none"))]
+pub type Duration = std::time::Duration;
+
 pub struct AppProps;
 
-pub async fn get_state(
-    extract::State(SharedStateMutex(shared)): extract::State<SharedStateMutex>,
-) -> impl IntoResponse {
-    json::Json(shared.lock().await.clone())
+fn unprocessable(err: ValidationError) -> impl IntoResponse {
+    json::Json(err)
+        .into_response()
+        .with_status_code(StatusCode::UNPROCESSABLE_ENTITY)
+}
+
+fn storage_error(err: StorageError) -> impl IntoResponse {
+    let status = match err {
+        StorageError::NotFound => StatusCode::NOT_FOUND,
+        StorageError::AlreadyExists => StatusCode::CONFLICT,
+        StorageError::Full => StatusCode::INSUFFICIENT_STORAGE,
+        StorageError::Corrupt | StorageError::Undecodable | StorageError::Flash => {
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+    // Shaped like a `ValidationError` without the field, as over the WebSocket
+    json::Json(ErrorReply::Failed {
+        reason: err.reason(),
+    })
+    .into_response()
+    .with_status_code(status)
+}
+
+impl IntoResponse for ApiError {
+    async fn write_to<R: Read, W: ResponseWriter<Error = R::Error>>(
+        self,
+        connection: Connection<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        match self {
+            ApiError::Invalid(err) => {
+                unprocessable(err)
+                    .write_to(connection, response_writer)
+                    .await
+            }
+            ApiError::Storage(err) => {
+                storage_error(err)
+                    .write_to(connection, response_writer)
+                    .await
+            }
+        }
+    }
+}
+
+/// A path operating systems fetch to check for internet access. In captive
+/// portal mode every name resolves to us, so answering these with a redirect
+/// makes the OS open the control panel.
+struct ConnectivityCheck;
+
+impl FromStr for ConnectivityCheck {
+    type Err = ();
+
+    fn from_str(path: &str) -> Result<Self, Self::Err> {
+        match path {
+            // Android and ChromeOS
+            "generate_204" | "gen_204"
+            // Apple
+            | "hotspot-detect.html"
+            // Windows
+            | "connecttest.txt" | "ncsi.txt" | "redirect"
+            // Firefox
+            | "canonical.html" | "success.txt"
+            // NetworkManager
+            | "check_network_status.txt" => Ok(ConnectivityCheck),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Like picoserve's `Redirect`, but to the portal URL worked out at boot
+/// rather than a fixed string.
+struct PortalRedirect(PortalUrl);
+
+impl IntoResponse for PortalRedirect {
+    async fn write_to<R: Read, W: ResponseWriter<Error = R::Error>>(
+        self,
+        connection: Connection<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        (
+            StatusCode::SEE_OTHER,
+            ("Location", self.0.as_str()),
+            format_args!("{}\n", self.0),
+        )
+            .write_to(connection, response_writer)
+            .await
+    }
+}
+
+/// `POST /json/state` bodies, which need `WledStateUpdate::from_json` rather
+/// than `json::Json`.
+impl<'r> FromRequest<'r, AppState> for WledStateUpdate {
+    type Rejection = JsonRejection;
+
+    async fn from_request<R: Read>(
+        _state: &'r AppState,
+        _request_parts: RequestParts<'r>,
+        request_body: RequestBody<'r, R>,
+    ) -> Result<Self, Self::Rejection> {
+        let body = request_body
+            .read_all()
+            .await
+            .map_err(|_| JsonRejection::IoError)?;
+        WledStateUpdate::from_json(body).map_err(JsonRejection::DeserializationError)
+    }
+}
+
+/// How many WebSockets can be open at once. Each holds on to a web task for
+/// as long as it's open, so this leaves tasks free for plain requests.
+const MAX_WEB_SOCKETS: usize = 3;
+
+static OPEN_WEB_SOCKETS: Mutex<CriticalSectionRawMutex, Cell<usize>> = Mutex::new(Cell::new(0));
+
+/// One of the `MAX_WEB_SOCKETS`, given back when dropped.
+struct SocketSlot;
+
+impl SocketSlot {
+    fn take() -> Option<Self> {
+        OPEN_WEB_SOCKETS.lock(|open| {
+            (open.get() < MAX_WEB_SOCKETS).then(|| {
+                open.set(open.get() + 1);
+                SocketSlot
+            })
+        })
+    }
+}
+
+impl Drop for SocketSlot {
+    fn drop(&mut self) {
+        OPEN_WEB_SOCKETS.lock(|open| open.set(open.get() - 1));
+    }
 }
 
-pub async fn set_state(
-    extract::State(SharedStateMutex(shared)): extract::State<SharedStateMutex>,
-    json::Json(state): json::Json<SharedState>,
-) -> impl IntoResponse {
-    let mut shared = shared.lock().await;
-    *shared = state;
-    json::Json(shared.clone())
+/// A WebSocket client: sent the full state when it connects and whenever the
+/// state changes, and able to send any `api::Command`.
+struct StateSocket {
+    app: AppState,
+}
+
+impl WebSocketCallback for StateSocket {
+    async fn run<R: Read, W: Write<Error = R::Error>>(
+        self,
+        mut rx: SocketRx<R>,
+        mut tx: SocketTx<W>,
+    ) -> Result<(), W::Error> {
+        let slot = SocketSlot::take();
+        let state_changes = slot.as_ref().and_then(|_| self.app.shared.receiver());
+        let Some(mut state_changes) = state_changes else {
+            tx.send_json(Reply::Error(ErrorReply::Failed {
+                reason: "Too many connections",
+            }))
+            .await?;
+            return tx.close(None).await;
+        };
+
+        let mut buffer = [0; 2048];
+        tx.send_json(Reply::State(state_changes.get().await))
+            .await?;
+
+        loop {
+            // Reading a message can't be cancelled part way through, so keep
+            // the same read going while passing on changes
+            let message = {
+                let mut next_message = pin!(rx.next_message(&mut buffer));
+                loop {
+                    match select(&mut next_message, state_changes.changed()).await {
+                        Either::First(message) => break message,
+                        Either::Second(state) => tx.send_json(Reply::State(state)).await?,
+                    }
+                }
+            };
+
+            let reply = match message {
+                Ok(Message::Text(text)) => {
+                    match serde_json_core::from_str_escaped::<Command>(text, &mut [0; 64]) {
+                        Ok((command, _)) => {
+                            let reply = command.execute(&self.app).await;
+                            // The reply already holds the new state, so don't
+                            // send it again as a change
+                            if let Reply::State(_) = reply {
+                                let _ = state_changes.try_changed();
+                            }
+                            reply
+                        }
+                        Err(_) => Reply::Error(ErrorReply::Failed {
+                            reason: "Unrecognised command",
+                        }),
+                    }
+                }
+                Ok(Message::Ping(data)) => {
+                    tx.send_pong(data).await?;
+                    continue;
+                }
+                Ok(Message::Binary(_) | Message::Pong(_)) => continue,
+                Ok(Message::Close(_)) | Err(_) => break,
+            };
+            tx.send_json(reply).await?;
+        }
+
+        tx.close(None).await
+    }
 }
 
 impl AppWithStateBuilder for AppProps {
@@ -50,31 +264,176 @@ impl AppWithStateBuilder for AppProps {
                 )),
             )
             .route("/script.js", get_service(File::javascript(SCRIPT_JS)))
-            .route("/state", get(get_state).put(set_state))
             .route(
-                "/power",
+                "/state",
+                get(|State(app): State<AppState>| async move {
+                    json::Json(api::get_state(&app).await)
+                })
+                .put(
+                    |State(app): State<AppState>, json::Json(state): json::Json<SharedState>| async move {
+                        api::set_state(&app, state).await.map(json::Json)
+                    },
+                ),
+            )
+            .route(
+                "/ws",
+                get(
+                    |State(app): State<AppState>, upgrade: WebSocketUpgrade| async move {
+                        upgrade.on_upgrade(StateSocket { app })
+                    },
+                ),
+            )
+            .route(
+                "/presets",
+                get(|State(app): State<AppState>| async move {
+                    api::list_presets(&app).await.map(json::Json)
+                }),
+            )
+            .route(
+                ("/presets", parse_path_segment::<PresetName>()),
+                put(|name, State(app): State<AppState>| async move {
+                    api::save_preset(&app, name).await.map(json::Json)
+                })
+                .delete(|name, State(app): State<AppState>| async move {
+                    api::delete_preset(&app, &name).await.map(json::Json)
+                }),
+            )
+            .route(
+                ("/presets", parse_path_segment::<PresetName>(), "/recall"),
+                post(|name, State(app): State<AppState>| async move {
+                    api::recall_preset(&app, &name).await.map(json::Json)
+                }),
+            )
+            .route(
+                ("/presets", parse_path_segment::<PresetName>(), "/rename"),
                 post(
-                    |State(SharedStateMutex(shared)): State<SharedStateMutex>| async {
-                        let power = &mut shared.lock().await.streetlamps_enabled;
-                        *power = !*power;
-                        json::Json(*power)
+                    |name, State(app): State<AppState>, json::Json(new_name): json::Json<PresetName>| async move {
+                        api::rename_preset(&app, &name, new_name)
+                            .await
+                            .map(json::Json)
+                    },
+                ),
+            )
+            .route(
+                "/playlist",
+                get(|State(app): State<AppState>| async move {
+                    json::Json(api::get_playlist(&app).await)
+                })
+                .put(
+                    |State(app): State<AppState>, json::Json(playlist): json::Json<Playlist>| async move {
+                        api::set_playlist(&app, playlist).await.map(json::Json)
+                    },
+                ),
+            )
+            .route(
+                "/playlist/status",
+                get(|State(app): State<AppState>| async move {
+                    json::Json(api::playlist_status(&app).await)
+                }),
+            )
+            .route(
+                ("/playlist", parse_path_segment::<PlaylistCommand>()),
+                post(|command, State(app): State<AppState>| async move {
+                    api::control_playlist(&app, command).await
+                }),
+            )
+            .route(
+                "/dmx",
+                get(|State(app): State<AppState>| async move {
+                    json::Json(api::get_dmx_config(&app).await)
+                })
+                .put(
+                    |State(app): State<AppState>, json::Json(config): json::Json<DmxConfig>| async move {
+                        api::set_dmx_config(&app, config).await.map(json::Json)
+                    },
+                ),
+            )
+            .route(
+                "/mqtt",
+                get(|State(app): State<AppState>| async move {
+                    json::Json(api::get_mqtt_config(&app).await)
+                })
+                .put(
+                    |State(app): State<AppState>, json::Json(update): json::Json<MqttConfigUpdate>| async move {
+                        api::set_mqtt_config(&app, update).await.map(json::Json)
                     },
                 ),
             )
+            .route(
+                "/midi",
+                get(|State(app): State<AppState>| async move {
+                    json::Json(api::get_midi_config(&app).await)
+                })
+                .put(
+                    |State(app): State<AppState>, json::Json(config): json::Json<MidiConfig>| async move {
+                        api::set_midi_config(&app, config).await.map(json::Json)
+                    },
+                ),
+            )
+            .route(
+                "/info",
+                get(|State(app): State<AppState>| async move {
+                    json::Json(api::get_info(&app).await)
+                }),
+            )
+            .route("/services", get(|| async { json::Json(api::get_services()) }))
+            .route(
+                "/network",
+                get(|State(app): State<AppState>| async move {
+                    api::get_network_config(&app).await.map(json::Json)
+                })
+                .put(
+                    |State(app): State<AppState>, json::Json(config): json::Json<NetworkConfig>| async move {
+                        api::set_network_config(&app, config).await.map(json::Json)
+                    },
+                ),
+            )
+            .route(
+                parse_path_segment::<ConnectivityCheck>(),
+                get(|_, State(app): State<AppState>| async move {
+                    PortalRedirect(app.network.portal_url())
+                }),
+            )
+            // Apple's other check, the only one more than one segment deep
+            .route(
+                "/library/test/success.html",
+                get(|State(app): State<AppState>| async move {
+                    PortalRedirect(app.network.portal_url())
+                }),
+            )
+            .route(
+                "/json",
+                get(|State(app): State<AppState>| async move {
+                    json::Json(wled::get_all(&app).await)
+                }),
+            )
+            .route(
+                "/json/state",
+                get(|State(app): State<AppState>| async move {
+                    json::Json(wled::get_state(&app).await)
+                })
+                .post(
+                    |State(app): State<AppState>, update: WledStateUpdate| async move {
+                        wled::update_state(&app, update).await.map(json::Json)
+                    },
+                ),
+            )
+            .route(
+                "/json/info",
+                get(|State(app): State<AppState>| async move { json::Json(wled::info(&app)) }),
+            )
+            .route("/json/effects", get(|| async { json::Json(EFFECTS) }))
+            .route(
+                "/power",
+                post(|State(app): State<AppState>| async move {
+                    json::Json(api::toggle_power(&app).await)
+                }),
+            )
             .route(
                 ("/lamp", parse_path_segment(), parse_path_segment()),
                 post(
-                    |(id, state): (usize, u8),
-                     State(SharedStateMutex(shared)): State<SharedStateMutex>| async move {
-                        let mut shared = shared.lock().await;
-                        if id < shared.streetlamps_modes.len() {
-                            shared.streetlamps_modes[id as usize] = match state {
-                                0 => streetlamps::StreetlampMode::Off,
-                                1 => streetlamps::StreetlampMode::On,
-                                2 => streetlamps::StreetlampMode::Flickering { chance: 90 },
-                                _ => streetlamps::StreetlampMode::Off,
-                            };
-                        }
+                    |(id, mode): (usize, u8), State(app): State<AppState>| async move {
+                        api::set_lamp(&app, id, mode).await
                     },
                 ),
             )
@@ -83,16 +442,16 @@ impl AppWithStateBuilder for AppProps {
 
 pub fn make_web_app() -> (
     &'static AppRouter<AppProps>,
-    &'static picoserve::Config<embassy_time::Duration>,
+    &'static picoserve::Config<Duration>,
 ) {
     // Setup web app
     let app = make_static!(AppRouter<AppProps>, AppProps.build_app());
     let config = make_static!(
-        picoserve::Config<embassy_time::Duration>,
+        picoserve::Config<Duration>,
         picoserve::Config::new(picoserve::Timeouts {
-            start_read_request: Some(embassy_time::Duration::from_secs(5)),
-            read_request: Some(embassy_time::Duration::from_secs(1)),
-            write: Some(embassy_time::Duration::from_secs(1)),
+            start_read_request: Some(Duration::from_secs(5)),
+            read_request: Some(Duration::from_secs(1)),
+            write: Some(Duration::from_secs(1)),
         })
         .keep_connection_alive()
     );
@@ -100,14 +459,16 @@ pub fn make_web_app() -> (
     (app, config)
 }
 
-pub(crate) const WEB_TASK_POOL_SIZE: usize = 3;
+#[cfg(target_os = "none")]
+pub(crate) const WEB_TASK_POOL_SIZE: usize = MAX_WEB_SOCKETS + 2;
+#[cfg(target_os = "none")]
 #[embassy_executor::task(pool_size = WEB_TASK_POOL_SIZE)]
 pub async fn web_task(
     id: usize,
     stack: Stack<'static>,
     state: AppState,
     app: &'static AppRouter<AppProps>,
-    config: &'static Config<Duration>,
+    config: &'static picoserve::Config<Duration>,
 ) -> ! {
     let port = 80;
     let mut tcp_rx_buffer = [0; 1024];
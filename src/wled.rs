@@ -0,0 +1,439 @@
+//! Enough of WLED's JSON API for the WLED apps and Home Assistant's WLED
+//! integration to control the diorama as if it were a WLED device.
+//!
+//! The underpass strip is WLED's only segment, and its effects are the
+//! `LightingState`s. Power covers both the streetlamps and the strip, and the
+//! master brightness is the streetlamps' brightness.
+
+use core::fmt::Write;
+
+use embassy_time::Instant;
+use heapless::{String, Vec};
+use smart_leds::RGB8;
+
+use crate::api::{self, set_strip_power, ApiError};
+use crate::realtime::WLED_REALTIME_PORT;
+use crate::state::AppState;
+use crate::underpass_lights::{
+    LightingState, MAX_SPEED_LIMIT_KPH, MAX_TRANSITION_MS, MIN_SPEED_LIMIT_KPH, NUM_LEDS,
+};
+use crate::DEVICE_NAME;
+
+/// The WLED release whose API this follows
+const WLED_VERSION: &str = "0.14.0";
+const WLED_BUILD: u32 = 2310130;
+
+/// Indexed by the `fx` number
+pub const EFFECTS: [&str; 3] = ["Solid", "Rainbow", "Cars"];
+const FX_SOLID: u8 = 0;
+const FX_RAINBOW: u8 = 1;
+const FX_CARS: u8 = 2;
+
+/// WLED's default colour, for effects that don't have one of their own
+const DEFAULT_COLOUR: RGB8 = RGB8::new(255, 160, 0);
+
+/// WLED transitions are in tenths of a second
+const TRANSITION_UNIT_MS: u16 = 100;
+
+#[derive(serde::Serialize)]
+pub struct WledState {
+    on: bool,
+    bri: u8,
+    transition: u16,
+    ps: i8,
+    pl: i8,
+    lor: u8,
+    mainseg: u8,
+    seg: [WledSegment; 1],
+}
+
+#[derive(serde::Serialize)]
+struct WledSegment {
+    id: u8,
+    start: u16,
+    stop: u16,
+    len: u16,
+    grp: u8,
+    spc: u8,
+    of: u8,
+    on: bool,
+    frz: bool,
+    bri: u8,
+    cct: u8,
+    col: [[u8; 3]; 3],
+    fx: u8,
+    sx: u8,
+    ix: u8,
+    pal: u8,
+    sel: bool,
+    rev: bool,
+    mi: bool,
+}
+
+/// A `POST /json/state` body, where everything left out stays as it is.
+#[derive(serde::Deserialize)]
+pub struct StateUpdate<S> {
+    on: Option<bool>,
+    bri: Option<u8>,
+    transition: Option<u16>,
+    #[serde(default)]
+    seg: S,
+    /// Reply with the new state instead of just success
+    #[serde(default)]
+    v: bool,
+}
+
+pub type WledStateUpdate = StateUpdate<Vec<WledSegmentUpdate, 4>>;
+
+impl WledStateUpdate {
+    /// Parse a `POST /json/state` body. WLED takes `seg` as either an array of
+    /// segments or a single one, and serde_json_core can't look ahead to see
+    /// which, so this tries one and then the other.
+    pub fn from_json(body: &[u8]) -> Result<Self, serde_json_core::de::Error> {
+        if let Ok((update, _)) = serde_json_core::from_slice::<Self>(body) {
+            return Ok(update);
+        }
+        let (update, _) =
+            serde_json_core::from_slice::<StateUpdate<Option<WledSegmentUpdate>>>(body)?;
+        Ok(StateUpdate {
+            on: update.on,
+            bri: update.bri,
+            transition: update.transition,
+            seg: update.seg.into_iter().collect(),
+            v: update.v,
+        })
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct WledSegmentUpdate {
+    id: Option<u8>,
+    on: Option<bool>,
+    /// Colours as `[r, g, b]` or `[r, g, b, w]`, the first being the primary
+    col: Option<Vec<Vec<u8, 4>, 3>>,
+    fx: Option<u8>,
+    sx: Option<u8>,
+}
+
+#[derive(serde::Serialize)]
+pub struct WledInfo {
+    ver: &'static str,
+    vid: u32,
+    leds: WledLeds,
+    str: bool,
+    name: &'static str,
+    udpport: u16,
+    live: bool,
+    fxcount: u8,
+    palcount: u8,
+    arch: &'static str,
+    core: &'static str,
+    freeheap: u32,
+    uptime: u64,
+    brand: &'static str,
+    product: &'static str,
+    mac: String<12>,
+    ip: String<15>,
+}
+
+#[derive(serde::Serialize)]
+struct WledLeds {
+    count: u16,
+    rgbw: bool,
+    wv: u8,
+    cct: u8,
+    pwr: u16,
+    fps: u8,
+    maxpwr: u16,
+    maxseg: u8,
+    seglc: [u8; 1],
+    lc: u8,
+}
+
+/// Everything at once, as fetched from `/json`
+#[derive(serde::Serialize)]
+pub struct WledAll {
+    state: WledState,
+    info: WledInfo,
+    effects: [&'static str; EFFECTS.len()],
+    palettes: [&'static str; 1],
+}
+
+#[derive(serde::Serialize)]
+#[serde(untagged)]
+pub enum WledUpdateReply {
+    State(WledState),
+    Success { success: bool },
+}
+
+fn primary_colour(lighting: &LightingState) -> RGB8 {
+    lighting.colour().unwrap_or(DEFAULT_COLOUR)
+}
+
+fn speed_to_sx(speed_limit_kph: u32) -> u8 {
+    let range = MAX_SPEED_LIMIT_KPH - MIN_SPEED_LIMIT_KPH;
+    ((speed_limit_kph.clamp(MIN_SPEED_LIMIT_KPH, MAX_SPEED_LIMIT_KPH) - MIN_SPEED_LIMIT_KPH) * 255
+        / range) as u8
+}
+
+/// Rounds up, so that `speed_to_sx` gives back the same `sx` and the WLED
+/// apps' speed slider doesn't creep down each time it's moved.
+fn sx_to_speed(sx: u8) -> u32 {
+    MIN_SPEED_LIMIT_KPH + (sx as u32 * (MAX_SPEED_LIMIT_KPH - MIN_SPEED_LIMIT_KPH)).div_ceil(255)
+}
+
+impl WledSegmentUpdate {
+    fn primary_colour(&self) -> Option<RGB8> {
+        match self.col.as_ref()?.first()?[..] {
+            [r, g, b, ..] => Some(RGB8::new(r, g, b)),
+            _ => None,
+        }
+    }
+
+    fn apply(&self, lighting: &mut LightingState) {
+        if let Some(on) = self.on {
+            set_strip_power(lighting, on);
+        }
+        if let Some(fx) = self.fx {
+            let colour = primary_colour(lighting);
+            *lighting = match fx {
+                FX_SOLID => LightingState::SingleColour(colour),
+                FX_RAINBOW => LightingState::RainbowCycle,
+                FX_CARS => match *lighting {
+                    cars @ LightingState::Cars { .. } => cars,
+                    _ => LightingState::cars(colour),
+                },
+                _ => *lighting,
+            };
+        }
+        if let Some(new_colour) = self.primary_colour() {
+            match lighting {
+                LightingState::SingleColour(colour) => *colour = new_colour,
+                LightingState::Cars { default_color, .. } => *default_color = new_colour,
+                LightingState::Off | LightingState::RainbowCycle => {}
+            }
+        }
+        if let (
+            Some(sx),
+            LightingState::Cars {
+                speed_limit_kph, ..
+            },
+        ) = (self.sx, lighting)
+        {
+            *speed_limit_kph = sx_to_speed(sx);
+        }
+    }
+}
+
+pub async fn get_state(app: &AppState) -> WledState {
+    let state = api::get_state(app).await;
+    let lighting = state.underpass_lights_state;
+    let colour = primary_colour(&lighting);
+    let (fx, sx) = match lighting {
+        LightingState::Off | LightingState::SingleColour(_) => (FX_SOLID, 128),
+        LightingState::RainbowCycle => (FX_RAINBOW, 128),
+        LightingState::Cars {
+            speed_limit_kph, ..
+        } => (FX_CARS, speed_to_sx(speed_limit_kph)),
+    };
+    let strip_on = lighting != LightingState::Off;
+
+    WledState {
+        on: state.streetlamps_enabled || strip_on,
+        bri: state.streetlamps_brightness,
+        transition: state.underpass_transition_ms / TRANSITION_UNIT_MS,
+        ps: -1,
+        pl: -1,
+        lor: 0,
+        mainseg: 0,
+        seg: [WledSegment {
+            id: 0,
+            start: 0,
+            stop: NUM_LEDS as u16,
+            len: NUM_LEDS as u16,
+            grp: 1,
+            spc: 0,
+            of: 0,
+            on: strip_on,
+            frz: false,
+            bri: 255,
+            cct: 127,
+            col: [[colour.r, colour.g, colour.b], [0; 3], [0; 3]],
+            fx,
+            sx,
+            ix: 128,
+            pal: 0,
+            sel: true,
+            rev: false,
+            mi: false,
+        }],
+    }
+}
+
+pub async fn update_state(
+    app: &AppState,
+    update: WledStateUpdate,
+) -> Result<WledUpdateReply, ApiError> {
+    let mut state = api::get_state(app).await;
+    if let Some(on) = update.on {
+        state.streetlamps_enabled = on;
+        set_strip_power(&mut state.underpass_lights_state, on);
+    }
+    if let Some(bri) = update.bri {
+        state.streetlamps_brightness = bri;
+    }
+    if let Some(transition) = update.transition {
+        state.underpass_transition_ms = transition
+            .saturating_mul(TRANSITION_UNIT_MS)
+            .min(MAX_TRANSITION_MS);
+    }
+    for segment in update.seg.iter().filter(|seg| seg.id.unwrap_or(0) == 0) {
+        segment.apply(&mut state.underpass_lights_state);
+    }
+    api::set_state(app, state).await?;
+
+    Ok(match update.v {
+        true => WledUpdateReply::State(get_state(app).await),
+        false => WledUpdateReply::Success { success: true },
+    })
+}
+
+pub fn info(app: &AppState) -> WledInfo {
+    let mut ip = String::new();
+    let _ = write!(ip, "{}", app.network.address);
+
+    WledInfo {
+        ver: WLED_VERSION,
+        vid: WLED_BUILD,
+        leds: WledLeds {
+            count: NUM_LEDS as u16,
+            rgbw: false,
+            wv: 0,
+            cct: 0,
+            pwr: 0,
+            fps: 0,
+            maxpwr: 0,
+            maxseg: 1,
+            seglc: [1],
+            lc: 1,
+        },
+        str: false,
+        name: DEVICE_NAME,
+        udpport: WLED_REALTIME_PORT,
+        live: app.live.is_streaming(),
+        fxcount: EFFECTS.len() as u8,
+        palcount: 1,
+        arch: "rp2040",
+        core: "embassy",
+        freeheap: 0,
+        uptime: Instant::now().as_secs(),
+        brand: "WLED",
+        product: DEVICE_NAME,
+        mac: app.device.mac_hex(),
+        ip,
+    }
+}
+
+pub async fn get_all(app: &AppState) -> WledAll {
+    WledAll {
+        state: get_state(app).await,
+        info: info(app),
+        effects: EFFECTS,
+        palettes: ["Default"],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COLOUR: RGB8 = RGB8::new(1, 2, 3);
+
+    fn update(json: &str) -> WledStateUpdate {
+        WledStateUpdate::from_json(json.as_bytes()).unwrap_or_else(|_| panic!("{json}"))
+    }
+
+    /// Apply the first segment of `json` to `lighting`.
+    fn applied(json: &str, mut lighting: LightingState) -> LightingState {
+        update(json).seg[0].apply(&mut lighting);
+        lighting
+    }
+
+    #[test]
+    fn round_trips_speeds() {
+        for sx in 0..=255 {
+            assert_eq!(speed_to_sx(sx_to_speed(sx)), sx);
+        }
+        assert_eq!(sx_to_speed(0), MIN_SPEED_LIMIT_KPH);
+        assert_eq!(sx_to_speed(255), MAX_SPEED_LIMIT_KPH);
+        assert_eq!(speed_to_sx(0), 0);
+        assert_eq!(speed_to_sx(MAX_SPEED_LIMIT_KPH + 1), 255);
+    }
+
+    #[test]
+    fn accepts_one_segment_or_an_array() {
+        let single = update(r#"{"on":true,"seg":{"fx":1}}"#);
+        assert_eq!(single.on, Some(true));
+        assert_eq!(single.seg.len(), 1);
+        assert_eq!(single.seg[0].fx, Some(1));
+
+        let array = update(r#"{"seg":[{"id":0,"fx":2},{"id":1,"fx":0}],"v":true}"#);
+        assert!(array.v);
+        assert_eq!(array.seg.len(), 2);
+        assert_eq!(array.seg[1].id, Some(1));
+
+        assert!(update(r#"{"bri":10}"#).seg.is_empty());
+        assert!(WledStateUpdate::from_json(br#"{"seg":1}"#).is_err());
+    }
+
+    #[test]
+    fn keeps_the_colour_when_changing_effect() {
+        let cars = applied(r#"{"seg":{"fx":2}}"#, LightingState::SingleColour(COLOUR));
+        assert!(cars == LightingState::cars(COLOUR));
+
+        let solid = applied(r#"{"seg":{"fx":0}}"#, cars);
+        assert!(solid == LightingState::SingleColour(COLOUR));
+
+        let solid = applied(r#"{"seg":{"fx":0}}"#, LightingState::RainbowCycle);
+        assert!(solid == LightingState::SingleColour(DEFAULT_COLOUR));
+    }
+
+    #[test]
+    fn keeps_the_traffic_when_choosing_cars_again() {
+        let mut cars = LightingState::cars(COLOUR);
+        if let LightingState::Cars { min_interval, .. } = &mut cars {
+            *min_interval = 99;
+        }
+        assert!(applied(r#"{"seg":{"fx":2}}"#, cars) == cars);
+    }
+
+    #[test]
+    fn colours_the_cars() {
+        let cars = applied(
+            r#"{"seg":{"col":[[4,5,6,0],[0,0,0]]}}"#,
+            LightingState::cars(COLOUR),
+        );
+        assert!(cars == LightingState::cars(RGB8::new(4, 5, 6)));
+    }
+
+    #[test]
+    fn sets_the_speed_of_the_cars_only() {
+        let cars = applied(r#"{"seg":{"sx":255}}"#, LightingState::cars(COLOUR));
+        assert!(matches!(
+            cars,
+            LightingState::Cars {
+                speed_limit_kph: MAX_SPEED_LIMIT_KPH,
+                ..
+            }
+        ));
+
+        let solid = LightingState::SingleColour(COLOUR);
+        assert!(applied(r#"{"seg":{"sx":255}}"#, solid) == solid);
+    }
+
+    #[test]
+    fn turns_the_strip_off_and_on() {
+        let off = applied(r#"{"seg":{"on":false}}"#, LightingState::cars(COLOUR));
+        assert!(off == LightingState::Off);
+    }
+}
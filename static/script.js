@@ -20,6 +20,13 @@ const modeMap = {
   Flickering: 2,
 };
 
+// Validation errors name the field, and storage errors only give a reason
+function errorMessage(err) {
+  if (!err.field) return err.reason;
+  const index = err.index === undefined ? "" : ` ${err.index + 1}`;
+  return `Invalid ${err.field}${index}: ${err.reason}`;
+}
+
 function enumToString(enumValue) {
   if (typeof enumValue === "string") return enumValue;
   if (typeof enumValue === "object") {
@@ -59,16 +66,37 @@ document.addEventListener("DOMContentLoaded", function () {
     return mode;
   });
 
+  function showState(data) {
+    lightingToggle.checked = data.streetlamps_enabled;
+    streetlampModes.forEach((mode, index) => {
+      mode.value = modeMap[enumToString(data.streetlamps_modes[index])];
+    });
+    updateUnderpassState(data);
+  }
+
   function checkState() {
     fetch("./state")
       .then((response) => response.json())
-      .then((data) => {
-        lightingToggle.checked = data.streetlamps_enabled;
-        streetlampModes.forEach((mode, index) => {
-          mode.value = modeMap[enumToString(data.streetlamps_modes[index])];
-        });
-        updateUnderpassState(data);
-      });
+      .then(showState);
+  }
+
+  // The diorama pushes every state change down the socket; fall back to
+  // polling whenever it isn't connected
+  let socket;
+  function connectSocket() {
+    const url = new URL("./ws", window.location.href);
+    url.protocol = url.protocol === "https:" ? "wss:" : "ws:";
+    socket = new WebSocket(url);
+    socket.addEventListener("message", (event) => {
+      const reply = JSON.parse(event.data);
+      if (reply.State) {
+        showState(reply.State);
+      }
+    });
+    socket.addEventListener("close", () => {
+      socket = undefined;
+      setTimeout(connectSocket, 5000);
+    });
   }
 
   lightingToggle.addEventListener(
@@ -174,8 +202,10 @@ document.addEventListener("DOMContentLoaded", function () {
 
   const underpassMode = document.getElementById("underpassMode");
   const underpassParams = document.getElementById("underpassModeParams");
+  const underpassTransition = document.getElementById("underpassTransition");
 
   function updateUnderpassState(state) {
+    underpassTransition.value = state.underpass_transition_ms;
     // Set mode dropdown
     const modeVal = getUnderpassModeValue(state.underpass_lights_state);
     if (underpassMode.value !== modeVal || dirty) {
@@ -212,6 +242,7 @@ document.addEventListener("DOMContentLoaded", function () {
       .then((response) => response.json())
       .then((data) => {
         let newState = { ...data };
+        newState.underpass_transition_ms = parseInt(underpassTransition.value);
         const mode = underpassMode.value;
         if (mode === "Off" || mode === "RainbowCycle") {
           newState.underpass_lights_state = mode;
@@ -249,15 +280,321 @@ document.addEventListener("DOMContentLoaded", function () {
           method: "PUT",
           headers: { "Content-Type": "application/json" },
           body: JSON.stringify(newState),
-        }).then(() => {
+        }).then((response) => {
+          if (!response.ok) {
+            response.json().then((err) => alert(errorMessage(err)));
+          }
           checkState();
         });
       });
   }
 
+  const presetList = document.getElementById("presetList");
+
+  function renderPresets(names) {
+    const selected = presetList.value;
+    presetList.innerHTML = "";
+    names.forEach((name) => {
+      const option = document.createElement("option");
+      option.value = name;
+      option.textContent = name;
+      presetList.appendChild(option);
+    });
+    if (names.includes(selected)) presetList.value = selected;
+  }
+
+  function presetRequest(url, options) {
+    return fetch(url, options).then((response) => {
+      if (!response.ok) {
+        return response.json().then((err) => {
+          const message = errorMessage(err);
+          alert(message);
+          throw new Error(message);
+        });
+      }
+      return response.json();
+    });
+  }
+
+  function presetUrl(name) {
+    return `./presets/${encodeURIComponent(name)}`;
+  }
+
+  function loadPresets() {
+    presetRequest("./presets").then(renderPresets);
+  }
+
+  document.getElementById("presetRecall").addEventListener("click", () => {
+    if (!presetList.value) return;
+    presetRequest(`${presetUrl(presetList.value)}/recall`, {
+      method: "POST",
+    }).then(() => {
+      dirty = true;
+      checkState();
+    });
+  });
+
+  document.getElementById("presetSave").addEventListener("click", () => {
+    const name = prompt("Save the current lighting as:", presetList.value);
+    if (!name) return;
+    presetRequest(presetUrl(name), { method: "PUT" }).then((names) => {
+      renderPresets(names);
+      presetList.value = name;
+    });
+  });
+
+  document.getElementById("presetRename").addEventListener("click", () => {
+    if (!presetList.value) return;
+    const name = prompt("Rename preset to:", presetList.value);
+    if (!name) return;
+    presetRequest(`${presetUrl(presetList.value)}/rename`, {
+      method: "POST",
+      headers: { "Content-Type": "application/json" },
+      body: JSON.stringify(name),
+    }).then((names) => {
+      renderPresets(names);
+      presetList.value = name;
+    });
+  });
+
+  document.getElementById("presetDelete").addEventListener("click", () => {
+    if (!presetList.value) return;
+    if (!confirm(`Delete preset "${presetList.value}"?`)) return;
+    presetRequest(presetUrl(presetList.value), { method: "DELETE" }).then(
+      renderPresets
+    );
+  });
+
+  const playlistStatus = document.getElementById("playlistStatus");
+
+  function checkPlaylist() {
+    fetch("./playlist/status")
+      .then((response) => response.json())
+      .then((status) => {
+        playlistStatus.textContent = status.playing
+          ? `Playing scene ${status.entry + 1}`
+          : "Stopped";
+      });
+  }
+
+  ["start", "next", "stop"].forEach((command) => {
+    const id = `playlist${command[0].toUpperCase()}${command.slice(1)}`;
+    document.getElementById(id).addEventListener("click", () => {
+      fetch(`./playlist/${command}`, { method: "POST" }).then(() => {
+        // Give the playlist a moment to act on the command
+        setTimeout(() => {
+          checkPlaylist();
+          dirty = true;
+          checkState();
+        }, 200);
+      });
+    });
+  });
+
+  const dmxForm = document.getElementById("dmxForm");
+  const dmxEnabled = document.getElementById("dmxEnabled");
+  const dmxUniverse = document.getElementById("dmxUniverse");
+  const dmxStartAddress = document.getElementById("dmxStartAddress");
+  const dmxStreetlampsAddress = document.getElementById(
+    "dmxStreetlampsAddress"
+  );
+  const dmxTimeout = document.getElementById("dmxTimeout");
+
+  function renderDmxConfig(config) {
+    dmxEnabled.checked = config.enabled;
+    dmxUniverse.value = config.universe;
+    dmxStartAddress.value = config.start_address;
+    dmxStreetlampsAddress.value = config.streetlamps_address ?? "";
+    dmxTimeout.value = config.timeout_ms;
+  }
+
+  dmxForm.addEventListener("submit", (event) => {
+    event.preventDefault();
+    presetRequest("./dmx", {
+      method: "PUT",
+      body: JSON.stringify({
+        enabled: dmxEnabled.checked,
+        universe: parseInt(dmxUniverse.value),
+        start_address: parseInt(dmxStartAddress.value),
+        streetlamps_address: dmxStreetlampsAddress.value
+          ? parseInt(dmxStreetlampsAddress.value)
+          : null,
+        timeout_ms: parseInt(dmxTimeout.value),
+      }),
+      headers: {
+        "Content-Type": "application/json",
+      },
+    }).then(renderDmxConfig);
+  });
+
+  const mqttForm = document.getElementById("mqttForm");
+  const mqttEnabled = document.getElementById("mqttEnabled");
+  const mqttBroker = document.getElementById("mqttBroker");
+  const mqttPort = document.getElementById("mqttPort");
+  const mqttUsername = document.getElementById("mqttUsername");
+  const mqttPassword = document.getElementById("mqttPassword");
+
+  function renderMqttConfig(config) {
+    mqttEnabled.checked = config.enabled;
+    mqttBroker.value = config.broker;
+    mqttPort.value = config.port;
+    mqttUsername.value = config.username ?? "";
+    // The password is never sent back, so leaving it blank keeps it
+    mqttPassword.value = "";
+    mqttPassword.placeholder = config.password_set ? "Unchanged" : "None";
+  }
+
+  mqttForm.addEventListener("submit", (event) => {
+    event.preventDefault();
+    const config = {
+      enabled: mqttEnabled.checked,
+      broker: mqttBroker.value,
+      port: parseInt(mqttPort.value),
+      username: mqttUsername.value || null,
+    };
+    if (mqttPassword.value) {
+      config.password = mqttPassword.value;
+    } else if (!config.username) {
+      // Anonymous clients don't have a password either
+      config.password = null;
+    }
+    presetRequest("./mqtt", {
+      method: "PUT",
+      body: JSON.stringify(config),
+      headers: {
+        "Content-Type": "application/json",
+      },
+    }).then(renderMqttConfig);
+  });
+
+  const midiForm = document.getElementById("midiForm");
+  const midiEnabled = document.getElementById("midiEnabled");
+  const midiChannel = document.getElementById("midiChannel");
+  const midiBrightnessCc = document.getElementById("midiBrightnessCc");
+  const midiCarIntervalCc = document.getElementById("midiCarIntervalCc");
+  const midiSpeedLimitCc = document.getElementById("midiSpeedLimitCc");
+  const midiTriggers = document.getElementById("midiTriggers");
+
+  // Triggers are edited as lines like "note 60 Cars" or
+  // "program 5 preset Evening"
+  function formatMidiTrigger({ event, action }) {
+    const source =
+      "Note" in event ? `note ${event.Note}` : `program ${event.Program}`;
+    const target =
+      "RecallPreset" in action
+        ? `preset ${action.RecallPreset}`
+        : action.SetEffect;
+    return `${source} ${target}`;
+  }
+
+  function parseMidiTrigger(line) {
+    const match = line.match(
+      /^(note|program)\s+(\d+)\s+(?:preset\s+(.+)|(\w+))$/i
+    );
+    if (!match) {
+      alert(`Can't read trigger "${line}"`);
+      throw new Error(line);
+    }
+    const [, kind, number, preset, effect] = match;
+    return {
+      event:
+        kind.toLowerCase() === "note"
+          ? { Note: parseInt(number) }
+          : { Program: parseInt(number) },
+      action: preset ? { RecallPreset: preset } : { SetEffect: effect },
+    };
+  }
+
+  function optionalNumber(input) {
+    return input.value ? parseInt(input.value) : null;
+  }
+
+  function renderMidiConfig(config) {
+    midiEnabled.checked = config.enabled;
+    midiChannel.value = config.channel ?? "";
+    midiBrightnessCc.value = config.brightness_cc ?? "";
+    midiCarIntervalCc.value = config.car_interval_cc ?? "";
+    midiSpeedLimitCc.value = config.speed_limit_cc ?? "";
+    midiTriggers.value = config.triggers.map(formatMidiTrigger).join("\n");
+  }
+
+  midiForm.addEventListener("submit", (event) => {
+    event.preventDefault();
+    const triggers = midiTriggers.value
+      .split("\n")
+      .map((line) => line.trim())
+      .filter((line) => line)
+      .map(parseMidiTrigger);
+    presetRequest("./midi", {
+      method: "PUT",
+      body: JSON.stringify({
+        enabled: midiEnabled.checked,
+        channel: optionalNumber(midiChannel),
+        triggers,
+        brightness_cc: optionalNumber(midiBrightnessCc),
+        car_interval_cc: optionalNumber(midiCarIntervalCc),
+        speed_limit_cc: optionalNumber(midiSpeedLimitCc),
+      }),
+      headers: {
+        "Content-Type": "application/json",
+      },
+    }).then(renderMidiConfig);
+  });
+
+  const networkForm = document.getElementById("networkForm");
+  const networkAddress = document.getElementById("networkAddress");
+  const networkPrefixLen = document.getElementById("networkPrefixLen");
+  const networkHostname = document.getElementById("networkHostname");
+  const networkPoolStart = document.getElementById("networkPoolStart");
+  const networkPoolSize = document.getElementById("networkPoolSize");
+  const networkLeaseSecs = document.getElementById("networkLeaseSecs");
+  const networkCaptivePortal = document.getElementById("networkCaptivePortal");
+
+  function renderNetworkConfig(config) {
+    networkAddress.value = config.address;
+    networkPrefixLen.value = config.prefix_len;
+    networkHostname.value = config.hostname;
+    networkPoolStart.value = config.dhcp_pool_start;
+    networkPoolSize.value = config.dhcp_pool_size;
+    networkLeaseSecs.value = config.dhcp_lease_secs;
+    networkCaptivePortal.checked = config.captive_portal;
+  }
+
+  networkForm.addEventListener("submit", (event) => {
+    event.preventDefault();
+    presetRequest("./network", {
+      method: "PUT",
+      body: JSON.stringify({
+        address: networkAddress.value,
+        prefix_len: parseInt(networkPrefixLen.value),
+        hostname: networkHostname.value,
+        dhcp_pool_start: networkPoolStart.value,
+        dhcp_pool_size: parseInt(networkPoolSize.value),
+        dhcp_lease_secs: parseInt(networkLeaseSecs.value),
+        captive_portal: networkCaptivePortal.checked,
+      }),
+      headers: {
+        "Content-Type": "application/json",
+      },
+    }).then(renderNetworkConfig);
+  });
+
   checkState();
-  setInterval(checkState, 10000);
+  connectSocket();
+  setInterval(() => {
+    if (!socket || socket.readyState !== WebSocket.OPEN) {
+      checkState();
+    }
+  }, 10000);
+  checkPlaylist();
+  setInterval(checkPlaylist, 10000);
+  loadPresets();
+  presetRequest("./dmx").then(renderDmxConfig);
+  presetRequest("./mqtt").then(renderMqttConfig);
+  presetRequest("./midi").then(renderMidiConfig);
+  presetRequest("./network").then(renderNetworkConfig);
 
   underpassMode.addEventListener("change", updateUnderpassConfig);
   underpassParams.addEventListener("change", updateUnderpassConfig);
+  underpassTransition.addEventListener("change", updateUnderpassConfig);
 });